@@ -39,10 +39,7 @@ async fn many_to_many(n_clients: u16, n_servers: u16) -> Result<()> {
             client.trust_cert(certs[srv_idx as usize].clone())?;
             client
                 .connect(
-                    SocketAddr::new(
-                        IpAddr::V4(Ipv4Addr::LOCALHOST),
-                        BASE_SRV_PORT + (srv_idx as u16),
-                    ),
+                    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), BASE_SRV_PORT + srv_idx),
                     "localhost",
                 )
                 .await?;
@@ -72,7 +69,7 @@ async fn many_to_many(n_clients: u16, n_servers: u16) -> Result<()> {
                 // We don't escape the content sent from the server because this is a demo/poc
                 println!(
                     "client {} reporting message: {}",
-                    client_idx.to_string(),
+                    client_idx,
                     str::from_utf8(&resp)?
                 );
 
@@ -1,7 +1,1030 @@
-trait Module {}
+use anyhow::{Result, bail};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    path::Path,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    sync::{Notify, watch},
+    task::{self, AbortHandle, JoinError, JoinSet},
+    time::Instant,
+};
+use tracing::{error, info, warn};
 
-struct Config {}
+use crate::{
+    checkpoint::CheckpointStore,
+    config::{self, ConfigError, Registry, SinkConfig, SourceConfig, TransformConfig},
+    event,
+    graph::{Channels, Graph},
+    module::{Component, Health, Shutdown},
+};
 
-struct Agent {
+/// How often the config file is checked for changes, if `watch_config` is set.
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Everything needed to run an agent, loaded from a TOML file.
+///
+/// Components are keyed by name, which must be unique across sources, transforms and sinks.
+/// Transforms and sinks list the components they read from in `inputs`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Config {
+    pub agent: AgentOptions,
+    pub sources: BTreeMap<String, SourceConfig>,
+    pub transforms: BTreeMap<String, TransformConfig>,
+    pub sinks: BTreeMap<String, SinkConfig>,
+}
+
+/// Settings for the agent itself rather than any one component.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AgentOptions {
+    /// Number of records that can be queued between two components before the sender has to wait.
+    #[serde(deserialize_with = "config::non_zero")]
+    pub channel_capacity: usize,
+    /// Where state that needs to survive a restart (e.g. checkpoints) is kept.
+    pub data_dir: PathBuf,
+    #[serde(deserialize_with = "config::non_zero")]
+    pub checkpoint_interval_ms: u64,
+    /// How long to wait for events to drain through to the sinks when shutting down, before giving up on them.
+    pub shutdown_timeout_ms: u64,
+    /// Reload the config whenever the file changes, as well as on SIGHUP.
+    pub watch_config: bool,
+    /// What to do when a component fails.
+    pub restart: RestartPolicy,
+}
+
+impl Default for AgentOptions {
+    fn default() -> Self {
+        Self {
+            channel_capacity: 100,
+            data_dir: PathBuf::from("."),
+            checkpoint_interval_ms: 5000,
+            shutdown_timeout_ms: 30_000,
+            watch_config: false,
+            restart: RestartPolicy::default(),
+        }
+    }
+}
+
+/// Failed components are restarted after a backoff that doubles with each failure, up to `max_backoff_ms`.
+/// A component that fails more than `max_restarts` times within `failure_window_ms` is given up on, which
+/// makes the agent unhealthy.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RestartPolicy {
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// 0 means failed components are never restarted.
+    pub max_restarts: usize,
+    pub failure_window_ms: u64,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30_000,
+            max_restarts: 5,
+            failure_window_ms: 60_000,
+        }
+    }
+}
+
+impl RestartPolicy {
+    /// How long to wait before restarting a component that has failed `failures` times in the window.
+    fn backoff(&self, failures: usize) -> Duration {
+        let exponent = failures.saturating_sub(1).min(31) as u32;
+        let backoff = self.initial_backoff_ms.saturating_mul(1 << exponent);
+        Duration::from_millis(backoff.min(self.max_backoff_ms))
+    }
+}
+
+impl AgentOptions {
+    pub fn checkpoint_interval(&self) -> Duration {
+        Duration::from_millis(self.checkpoint_interval_ms)
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_millis(self.shutdown_timeout_ms)
+    }
+}
+
+impl Config {
+    /// Component types are looked up in `registry`, see `Registry`.
+    pub fn load(path: &Path, registry: &Registry) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(|e| ConfigError::io(path, e))?;
+        Self::parse(path, &contents, registry)
+    }
+
+    /// `path` is only used for error messages.
+    pub fn parse(path: &Path, contents: &str, registry: &Registry) -> Result<Self, ConfigError> {
+        config::parse(path, contents, registry)
+    }
+}
+
+/// Runs the pipeline described by a `Config`, supervising each component so that failures are restarted
+/// according to the `RestartPolicy`, and replacing components as the config is reloaded.
+pub struct Agent {
+    /// Where the config was loaded from, and is reloaded from.
+    path: PathBuf,
     config: Config,
+    /// What the config was loaded with, and is reloaded with.
+    registry: Registry,
+    graph: Graph,
+}
+
+/// The result of a supervised component, along with the component so that its input can be handed on.
+type Supervised = (Box<dyn Component>, Result<()>);
+
+/// A component task that is currently running.
+struct Running {
+    id: task::Id,
+    abort: AbortHandle,
+    /// Triggers the `Shutdown` given to this component only.
+    stop: watch::Sender<bool>,
+}
+
+/// The state of a running agent.
+struct Pipeline {
+    path: PathBuf,
+    config: Config,
+    registry: Registry,
+    graph: Graph,
+    checkpoints: CheckpointStore,
+    channels: Channels,
+    tasks: JoinSet<Supervised>,
+    running: BTreeMap<String, Running>,
+    names: HashMap<task::Id, String>,
+    /// Components that have been given up on and not started again since.
+    failed: BTreeSet<String>,
+    /// Set if the last reload couldn't start every component it should have.
+    reload_failed: bool,
+    /// Last reported by `report_health`.
+    health: Health,
+}
+
+impl Agent {
+    /// `config` must have been loaded from `path` with `registry`. Fails if the pipeline in it isn't valid.
+    pub fn new(path: PathBuf, config: Config, registry: Registry) -> Result<Self> {
+        let graph = Graph::new(&config)?;
+        Ok(Self {
+            path,
+            config,
+            registry,
+            graph,
+        })
+    }
+
+    /// Runs every component until they have all stopped. Fails if any component had to be given up on.
+    ///
+    /// Once `shutdown` is triggered sources stop reading, and everything they have already sent drains through
+    /// to the sinks as each component finishes and drops its outputs. Anything still running once
+    /// `shutdown_timeout_ms` has passed is aborted, abandoning whatever events it hadn't got to. File sources
+    /// only checkpoint what has been delivered, so those are read again once the agent restarts.
+    ///
+    /// Each time `reload` is notified the config is read again and the pipeline updated to match, see
+    /// `Pipeline::reload`.
+    pub async fn run(self, mut shutdown: Shutdown, reload: Arc<Notify>) -> Result<()> {
+        let options = self.config.agent.clone();
+        let checkpoints = CheckpointStore::load(options.data_dir.join("checkpoints")).await?;
+        let flusher = checkpoints.spawn_flusher(options.checkpoint_interval());
+        let mut pipeline = Pipeline::start(self, checkpoints).await?;
+
+        let mut config_poll = tokio::time::interval(CONFIG_POLL_INTERVAL);
+        config_poll.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut config_modified = modified(&pipeline.path).await;
+
+        // Set once shutdown has been triggered, cleared once it has passed and everything has been aborted.
+        let mut deadline = None;
+        let mut draining = false;
+        let mut abandoned = None;
+        // Stop once every component has, unless a reload failed to start some of them, in which case we wait
+        // for the config to be fixed.
+        while !pipeline.tasks.is_empty() || (pipeline.reload_failed && !draining) {
+            tokio::select! {
+                Some(finished) = pipeline.tasks.join_next_with_id() => {
+                    pipeline.finished(finished);
+                }
+                _ = shutdown.wait(), if !draining => {
+                    let timeout = options.shutdown_timeout();
+                    info!("Shutting down, waiting up to {:?} for {} event(s) to drain", timeout, event::in_flight());
+                    pipeline.drain();
+                    deadline = Some(Instant::now() + timeout);
+                    draining = true;
+                }
+                _ = reload.notified(), if !draining => {
+                    pipeline.reload().await;
+                }
+                _ = config_poll.tick(), if options.watch_config && !draining => {
+                    let modified = modified(&pipeline.path).await;
+                    if modified != config_modified {
+                        config_modified = modified;
+                        info!("{} has changed", pipeline.path.display());
+                        pipeline.reload().await;
+                    }
+                }
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    abandoned = Some(event::in_flight());
+                    error!("Shutdown deadline exceeded, stopping {} component(s) that are still running", pipeline.tasks.len());
+                    pipeline.tasks.abort_all();
+                    deadline = None;
+                }
+            }
+            pipeline.report_health();
+        }
+
+        flusher.abort();
+        pipeline.checkpoints.flush().await?;
+        if let Some(abandoned) = abandoned {
+            bail!(
+                "shutdown deadline exceeded, abandoned {} event(s) still in flight",
+                abandoned
+            );
+        }
+        if !pipeline.failed.is_empty() {
+            let failed: Vec<_> = pipeline.failed.into_iter().collect();
+            bail!("agent is unhealthy, gave up on {}", failed.join(", "));
+        }
+        Ok(())
+    }
+}
+
+impl Pipeline {
+    /// Builds and starts every component in the agent's config.
+    async fn start(agent: Agent, checkpoints: CheckpointStore) -> Result<Self> {
+        let mut pipeline = Pipeline {
+            path: agent.path,
+            channels: Channels::new(agent.config.agent.channel_capacity),
+            config: agent.config,
+            registry: agent.registry,
+            graph: agent.graph,
+            checkpoints,
+            tasks: JoinSet::new(),
+            running: BTreeMap::new(),
+            names: HashMap::new(),
+            failed: BTreeSet::new(),
+            reload_failed: false,
+            health: Health::Healthy,
+        };
+        // Need to construct the consumers first, so that we can create the required
+        // channels to pass to the producers/senders. `Graph::build` takes care of this.
+        let components = pipeline
+            .graph
+            .build(
+                &pipeline.config,
+                &pipeline.checkpoints,
+                &mut pipeline.channels,
+                None,
+                BTreeMap::new(),
+            )
+            .await
+            .into_iter()
+            .map(|(_, component)| component)
+            .collect::<Result<Vec<_>>>()?;
+        for component in components {
+            pipeline.spawn(component);
+        }
+        Ok(pipeline)
+    }
+
+    fn spawn(&mut self, component: Box<dyn Component>) {
+        let name = component.name().to_string();
+        info!("Starting {}", name);
+        let (stop, shutdown) = Shutdown::channel();
+        let abort = self.tasks.spawn(supervise(
+            component,
+            self.config.agent.restart.clone(),
+            shutdown,
+        ));
+        self.names.insert(abort.id(), name.clone());
+        self.failed.remove(&name);
+        self.running.insert(
+            name,
+            Running {
+                id: abort.id(),
+                abort,
+                stop,
+            },
+        );
+    }
+
+    /// Records that a component task has finished, returning the component if it didn't panic.
+    fn finished(
+        &mut self,
+        finished: Result<(task::Id, Supervised), JoinError>,
+    ) -> Option<(String, Box<dyn Component>)> {
+        let id = match &finished {
+            Ok((id, _)) => *id,
+            Err(e) => e.id(),
+        };
+        let name = self.names.remove(&id)?;
+        // A removed component may still be draining when a new one with the same name is started.
+        if self
+            .running
+            .get(&name)
+            .is_some_and(|running| running.id == id)
+        {
+            self.running.remove(&name);
+        }
+
+        match finished {
+            Ok((_, (component, Ok(())))) => Some((name, component)),
+            Ok((_, (component, Err(e)))) => {
+                error!("{:#}", e);
+                self.failed.insert(name.clone());
+                Some((name, component))
+            }
+            Err(e) if e.is_cancelled() => {
+                self.failed.insert(name);
+                None
+            }
+            Err(e) => {
+                error!("{}: panicked: {}", name, e);
+                self.failed.insert(name);
+                None
+            }
+        }
+    }
+
+    /// Logs whenever the agent becomes unhealthy, because a component has been given up on or couldn't be
+    /// started, or healthy again once every such component has been restarted.
+    fn report_health(&mut self) {
+        let health = if self.failed.is_empty() {
+            Health::Healthy
+        } else {
+            let failed: Vec<_> = self.failed.iter().map(String::as_str).collect();
+            Health::Unhealthy(format!("gave up on {}", failed.join(", ")))
+        };
+        if health == self.health {
+            return;
+        }
+        match &health {
+            Health::Healthy => info!("Agent is healthy again"),
+            Health::Degraded(reason) => warn!("Agent is degraded, {}", reason),
+            Health::Unhealthy(reason) => error!("Agent is unhealthy, {}", reason),
+        }
+        self.health = health;
+    }
+
+    /// Stops the sources and drops our senders, so that every other component stops once it has drained.
+    fn drain(&mut self) {
+        for (name, running) in &self.running {
+            if self.graph.is_source(name) {
+                let _ = running.stop.send(true);
+            }
+        }
+        self.channels.clear();
+    }
+
+    /// Reads the config again and updates the pipeline to match. If the new config isn't valid it is rejected
+    /// and the pipeline is left as it is.
+    ///
+    /// Only components that have been added or changed are started, with any that changed being stopped first.
+    /// Transforms and sinks hand their input channel on to their replacement, and sources resume from their
+    /// checkpoints, so nothing sent before the reload is lost. Removed transforms and sinks are left to drain.
+    async fn reload(&mut self) {
+        info!("Reloading {}", self.path.display());
+        let mut config = match Config::load(&self.path, &self.registry) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Not reloading, {}", e);
+                return;
+            }
+        };
+        let graph = match Graph::new(&config) {
+            Ok(graph) => graph,
+            Err(e) => {
+                error!(
+                    "Not reloading, invalid pipeline in {}: {:#}",
+                    self.path.display(),
+                    e
+                );
+                return;
+            }
+        };
+        if config.agent != self.config.agent {
+            warn!(
+                "[agent] settings have changed, these only take effect once the agent is restarted"
+            );
+            config.agent = self.config.agent.clone();
+        }
+
+        let diff = self.graph.diff(&self.config, &graph, &config);
+        let mut rebuild: BTreeSet<String> = diff.added.union(&diff.changed).cloned().collect();
+        // Anything that has stopped, e.g. because it was given up on, gets another go.
+        for name in graph.order() {
+            if self.graph.contains(name) && !self.running.contains_key(name) {
+                rebuild.insert(name.clone());
+            }
+        }
+        // A transform or sink that isn't running can't hand on its input, so it gets a new channel and
+        // everything upstream of it has to be restarted to send to that instead.
+        loop {
+            let mut upstream = BTreeSet::new();
+            for name in &rebuild {
+                let has_input = self.running.contains_key(name) && !self.graph.is_source(name);
+                if graph.is_source(name) || has_input {
+                    continue;
+                }
+                for input in graph.inputs(name) {
+                    if self.graph.contains(input) && !rebuild.contains(input) {
+                        upstream.insert(input.clone());
+                    }
+                }
+            }
+            if upstream.is_empty() {
+                break;
+            }
+            rebuild.extend(upstream);
+        }
+        if rebuild.is_empty() && diff.removed.is_empty() {
+            info!("Reloaded {}, nothing has changed", self.path.display());
+            return;
+        }
+
+        let mut stopping = HashSet::new();
+        for name in rebuild.iter().chain(&diff.removed) {
+            let Some(running) = self.running.get(name) else {
+                continue;
+            };
+            if diff.removed.contains(name) && !self.graph.is_source(name) {
+                // Nothing will send to it once its inputs have been restarted, so it finishes by itself.
+                info!("{}: removed, stopping once it has drained", name);
+                self.channels.remove(name);
+                continue;
+            }
+            let _ = running.stop.send(true);
+            stopping.insert(running.id);
+        }
+
+        let mut inputs = BTreeMap::new();
+        for (name, mut component) in self.wait_for(stopping).await {
+            if rebuild.contains(&name)
+                && !graph.is_source(&name)
+                && let Some(input) = component.take_input()
+            {
+                inputs.insert(name, input);
+            }
+        }
+
+        self.config = config;
+        self.graph = graph;
+        let components = self
+            .graph
+            .build(
+                &self.config,
+                &self.checkpoints,
+                &mut self.channels,
+                Some(&rebuild),
+                inputs,
+            )
+            .await;
+        let mut started = 0;
+        self.reload_failed = false;
+        for (name, component) in components {
+            match component {
+                Ok(component) => {
+                    self.spawn(component);
+                    started += 1;
+                }
+                Err(e) => {
+                    error!("{}: unable to start: {:#}", name, e);
+                    self.failed.insert(name);
+                    self.reload_failed = true;
+                }
+            }
+        }
+        info!(
+            "Reloaded {}, started {} component(s) and removed {}",
+            self.path.display(),
+            started,
+            diff.removed.len()
+        );
+    }
+
+    /// Waits for the component tasks in `ids` to finish, returning the components. Anything still running
+    /// after `shutdown_timeout_ms` is aborted.
+    async fn wait_for(&mut self, mut ids: HashSet<task::Id>) -> Vec<(String, Box<dyn Component>)> {
+        let deadline = Instant::now() + self.config.agent.shutdown_timeout();
+        let mut timed_out = false;
+        let mut stopped = vec![];
+        while !ids.is_empty() {
+            let finished = tokio::select! {
+                finished = self.tasks.join_next_with_id() => finished,
+                _ = tokio::time::sleep_until(deadline), if !timed_out => {
+                    timed_out = true;
+                    for running in self.running.values() {
+                        if ids.contains(&running.id) {
+                            warn!("Timed out waiting for a component to stop, aborting it");
+                            running.abort.abort();
+                        }
+                    }
+                    continue;
+                }
+            };
+            let Some(finished) = finished else {
+                break;
+            };
+            let id = match &finished {
+                Ok((id, _)) => *id,
+                Err(e) => e.id(),
+            };
+            if let Some(component) = self.finished(finished)
+                && ids.contains(&id)
+            {
+                stopped.push(component);
+            }
+            ids.remove(&id);
+        }
+        stopped
+    }
+}
+
+/// When the file at `path` was last modified, `None` if that can't be determined.
+async fn modified(path: &Path) -> Option<SystemTime> {
+    tokio::fs::metadata(path).await.ok()?.modified().ok()
+}
+
+/// Runs `component` until it stops cleanly, restarting it whenever it fails unless the restart policy says
+/// to give up, in which case the last error is returned.
+async fn supervise(
+    mut component: Box<dyn Component>,
+    policy: RestartPolicy,
+    mut shutdown: Shutdown,
+) -> Supervised {
+    let window = Duration::from_millis(policy.failure_window_ms);
+    // When each recent failure happened, oldest first.
+    let mut failures: VecDeque<Instant> = VecDeque::new();
+    let result = loop {
+        let Err(e) = component.start(shutdown.clone()).await else {
+            break Ok(());
+        };
+
+        if let Health::Unhealthy(reason) = component.health() {
+            break Err(e.context(format!(
+                "{}: unhealthy ({}), not restarting",
+                component.name(),
+                reason
+            )));
+        }
+        let now = Instant::now();
+        while failures
+            .front()
+            .is_some_and(|failed| now - *failed > window)
+        {
+            failures.pop_front();
+        }
+        failures.push_back(now);
+        if failures.len() > policy.max_restarts {
+            break Err(e.context(format!(
+                "{}: failed {} times in {:?}, giving up",
+                component.name(),
+                failures.len(),
+                window
+            )));
+        }
+
+        let backoff = policy.backoff(failures.len());
+        warn!(
+            "{}: failed, restarting in {:?}: {:#}",
+            component.name(),
+            backoff,
+            e
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = shutdown.wait() => break Err(e.context(format!("{}: failed while shutting down", component.name()))),
+        }
+    };
+
+    if let Err(e) = component.shutdown().await {
+        error!("{}: failed to shut down: {:#}", component.name(), e);
+    }
+    (component, result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::BuildSink,
+        module::{FileIdentity, Input, Sink},
+    };
+    use futures::future::BoxFuture;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(test: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("logga-agent-{}-{}", std::process::id(), test));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Fails the first `failures` times it is started, then stops cleanly.
+    struct Flaky {
+        failures: usize,
+        health: Health,
+        starts: Arc<AtomicUsize>,
+    }
+
+    impl Component for Flaky {
+        fn name(&self) -> &str {
+            "flaky"
+        }
+
+        fn start(&mut self, _shutdown: Shutdown) -> BoxFuture<'_, Result<()>> {
+            Box::pin(async {
+                self.starts.fetch_add(1, Ordering::SeqCst);
+                if self.failures > 0 {
+                    self.failures -= 1;
+                    bail!("broken");
+                }
+                Ok(())
+            })
+        }
+
+        fn health(&self) -> Health {
+            self.health.clone()
+        }
+    }
+
+    /// A `Flaky` component, and how many times it has been started.
+    fn flaky(failures: usize, health: Health) -> (Box<dyn Component>, Arc<AtomicUsize>) {
+        let starts = Arc::new(AtomicUsize::new(0));
+        let flaky = Flaky {
+            failures,
+            health,
+            starts: starts.clone(),
+        };
+        (Box::new(flaky), starts)
+    }
+
+    fn policy(max_restarts: usize) -> RestartPolicy {
+        RestartPolicy {
+            initial_backoff_ms: 1,
+            max_backoff_ms: 10,
+            max_restarts,
+            failure_window_ms: 60_000,
+        }
+    }
+
+    /// A sink of `type = "test"` that fails as soon as it is started if `fail` is set, and otherwise never
+    /// finishes, not even once its input closes or it is asked to stop.
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct TestSinkConfig {
+        inputs: Vec<String>,
+        #[serde(default)]
+        fail: bool,
+    }
+
+    struct TestSink {
+        name: String,
+        fail: bool,
+        _input: Input,
+    }
+
+    impl BuildSink for TestSinkConfig {
+        fn inputs(&self) -> &[String] {
+            &self.inputs
+        }
+
+        fn build<'a>(
+            &'a self,
+            name: &'a str,
+            input: Input,
+        ) -> BoxFuture<'a, Result<Box<dyn Sink>>> {
+            Box::pin(async move {
+                let sink = TestSink {
+                    name: name.to_string(),
+                    fail: self.fail,
+                    _input: input,
+                };
+                Ok(Box::new(sink) as Box<dyn Sink>)
+            })
+        }
+    }
+
+    impl Component for TestSink {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn start(&mut self, _shutdown: Shutdown) -> BoxFuture<'_, Result<()>> {
+            Box::pin(async {
+                if self.fail {
+                    bail!("{}: broken", self.name);
+                }
+                std::future::pending().await
+            })
+        }
+
+        fn health(&self) -> Health {
+            if self.fail {
+                Health::Unhealthy("broken".to_string())
+            } else {
+                Health::Healthy
+            }
+        }
+    }
+
+    impl Sink for TestSink {
+        fn flush(&mut self) -> BoxFuture<'_, Result<()>> {
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn registry() -> Registry {
+        let mut registry = Registry::default();
+        registry.sink::<TestSinkConfig>("test");
+        registry
+    }
+
+    /// Writes a config following `in.log` in `dir` to `logga.toml` there, with `sinks` appended, returning
+    /// its path.
+    fn write_config(dir: &Path, sinks: &str) -> PathBuf {
+        let path = dir.join("logga.toml");
+        let contents = format!(
+            "[agent]\ndata_dir = {:?}\nshutdown_timeout_ms = 500\n\n\
+             [sources.app]\ntype = \"file\"\npath = {:?}\n\n{}",
+            dir,
+            dir.join("in.log"),
+            sinks
+        );
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn file_sink(name: &str, dir: &Path, file: &str) -> String {
+        format!(
+            "[sinks.{}]\ntype = \"file\"\ninputs = [\"app\"]\npath = {:?}\nflush_batch_size = 1\n\n",
+            name,
+            dir.join(file)
+        )
+    }
+
+    fn agent(path: PathBuf) -> Agent {
+        let registry = registry();
+        let config = Config::load(&path, &registry).unwrap();
+        Agent::new(path, config, registry).unwrap()
+    }
+
+    async fn pipeline(dir: &Path, sinks: &str) -> Pipeline {
+        append(&dir.join("in.log"), "");
+        let agent = agent(write_config(dir, sinks));
+        let checkpoints = CheckpointStore::load(dir.join("checkpoints"))
+            .await
+            .unwrap();
+        Pipeline::start(agent, checkpoints).await.unwrap()
+    }
+
+    fn append(path: &Path, contents: &str) {
+        use std::io::Write;
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+    }
+
+    /// Waits for the file at `path` to hold `contents`.
+    async fn wait_for_contents(path: &Path, contents: &str) {
+        let waited = tokio::time::timeout(Duration::from_secs(5), async {
+            while std::fs::read_to_string(path).ok().as_deref() != Some(contents) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        assert!(
+            waited.is_ok(),
+            "{} holds {:?}",
+            path.display(),
+            std::fs::read_to_string(path)
+        );
+    }
+
+    fn task_ids(pipeline: &Pipeline) -> BTreeMap<String, task::Id> {
+        pipeline
+            .running
+            .iter()
+            .map(|(name, running)| (name.clone(), running.id))
+            .collect()
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        let policy = RestartPolicy {
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1000,
+            ..RestartPolicy::default()
+        };
+        let backoffs: Vec<_> = [1, 2, 3, 4, 5, 100]
+            .into_iter()
+            .map(|failures| policy.backoff(failures).as_millis())
+            .collect();
+        assert_eq!(backoffs, [100, 200, 400, 800, 1000, 1000]);
+    }
+
+    #[tokio::test]
+    async fn restarts_until_it_stops_cleanly() {
+        let (component, starts) = flaky(2, Health::Degraded("struggling".to_string()));
+        let (_, shutdown) = Shutdown::channel();
+        let (_, result) = supervise(component, policy(2), shutdown).await;
+        result.unwrap();
+        assert_eq!(starts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_restarts() {
+        let (component, starts) = flaky(usize::MAX, Health::Healthy);
+        let (_, shutdown) = Shutdown::channel();
+        let (_, result) = supervise(component, policy(2), shutdown).await;
+        let e = result.unwrap_err();
+        assert!(e.to_string().contains("failed 3 times"), "{}", e);
+        assert_eq!(starts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn forgets_failures_outside_the_window() {
+        let (component, starts) = flaky(5, Health::Healthy);
+        let (_, shutdown) = Shutdown::channel();
+        let policy = RestartPolicy {
+            failure_window_ms: 0,
+            ..policy(1)
+        };
+        let (_, result) = supervise(component, policy, shutdown).await;
+        result.unwrap();
+        assert_eq!(starts.load(Ordering::SeqCst), 6);
+    }
+
+    #[tokio::test]
+    async fn doesnt_restart_unhealthy_components() {
+        let (component, starts) = flaky(1, Health::Unhealthy("gone".to_string()));
+        let (_, shutdown) = Shutdown::channel();
+        let (_, result) = supervise(component, policy(5), shutdown).await;
+        let e = result.unwrap_err();
+        assert!(e.to_string().contains("not restarting"), "{}", e);
+        assert_eq!(starts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn stops_restarting_once_shut_down() {
+        let (component, starts) = flaky(usize::MAX, Health::Healthy);
+        let (stop, shutdown) = Shutdown::channel();
+        let policy = RestartPolicy {
+            initial_backoff_ms: 60_000,
+            max_backoff_ms: 60_000,
+            ..policy(5)
+        };
+        let supervised = tokio::spawn(supervise(component, policy, shutdown));
+        while starts.load(Ordering::SeqCst) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        stop.send(true).unwrap();
+        let (_, result) = tokio::time::timeout(Duration::from_secs(5), supervised)
+            .await
+            .unwrap()
+            .unwrap();
+        let e = result.unwrap_err();
+        assert!(e.to_string().contains("while shutting down"), "{}", e);
+        assert_eq!(starts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn drains_to_the_sinks_on_shutdown() {
+        let dir = TempDir::new("drains_to_the_sinks_on_shutdown");
+        append(&dir.0.join("in.log"), "one\ntwo\nthree\n");
+        // Only flushed by shutting down.
+        let sink = format!(
+            "[sinks.out]\ntype = \"file\"\ninputs = [\"app\"]\npath = {:?}\nflush_interval_ms = 60000\n",
+            dir.0.join("out.log")
+        );
+        let agent = agent(write_config(&dir.0, &sink));
+        let (stop, shutdown) = Shutdown::channel();
+        let run = tokio::spawn(agent.run(shutdown, Arc::new(Notify::new())));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        stop.send(true).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), run)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.0.join("out.log")).unwrap(),
+            "one\ntwo\nthree\n"
+        );
+        // Resumes after what was delivered.
+        let checkpoints = CheckpointStore::load(dir.0.join("checkpoints"))
+            .await
+            .unwrap();
+        let identity = FileIdentity::from(&std::fs::metadata(dir.0.join("in.log")).unwrap());
+        assert_eq!(checkpoints.get("app", identity, None, 14), Some(14));
+    }
+
+    #[tokio::test]
+    async fn abandons_what_hasnt_drained_by_the_deadline() {
+        let dir = TempDir::new("abandons_what_hasnt_drained_by_the_deadline");
+        append(&dir.0.join("in.log"), "one\n");
+        let agent = agent(write_config(
+            &dir.0,
+            "[sinks.out]\ntype = \"test\"\ninputs = [\"app\"]\n",
+        ));
+        let (stop, shutdown) = Shutdown::channel();
+        let run = tokio::spawn(agent.run(shutdown, Arc::new(Notify::new())));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        stop.send(true).unwrap();
+        let e = tokio::time::timeout(Duration::from_secs(5), run)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap_err();
+        assert!(e.to_string().contains("deadline exceeded"), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn reload_only_restarts_what_changed() {
+        let dir = TempDir::new("reload_only_restarts_what_changed");
+        let mut pipeline = pipeline(&dir.0, &file_sink("out", &dir.0, "out.log")).await;
+        append(&dir.0.join("in.log"), "one\n");
+        wait_for_contents(&dir.0.join("out.log"), "one\n").await;
+        let before = task_ids(&pipeline);
+
+        pipeline.reload().await;
+        assert_eq!(task_ids(&pipeline), before);
+
+        // The new sink carries on from the old one's input, so the source is left alone.
+        write_config(&dir.0, &file_sink("out", &dir.0, "moved.log"));
+        pipeline.reload().await;
+        let after = task_ids(&pipeline);
+        assert_eq!(after["app"], before["app"]);
+        assert_ne!(after["out"], before["out"]);
+        append(&dir.0.join("in.log"), "two\n");
+        wait_for_contents(&dir.0.join("moved.log"), "two\n").await;
+
+        // A new sink has no input yet, so the source is restarted to send to it.
+        let sinks = file_sink("out", &dir.0, "moved.log") + &file_sink("copy", &dir.0, "copy.log");
+        write_config(&dir.0, &sinks);
+        pipeline.reload().await;
+        let added = task_ids(&pipeline);
+        assert_ne!(added["app"], after["app"]);
+        assert_eq!(added["out"], after["out"]);
+        append(&dir.0.join("in.log"), "three\n");
+        wait_for_contents(&dir.0.join("moved.log"), "two\nthree\n").await;
+        wait_for_contents(&dir.0.join("copy.log"), "three\n").await;
+    }
+
+    #[tokio::test]
+    async fn reload_keeps_running_with_an_invalid_config() {
+        let dir = TempDir::new("reload_keeps_running_with_an_invalid_config");
+        let mut pipeline = pipeline(&dir.0, &file_sink("out", &dir.0, "out.log")).await;
+        let config = pipeline.config.clone();
+        let before = task_ids(&pipeline);
+
+        std::fs::write(dir.0.join("logga.toml"), "[sources.app\n").unwrap();
+        pipeline.reload().await;
+        write_config(
+            &dir.0,
+            "[sinks.out]\ntype = \"file\"\ninputs = [\"missing\"]\npath = \"out.log\"\n",
+        );
+        pipeline.reload().await;
+        assert_eq!(task_ids(&pipeline), before);
+        assert_eq!(pipeline.config, config);
+        append(&dir.0.join("in.log"), "one\n");
+        wait_for_contents(&dir.0.join("out.log"), "one\n").await;
+    }
+
+    #[tokio::test]
+    async fn reload_restarts_the_inputs_of_failed_sinks() {
+        let dir = TempDir::new("reload_restarts_the_inputs_of_failed_sinks");
+        let mut pipeline = pipeline(
+            &dir.0,
+            "[sinks.out]\ntype = \"test\"\ninputs = [\"app\"]\nfail = true\n",
+        )
+        .await;
+        let finished = pipeline.tasks.join_next_with_id().await.unwrap();
+        assert_eq!(pipeline.finished(finished).unwrap().0, "out");
+        assert!(pipeline.failed.contains("out"));
+        let before = task_ids(&pipeline);
+
+        // Given another go, with a new channel that the source has to be restarted to send to.
+        pipeline.reload().await;
+        let after = task_ids(&pipeline);
+        assert_ne!(after["app"], before["app"]);
+        assert!(after.contains_key("out"));
+        assert!(pipeline.failed.is_empty());
+    }
 }
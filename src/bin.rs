@@ -1,45 +1,81 @@
-use std::{fs::File, path::PathBuf};
+use std::{path::PathBuf, sync::Arc};
 
-use anyhow::Result;
-use loggalib::comms::recv::Server;
-use tokio::sync::mpsc;
+use anyhow::{Context, Result};
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    sync::Notify,
+};
+use tracing::{error, info};
+use tracing_subscriber::EnvFilter;
 
-use crate::module::{FileSink, FileSource};
+use loggalib::{
+    agent::{Agent, Config},
+    config::Registry,
+    module::Shutdown,
+};
 
-mod agent;
-mod module;
+/// Used when no config file is given on the command line.
+const DEFAULT_CONFIG_PATH: &str = "./logga.toml";
+/// What gets logged unless `RUST_LOG` says otherwise.
+const DEFAULT_LOG_FILTER: &str = "info";
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_FILTER)),
+        )
+        .with_writer(std::io::stderr)
+        .init();
+
     rustls::crypto::ring::default_provider()
         .install_default()
         .expect("Failed to install rustls crypto provider");
 
-    // TODO: do config parsing here once we have it implemented.
-
-    // For now, just do simple file input and output
-    // TODO: handle errors better
-
-    // Need to construct the consumers first, so that we can create the required
-    // channels to pass to the producers/senders
-    //
-    // TODO: When configs eventually become a thing, we would essentially construct a graph of flows,
-    // and create mpsc channels from "right to left" or "sink to source"
-    let (mut send, mut recv) = mpsc::channel::<&[u8]>(100); // TODO: create a sensible default and a method to customise based on Bytes or something
-    let output_path = PathBuf::from("./test-out.log");
-    let delimiter = "\n".as_bytes();
-    let output = FileSink::new("Sink1".to_string(), output_path.clone(), delimiter, recv).await?;
-
-    let input_path = PathBuf::from("./test-in.log");
-    dbg!(&input_path);
-    let input =
-        FileSource::new_with_channels("Source1".to_string(), input_path, delimiter, vec![send])
-            .await?;
-
-    // Create threads, one per module for now.
-    // TODO: Consider how we handle one of the threads crashing/stopping.
-    // TODO: In future there might be smarter ways to do threading.
-    tokio::spawn(output.start());
-    tokio::spawn(input.start());
-    Ok(())
+    let config_path = std::env::args()
+        .nth(1)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH));
+    let registry = Registry::default();
+    let config = Config::load(&config_path, &registry)?;
+    info!(
+        "Loaded {} with {} source(s), {} transform(s) and {} sink(s)",
+        config_path.display(),
+        config.sources.len(),
+        config.transforms.len(),
+        config.sinks.len()
+    );
+
+    let agent = Agent::new(config_path.clone(), config, registry)
+        .with_context(|| format!("invalid pipeline in {}", config_path.display()))?;
+
+    let (stop, shutdown) = Shutdown::channel();
+    let reload = Arc::new(Notify::new());
+    let mut hangup = signal(SignalKind::hangup())?;
+    let notify = reload.clone();
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("Received SIGHUP, reloading");
+            notify.notify_one();
+        }
+    });
+    tokio::spawn(async move {
+        match wait_for_signal().await {
+            Ok(signal) => info!("Received {}, shutting down", signal),
+            Err(e) => error!("Unable to listen for signals, shutting down: {:#}", e),
+        }
+        let _ = stop.send(true);
+    });
+    agent.run(shutdown, reload).await
+}
+
+/// Resolves with the name of the signal once we are asked to stop.
+async fn wait_for_signal() -> Result<&'static str> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    Ok(tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = interrupt.recv() => "SIGINT",
+    })
 }
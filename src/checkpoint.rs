@@ -0,0 +1,452 @@
+use anyhow::{Context, Result, anyhow, bail};
+use aws_lc_rs::digest;
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    os::unix::fs::FileExt,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{fs::File, task::JoinHandle};
+use tracing::{error, warn};
+
+use crate::module::FileIdentity;
+
+/// Number of bytes at the start of a file used to fingerprint it.
+const FINGERPRINT_LEN: usize = 256;
+const HEADER: &str = "# logga checkpoints v1";
+
+/// Hash of the first `FINGERPRINT_LEN` bytes of a file. Inode numbers get reused once a file is deleted,
+/// so this stops us from resuming a brand new file at an offset that was meant for an old one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint([u8; 16]);
+
+impl Fingerprint {
+    /// Returns `None` if the file is still too short to be fingerprinted.
+    pub async fn of(file: &File) -> Result<Option<Self>> {
+        // Read through a duplicate handle with `read_at` so that the position of `file` is untouched.
+        let file = file.try_clone().await?.into_std().await;
+        let fingerprint = tokio::task::spawn_blocking(move || -> std::io::Result<Option<Self>> {
+            let mut buf = [0u8; FINGERPRINT_LEN];
+            let mut read = 0;
+            while read < FINGERPRINT_LEN {
+                let n = file.read_at(&mut buf[read..], read as u64)?;
+                if n == 0 {
+                    return Ok(None);
+                }
+                read += n;
+            }
+            let hash = digest::digest(&digest::SHA256, &buf);
+            let mut fingerprint = [0u8; 16];
+            fingerprint.copy_from_slice(&hash.as_ref()[..16]);
+            Ok(Some(Self(fingerprint)))
+        })
+        .await??;
+        Ok(fingerprint)
+    }
+
+    fn to_hex(self) -> String {
+        self.0.iter().fold(String::new(), |mut s, b| {
+            let _ = write!(s, "{:02x}", b);
+            s
+        })
+    }
+
+    fn from_hex(hex: &str) -> Result<Self> {
+        if hex.len() != 32 {
+            bail!("fingerprint must be 32 hex characters");
+        }
+        let mut fingerprint = [0u8; 16];
+        for (i, byte) in fingerprint.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)?;
+        }
+        Ok(Self(fingerprint))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// `None` if the file was too short to fingerprint when the checkpoint was committed.
+    pub fingerprint: Option<Fingerprint>,
+    /// Everything before this offset has been read by the source and delivered by the sinks.
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CheckpointKey {
+    source: String,
+    identity: FileIdentity,
+}
+
+/// Records how far each file source has read, so that a restarted agent resumes where it left off instead
+/// of re-reading every file from the start.
+///
+/// Commits only update memory, `flush` (or the task from `spawn_flusher`) persists them to disk.
+/// This is cheap to clone, every clone refers to the same store.
+#[derive(Clone)]
+pub struct CheckpointStore {
+    path: PathBuf,
+    entries: Arc<Mutex<HashMap<CheckpointKey, Checkpoint>>>,
+}
+
+impl CheckpointStore {
+    /// Loads the checkpoints in `path`, starting with an empty store if the file doesn't exist yet.
+    pub async fn load(path: PathBuf) -> Result<Self> {
+        let mut entries = HashMap::new();
+        match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => {
+                for (idx, line) in contents.lines().enumerate() {
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+                    match parse_line(line) {
+                        Ok((key, checkpoint)) => {
+                            entries.insert(key, checkpoint);
+                        }
+                        // Losing one checkpoint just means re-reading that file, not worth refusing to start over.
+                        Err(e) => warn!(
+                            "{}:{}: ignoring invalid checkpoint: {}",
+                            path.display(),
+                            idx + 1,
+                            e
+                        ),
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to read {}", path.display()));
+            }
+        }
+
+        Ok(Self {
+            path,
+            entries: Arc::new(Mutex::new(entries)),
+        })
+    }
+
+    /// Returns where `source` should resume reading the file identified by `identity` and `fingerprint`.
+    /// `len` is the current length of the file.
+    pub fn get(
+        &self,
+        source: &str,
+        identity: FileIdentity,
+        fingerprint: Option<Fingerprint>,
+        len: u64,
+    ) -> Option<u64> {
+        let key = CheckpointKey {
+            source: source.to_string(),
+            identity,
+        };
+        let checkpoint = *self.entries.lock().unwrap().get(&key)?;
+        match (checkpoint.fingerprint, fingerprint) {
+            // Same inode but different contents, the inode has been reused for a new file.
+            (Some(expected), Some(actual)) if expected != actual => return None,
+            // Was long enough to fingerprint before, isn't now, so it can't be the same file.
+            (Some(_), None) => return None,
+            _ => {}
+        }
+        // The file was truncated while we weren't running.
+        if checkpoint.offset > len {
+            return None;
+        }
+        Some(checkpoint.offset)
+    }
+
+    /// Records that everything `source` read from the file before `checkpoint.offset` has been delivered.
+    /// Any checkpoints `source` had for other files are dropped, as it has moved on from them.
+    pub fn commit(&self, source: &str, identity: FileIdentity, checkpoint: Checkpoint) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|key, _| key.source != source || key.identity == identity);
+        entries.insert(
+            CheckpointKey {
+                source: source.to_string(),
+                identity,
+            },
+            checkpoint,
+        );
+    }
+
+    /// Like `commit`, but keeps any checkpoints `source` has for other files. For sources following several
+    /// files at once under the one name, i.e. `FileDiscovery`.
+    pub fn commit_file(&self, source: &str, identity: FileIdentity, checkpoint: Checkpoint) {
+        self.entries.lock().unwrap().insert(
+            CheckpointKey {
+                source: source.to_string(),
+                identity,
+            },
+            checkpoint,
+        );
+    }
+
+    /// Drops the checkpoint `source` has for the file, once it has been deleted and there is nothing left to
+    /// resume.
+    pub fn remove(&self, source: &str, identity: FileIdentity) {
+        self.entries.lock().unwrap().remove(&CheckpointKey {
+            source: source.to_string(),
+            identity,
+        });
+    }
+
+    /// Writes every checkpoint to disk. The file is replaced atomically so a crash mid-write can't corrupt it.
+    pub async fn flush(&self) -> Result<()> {
+        let mut contents = format!("{}\n", HEADER);
+        for (key, checkpoint) in self.entries.lock().unwrap().iter() {
+            let fingerprint = checkpoint
+                .fingerprint
+                .map(Fingerprint::to_hex)
+                .unwrap_or_else(|| "-".to_string());
+            let _ = writeln!(
+                contents,
+                "{}\t{}\t{}\t{}\t{}",
+                key.source, key.identity.dev, key.identity.ino, fingerprint, checkpoint.offset
+            );
+        }
+
+        let tmp_path = self.path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, contents)
+            .await
+            .with_context(|| format!("failed to write {}", tmp_path.display()))?;
+        File::open(&tmp_path).await?.sync_all().await?;
+        tokio::fs::rename(&tmp_path, &self.path)
+            .await
+            .with_context(|| format!("failed to replace {}", self.path.display()))?;
+        Ok(())
+    }
+
+    /// Flushes the store every `interval` until the returned task is aborted.
+    pub fn spawn_flusher(&self, interval: Duration) -> JoinHandle<()> {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut timer = tokio::time::interval(interval);
+            timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                timer.tick().await;
+                if let Err(e) = store.flush().await {
+                    error!("Failed to flush checkpoints: {:#}", e);
+                }
+            }
+        })
+    }
+}
+
+/// Source names are written as is, so they must not contain the characters used to separate fields.
+pub fn validate_source_name(name: &str) -> Result<()> {
+    if name.contains(['\t', '\n', '\r']) {
+        bail!("source name {:?} must not contain tabs or newlines", name);
+    }
+    Ok(())
+}
+
+fn parse_line(line: &str) -> Result<(CheckpointKey, Checkpoint)> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    let [source, dev, ino, fingerprint, offset] = fields[..] else {
+        return Err(anyhow!("expected 5 fields, found {}", fields.len()));
+    };
+    let fingerprint = match fingerprint {
+        "-" => None,
+        hex => Some(Fingerprint::from_hex(hex)?),
+    };
+    Ok((
+        CheckpointKey {
+            source: source.to_string(),
+            identity: FileIdentity {
+                dev: dev.parse()?,
+                ino: ino.parse()?,
+            },
+        },
+        Checkpoint {
+            fingerprint,
+            offset: offset.parse()?,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDENTITY: FileIdentity = FileIdentity { dev: 1, ino: 2 };
+
+    /// A path in a directory of its own, removed once the test is done with it.
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(test: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "logga-checkpoint-{}-{}",
+                std::process::id(),
+                test
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir.join("checkpoints"))
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(self.0.parent().unwrap());
+        }
+    }
+
+    fn fingerprint(byte: u8) -> Fingerprint {
+        Fingerprint([byte; 16])
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let path = TempPath::new("round_trip");
+        let store = CheckpointStore::load(path.0.clone()).await.unwrap();
+        assert_eq!(store.get("app", IDENTITY, None, 100), None);
+
+        let other = FileIdentity { dev: 1, ino: 3 };
+        store.commit(
+            "app",
+            IDENTITY,
+            Checkpoint {
+                fingerprint: Some(fingerprint(7)),
+                offset: 42,
+            },
+        );
+        store.commit(
+            "short",
+            other,
+            Checkpoint {
+                fingerprint: None,
+                offset: 5,
+            },
+        );
+        store.flush().await.unwrap();
+        assert!(!path.0.with_extension("tmp").exists());
+
+        let loaded = CheckpointStore::load(path.0.clone()).await.unwrap();
+        assert_eq!(
+            loaded.get("app", IDENTITY, Some(fingerprint(7)), 100),
+            Some(42)
+        );
+        assert_eq!(loaded.get("short", other, None, 100), Some(5));
+        // Checkpoints belong to the source that committed them.
+        assert_eq!(
+            loaded.get("other", IDENTITY, Some(fingerprint(7)), 100),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn commit_replaces_other_files() {
+        let path = TempPath::new("commit_replaces_other_files");
+        let store = CheckpointStore::load(path.0.clone()).await.unwrap();
+        let checkpoint = Checkpoint {
+            fingerprint: None,
+            offset: 10,
+        };
+        store.commit("app", IDENTITY, checkpoint);
+        let rotated = FileIdentity { dev: 1, ino: 9 };
+        store.commit("app", rotated, checkpoint);
+        assert_eq!(store.get("app", IDENTITY, None, 100), None);
+        assert_eq!(store.get("app", rotated, None, 100), Some(10));
+    }
+
+    #[tokio::test]
+    async fn commit_file_keeps_other_files() {
+        let path = TempPath::new("commit_file_keeps_other_files");
+        let store = CheckpointStore::load(path.0.clone()).await.unwrap();
+        let other = FileIdentity { dev: 1, ino: 9 };
+        let checkpoint = |offset| Checkpoint {
+            fingerprint: None,
+            offset,
+        };
+        store.commit_file("app", IDENTITY, checkpoint(10));
+        store.commit_file("app", other, checkpoint(20));
+        assert_eq!(store.get("app", IDENTITY, None, 100), Some(10));
+        assert_eq!(store.get("app", other, None, 100), Some(20));
+
+        store.remove("app", IDENTITY);
+        store.flush().await.unwrap();
+        let loaded = CheckpointStore::load(path.0.clone()).await.unwrap();
+        assert_eq!(loaded.get("app", IDENTITY, None, 100), None);
+        assert_eq!(loaded.get("app", other, None, 100), Some(20));
+    }
+
+    #[tokio::test]
+    async fn skips_invalid_lines() {
+        let path = TempPath::new("skips_invalid_lines");
+        let valid = format!("app\t1\t2\t{}\t42", fingerprint(7).to_hex());
+        let contents = [
+            HEADER,
+            "app\t1\t2\t-",
+            "app\t1\tnot-a-number\t-\t5",
+            "app\t1\t3\tabc\t5",
+            "",
+            &valid,
+        ]
+        .join("\n");
+        std::fs::write(&path.0, contents).unwrap();
+
+        let store = CheckpointStore::load(path.0.clone()).await.unwrap();
+        assert_eq!(store.entries.lock().unwrap().len(), 1);
+        assert_eq!(
+            store.get("app", IDENTITY, Some(fingerprint(7)), 100),
+            Some(42)
+        );
+    }
+
+    #[test]
+    fn parse_line_rejects_bad_lines() {
+        assert!(parse_line("app\t1\t2\t-").is_err());
+        assert!(parse_line("app\t1\t2\t-\t5\textra").is_err());
+        assert!(parse_line("app\tx\t2\t-\t5").is_err());
+        assert!(parse_line("app\t1\t2\tzz\t5").is_err());
+        assert!(parse_line("app\t1\t2\t-\t-5").is_err());
+        assert!(parse_line("app\t1\t2\t-\t5").is_ok());
+    }
+
+    #[tokio::test]
+    async fn mismatch_restarts_from_start() {
+        let path = TempPath::new("mismatch_restarts_from_start");
+        let store = CheckpointStore::load(path.0.clone()).await.unwrap();
+        store.commit(
+            "app",
+            IDENTITY,
+            Checkpoint {
+                fingerprint: Some(fingerprint(7)),
+                offset: 42,
+            },
+        );
+        assert_eq!(
+            store.get("app", IDENTITY, Some(fingerprint(7)), 100),
+            Some(42)
+        );
+        // The inode was reused for a file with different contents.
+        assert_eq!(store.get("app", IDENTITY, Some(fingerprint(8)), 100), None);
+        // The file is now too short to fingerprint.
+        assert_eq!(store.get("app", IDENTITY, None, 100), None);
+        // The file was truncated.
+        assert_eq!(store.get("app", IDENTITY, Some(fingerprint(7)), 41), None);
+    }
+
+    #[tokio::test]
+    async fn fingerprint_needs_enough_bytes() {
+        let path = TempPath::new("fingerprint_needs_enough_bytes");
+        let log = path.0.with_file_name("app.log");
+        std::fs::write(&log, vec![b'a'; FINGERPRINT_LEN - 1]).unwrap();
+        let file = File::open(&log).await.unwrap();
+        assert_eq!(Fingerprint::of(&file).await.unwrap(), None);
+
+        std::fs::write(&log, vec![b'a'; FINGERPRINT_LEN + 10]).unwrap();
+        let a = Fingerprint::of(&File::open(&log).await.unwrap())
+            .await
+            .unwrap();
+        std::fs::write(&log, vec![b'b'; FINGERPRINT_LEN]).unwrap();
+        let b = Fingerprint::of(&File::open(&log).await.unwrap())
+            .await
+            .unwrap();
+        assert!(a.is_some() && b.is_some());
+        assert_ne!(a, b);
+        assert_eq!(
+            Fingerprint::from_hex(&a.unwrap().to_hex()).unwrap(),
+            a.unwrap()
+        );
+    }
+}
@@ -1,2 +1,8 @@
+pub mod frame;
+pub mod limit;
+pub mod pool;
+pub mod proto;
 pub mod recv;
 pub mod send;
+pub mod tls;
+pub mod transport;
@@ -0,0 +1,51 @@
+use anyhow::{Result, bail};
+use bytes::Bytes;
+use quinn::{ReadExactError, RecvStream, SendStream};
+
+/// Frames longer than this are neither sent nor accepted, unless configured otherwise.
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// Writes `payload` as a single frame, a big endian `u32` length followed by the payload itself.
+pub async fn write_frame(send: &mut SendStream, payload: &[u8]) -> Result<()> {
+    let Ok(len) = u32::try_from(payload.len()) else {
+        bail!("frame of {} bytes is too long", payload.len());
+    };
+    send.write_all(&len.to_be_bytes()).await?;
+    send.write_all(payload).await?;
+    Ok(())
+}
+
+/// Reads the next frame from `recv`. Returns `None` once the stream has finished cleanly, i.e. between frames.
+pub async fn read_frame(recv: &mut RecvStream, max_len: usize) -> Result<Option<Bytes>> {
+    let Some(len) = read_frame_len(recv, max_len).await? else {
+        return Ok(None);
+    };
+    read_frame_payload(recv, len).await.map(Some)
+}
+
+/// Reads the length of the next frame from `recv`, leaving its payload to `read_frame_payload`, e.g. to
+/// make room for it first. Returns `None` once the stream has finished cleanly.
+pub async fn read_frame_len(recv: &mut RecvStream, max_len: usize) -> Result<Option<usize>> {
+    let mut len = [0u8; 4];
+    match recv.read_exact(&mut len).await {
+        Ok(()) => {}
+        Err(ReadExactError::FinishedEarly(0)) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max_len {
+        bail!(
+            "frame of {} bytes is longer than the maximum of {}",
+            len,
+            max_len
+        );
+    }
+    Ok(Some(len))
+}
+
+/// Reads the payload of a frame whose length was read by `read_frame_len`.
+pub async fn read_frame_payload(recv: &mut RecvStream, len: usize) -> Result<Bytes> {
+    let mut payload = vec![0u8; len];
+    recv.read_exact(&mut payload).await?;
+    Ok(payload.into())
+}
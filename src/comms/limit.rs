@@ -0,0 +1,201 @@
+//! Keeps senders from overwhelming a receiver. Both limits work by making the receiver wait before reading
+//! on, which QUIC's flow control turns into the sender waiting too, rather than by dropping anything.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+
+/// Limits how fast events and bytes are taken in, allowing bursts of up to a second's worth.
+#[derive(Debug)]
+pub struct RateLimiter {
+    events: Option<Mutex<Bucket>>,
+    bytes: Option<Mutex<Bucket>>,
+}
+
+impl RateLimiter {
+    /// No limit on either if unspecified.
+    pub fn new(events_per_sec: Option<u64>, bytes_per_sec: Option<u64>) -> Self {
+        Self {
+            events: events_per_sec.map(|rate| Mutex::new(Bucket::new(rate))),
+            bytes: bytes_per_sec.map(|rate| Mutex::new(Bucket::new(rate))),
+        }
+    }
+
+    /// Waits until neither limit is in debt, i.e. until whatever was taken beyond the allowance has been
+    /// earned back. Nothing should be held while waiting here, e.g. memory reserved for what is about to be
+    /// taken, so that a peer over its rate doesn't keep others from using it.
+    pub async fn ready(&self) {
+        let now = Instant::now();
+        let wait = [&self.events, &self.bytes]
+            .into_iter()
+            .filter_map(|bucket| Some(bucket.as_ref()?.lock().unwrap().wait(now)))
+            .max()
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Takes in `events` events of `bytes` bytes in total, straight away even if the allowance isn't enough,
+    /// so that batches larger than the allowance still get through. The next `ready` waits for the difference.
+    pub fn take(&self, events: u64, bytes: u64) {
+        let now = Instant::now();
+        for (bucket, amount) in [(&self.events, events), (&self.bytes, bytes)] {
+            if let Some(bucket) = bucket {
+                bucket.lock().unwrap().take(amount, now);
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    per_sec: u64,
+    /// What can be taken without waiting, negative when what was taken has to be waited for first.
+    allowance: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(per_sec: u64) -> Self {
+        Self {
+            per_sec,
+            allowance: per_sec as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Adds what has been earned since the last update, up to a second's worth.
+    fn refill(&mut self, now: Instant) {
+        let rate = self.per_sec as f64;
+        let earned = now.saturating_duration_since(self.updated).as_secs_f64() * rate;
+        self.updated = now;
+        self.allowance = (self.allowance + earned).min(rate);
+    }
+
+    /// How long until the debt, if any, has been earned back.
+    fn wait(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        if self.allowance >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.allowance / self.per_sec as f64)
+        }
+    }
+
+    fn take(&mut self, amount: u64, now: Instant) {
+        self.refill(now);
+        self.allowance -= amount as f64;
+    }
+}
+
+/// Bytes that can be held at once, shared by everything that reserves from it.
+#[derive(Debug, Clone)]
+pub struct Budget(Arc<Semaphore>);
+
+impl Budget {
+    pub fn new(bytes: usize) -> Self {
+        Self(Arc::new(Semaphore::new(bytes.min(Semaphore::MAX_PERMITS))))
+    }
+
+    /// Waits until `bytes` are free, and holds on to them until the `Reservation` is dropped. Waits forever
+    /// for more than the whole budget.
+    pub async fn reserve(&self, bytes: usize) -> Reservation {
+        let permits = u32::try_from(bytes).unwrap_or(u32::MAX);
+        // Only fails once closed, which it never is.
+        Reservation {
+            _permit: self.0.clone().acquire_many_owned(permits).await.unwrap(),
+        }
+    }
+}
+
+/// Bytes reserved from a `Budget`, given back when dropped.
+#[derive(Debug)]
+pub struct Reservation {
+    _permit: OwnedSemaphorePermit,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn bursts_up_to_the_rate() {
+        let mut bucket = Bucket::new(10);
+        let start = bucket.updated;
+        for _ in 0..10 {
+            assert_eq!(bucket.wait(start), Duration::ZERO);
+            bucket.take(1, start);
+        }
+        // Nobody is in debt yet, so this goes straight away too and makes the next one wait.
+        assert_eq!(bucket.wait(start), Duration::ZERO);
+        bucket.take(1, start);
+        assert_eq!(bucket.wait(start), SECOND / 10);
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let mut bucket = Bucket::new(10);
+        let start = bucket.updated;
+        bucket.take(15, start);
+        // The 5 overdrawn have to be earned back before anyone else goes.
+        assert_eq!(bucket.wait(start), SECOND / 2);
+        assert_eq!(bucket.wait(start + SECOND / 4), SECOND / 4);
+        assert_eq!(bucket.wait(start + SECOND / 2), Duration::ZERO);
+        bucket.take(6, start + SECOND);
+        assert_eq!(bucket.wait(start + SECOND), SECOND / 10);
+    }
+
+    #[test]
+    fn refills_no_more_than_a_second() {
+        let mut bucket = Bucket::new(10);
+        let start = bucket.updated;
+        bucket.take(11, start + 60 * SECOND);
+        assert_eq!(bucket.wait(start + 60 * SECOND), SECOND / 10);
+    }
+
+    #[tokio::test]
+    async fn unlimited_never_waits() {
+        let limiter = RateLimiter::new(None, None);
+        limiter.take(u64::MAX, u64::MAX);
+        assert!(tokio::time::timeout(SECOND, limiter.ready()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn ready_waits_for_the_slowest_limit() {
+        let limiter = RateLimiter::new(Some(1000), Some(10));
+        limiter.take(1, 15);
+        let started = Instant::now();
+        limiter.ready().await;
+        assert!(started.elapsed() >= SECOND / 2, "{:?}", started.elapsed());
+    }
+
+    #[tokio::test]
+    async fn budget_caps_bytes_held() {
+        let budget = Budget::new(100);
+        let first = budget.reserve(60).await;
+        let _second = budget.reserve(40).await;
+        let wait = Duration::from_millis(50);
+        assert!(tokio::time::timeout(wait, budget.reserve(1)).await.is_err());
+        drop(first);
+        assert!(tokio::time::timeout(wait, budget.reserve(60)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn budget_never_fits_more_than_itself() {
+        let budget = Budget::new(100);
+        let wait = Duration::from_millis(50);
+        assert!(
+            tokio::time::timeout(wait, budget.reserve(101))
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,611 @@
+//! Spreads batches over several peers, e.g. a set of aggregators, keeping track of which of them are
+//! reachable so that a peer going down only means sending to the others until it comes back.
+//!
+//! Peers are reconnected to when there is something to send to them, backing off after each failure. Lost
+//! connections are noticed within `TransportOptions::idle_timeout` even when nothing is being sent, and if every peer keeps
+//! failing the client moves to a new socket in case our own network changed, see `Client::rebind`. Where the
+//! pool is with each peer is reported as `PeerEvent::State`.
+
+use anyhow::{Context, Result, bail};
+use aws_lc_rs::digest;
+use quinn::{Connection, ConnectionError, RecvStream, SendStream};
+use rand::Rng;
+use std::{fmt::Display, net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
+use tokio::{
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    time::Instant,
+};
+use tracing::{info, warn};
+
+use crate::{
+    comms::{
+        proto::{self, Control, Message, close, read_message, write_message},
+        send::Client,
+    },
+    event::{self, Event},
+};
+
+/// Points on the hash ring per peer, more spread keys more evenly between peers.
+const VIRTUAL_NODES: u32 = 64;
+
+/// Move to a new socket each time every peer has failed this many more times in a row.
+const REBIND_AFTER: u32 = 3;
+
+/// An agent to send to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    /// The name the peer's certificate was issued for.
+    pub server_name: String,
+}
+
+/// How a `Pool` picks the peer to send each batch to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Balance {
+    /// Each peer in turn.
+    #[default]
+    RoundRobin,
+    /// The peer with the fewest batches waiting to be acknowledged.
+    LeastOutstanding,
+    /// The same peer for every event with the same key, so that e.g. all of a host's events end up on the
+    /// same aggregator. Only the keys of a peer that goes down move, to the next peer on the ring. Events
+    /// without the key are sent round-robin.
+    ///
+    /// Batches are picked for by the key of their first event. `QUICSink` only batches together events that
+    /// belong to the same peer, but while that peer is down each of its batches moves as a whole, to the next
+    /// peer on the ring after its first event's key, so other keys in the batch may not end up where they
+    /// would have on their own.
+    ConsistentHash(EventKey),
+}
+
+/// The part of an event that `Balance::ConsistentHash` hashes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventKey {
+    Host,
+    Source,
+    Path,
+    Tag(String),
+}
+
+impl EventKey {
+    pub fn get<'a>(&self, event: &'a Event) -> Option<&'a [u8]> {
+        let metadata = &event.metadata;
+        match self {
+            Self::Host => Some(metadata.host.as_bytes()),
+            Self::Source => Some(metadata.source.as_bytes()),
+            Self::Path => metadata
+                .path
+                .as_ref()
+                .map(|path| path.as_os_str().as_encoded_bytes()),
+            Self::Tag(name) => metadata.tags.get(name).map(String::as_bytes),
+        }
+    }
+}
+
+impl FromStr for EventKey {
+    type Err = anyhow::Error;
+
+    /// One of "host", "source", "path" or "tags.<name>".
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "host" => Self::Host,
+            "source" => Self::Source,
+            "path" => Self::Path,
+            _ => match s.strip_prefix("tags.") {
+                Some(name) if !name.is_empty() => Self::Tag(name.to_string()),
+                _ => bail!(
+                    "unknown event key `{}`, expected host, source, path or tags.<name>",
+                    s
+                ),
+            },
+        })
+    }
+}
+
+/// Controls how long a `Pool` waits before trying a peer again after failing to send to it. The wait doubles
+/// with each consecutive failure, up to `max_backoff`, and is then jittered so that agents that lost the same
+/// peer don't all come back to it at once.
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// How long to wait after `attempts` consecutive failed attempts, somewhere between half and all of the
+    /// exponential backoff.
+    pub fn backoff(&self, attempts: u32) -> Duration {
+        let exponent = attempts.saturating_sub(1).min(31);
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff);
+        backoff.mul_f64(rand::rng().random_range(0.5..=1.0))
+    }
+}
+
+/// What happened with the peers, see `Pool::next_event`.
+#[derive(Debug)]
+pub enum PeerEvent {
+    /// A peer acknowledged the batch with this sequence number.
+    Ack(u64),
+    /// The connection to `peer` with this `stable_id` was lost. Nothing sent on it that hasn't been
+    /// acknowledged yet ever will be. Not reported for connections closed by `Pool::failed`.
+    Lost { peer: usize, id: usize },
+    /// The pool's connection to `peer` changed state.
+    State { peer: usize, state: ConnectionState },
+}
+
+/// Where a `Pool` is with a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Connecting and handshaking.
+    Connecting,
+    /// Handshaken with the agent called `peer_agent`.
+    Connected { peer_agent: String },
+    /// Couldn't connect, or the connection was lost, `failures` times in a row, the last time because of
+    /// `error`. Not tried again until `retry_at`.
+    Down {
+        failures: u32,
+        retry_at: Instant,
+        error: String,
+    },
+}
+
+/// What the tasks reading the control streams tell the pool.
+enum Signal {
+    Event(PeerEvent),
+    /// The connection to `peer` with this `stable_id` closed, which is only news if it is the current one.
+    Closed {
+        peer: usize,
+        id: usize,
+        error: ConnectionError,
+    },
+}
+
+struct PeerState {
+    peer: Peer,
+    /// The connection and the stream to send control messages on, if connected.
+    link: Option<(Connection, SendStream)>,
+    /// Consecutive failures, reset by connecting.
+    failures: u32,
+    /// Not tried again until then, after a failure.
+    retry_at: Option<Instant>,
+    /// Batches sent on the current connection that haven't been acknowledged.
+    outstanding: usize,
+}
+
+impl PeerState {
+    fn available(&self, now: Instant) -> bool {
+        self.retry_at.is_none_or(|retry_at| retry_at <= now)
+    }
+}
+
+/// Connections to a set of peers, which are referred to by their index in the list the pool was created with.
+pub struct Pool {
+    name: Arc<str>,
+    client: Client,
+    peers: Vec<PeerState>,
+    balance: Balance,
+    /// Points on the hash ring and the peer each belongs to, sorted by point.
+    ring: Vec<(u64, usize)>,
+    /// Where round-robin carries on from.
+    next: usize,
+    reconnect: ReconnectPolicy,
+    signal_send: UnboundedSender<Signal>,
+    signals: UnboundedReceiver<Signal>,
+}
+
+impl Pool {
+    /// `name` prefixes log messages.
+    pub fn new(
+        name: Arc<str>,
+        client: Client,
+        peers: Vec<Peer>,
+        balance: Balance,
+        reconnect: ReconnectPolicy,
+    ) -> Result<Self> {
+        if peers.is_empty() {
+            bail!("{}: no peers to send to", name);
+        }
+        let mut ring: Vec<(u64, usize)> = peers
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, peer)| {
+                (0..VIRTUAL_NODES)
+                    .map(move |node| (hash(format!("{}#{}", peer.addr, node).as_bytes()), idx))
+            })
+            .collect();
+        ring.sort_unstable();
+        let (signal_send, signals) = mpsc::unbounded_channel();
+        Ok(Self {
+            name,
+            client,
+            peers: peers
+                .into_iter()
+                .map(|peer| PeerState {
+                    peer,
+                    link: None,
+                    failures: 0,
+                    retry_at: None,
+                    outstanding: 0,
+                })
+                .collect(),
+            balance,
+            ring,
+            next: 0,
+            reconnect,
+            signal_send,
+            signals,
+        })
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    pub fn peer(&self, idx: usize) -> &Peer {
+        &self.peers[idx].peer
+    }
+
+    /// How many peers aren't waiting to be retried after a failure.
+    pub fn available(&self) -> usize {
+        let now = Instant::now();
+        self.peers.iter().filter(|peer| peer.available(now)).count()
+    }
+
+    /// The hash of `event`'s key when balancing by consistent hashing, `None` otherwise or if the event
+    /// doesn't have the key.
+    pub fn key(&self, event: &Event) -> Option<u64> {
+        match &self.balance {
+            Balance::ConsistentHash(key) => key.get(event).map(hash),
+            _ => None,
+        }
+    }
+
+    /// The peer that `key` belongs to when every peer is available.
+    pub fn owner(&self, key: u64) -> usize {
+        self.ring_from(key).next().unwrap()
+    }
+
+    /// Picks the peer to send the next batch to from those that are available, for a batch of events with
+    /// `key` when balancing by consistent hashing. If none are, returns when the first will be.
+    pub fn pick(&mut self, key: Option<u64>) -> Result<usize, Instant> {
+        let now = Instant::now();
+        let count = self.peers.len();
+        let mut in_turn = (0..count).map(|i| (self.next + i) % count);
+        let picked = match (&self.balance, key) {
+            (Balance::ConsistentHash(_), Some(key)) => self
+                .ring_from(key)
+                .find(|idx| self.peers[*idx].available(now)),
+            (Balance::LeastOutstanding, _) => in_turn
+                .filter(|idx| self.peers[*idx].available(now))
+                .min_by_key(|idx| self.peers[*idx].outstanding),
+            _ => in_turn.find(|idx| self.peers[*idx].available(now)),
+        };
+        match picked {
+            Some(idx) => {
+                self.next = (idx + 1) % count;
+                Ok(idx)
+            }
+            None => Err(self
+                .peers
+                .iter()
+                .filter_map(|peer| peer.retry_at)
+                .min()
+                .unwrap_or(now)),
+        }
+    }
+
+    /// Peers around the ring from `key` on, each once.
+    fn ring_from(&self, key: u64) -> impl Iterator<Item = usize> + '_ {
+        let start = self.ring.partition_point(|(point, _)| *point < key);
+        let mut seen = vec![false; self.peers.len()];
+        self.ring[start..]
+            .iter()
+            .chain(&self.ring[..start])
+            .map(|(_, idx)| *idx)
+            .filter(move |idx| !std::mem::replace(&mut seen[*idx], true))
+    }
+
+    /// The connection to `idx`, connecting and handshaking with it first if needed.
+    pub async fn connection(&mut self, idx: usize) -> Result<Connection> {
+        if let Some((connection, _)) = &self.peers[idx].link {
+            return Ok(connection.clone());
+        }
+        self.report(idx, ConnectionState::Connecting);
+        let Peer { addr, server_name } = &self.peers[idx].peer;
+        let connection = self
+            .client
+            .connect(*addr, server_name)
+            .await
+            .with_context(|| format!("unable to connect to {}", addr))?;
+        let Control {
+            peer_agent,
+            send,
+            recv,
+        } = Control::connect(&connection, &event::hostname()).await?;
+        tokio::spawn(read_control(
+            self.name.clone(),
+            idx,
+            connection.clone(),
+            recv,
+            self.signal_send.clone(),
+        ));
+        let state = &mut self.peers[idx];
+        state.link = Some((connection.clone(), send));
+        state.failures = 0;
+        state.retry_at = None;
+        self.report(idx, ConnectionState::Connected { peer_agent });
+        Ok(connection)
+    }
+
+    /// Records a batch being sent to `idx`.
+    pub fn sent(&mut self, idx: usize) {
+        self.peers[idx].outstanding += 1;
+    }
+
+    /// Records a batch sent to `idx` being acknowledged.
+    pub fn acked(&mut self, idx: usize) {
+        let outstanding = &mut self.peers[idx].outstanding;
+        *outstanding = outstanding.saturating_sub(1);
+    }
+
+    /// Records failing to connect or send to `idx` because of `error`, closing the connection to it and not
+    /// trying it again until it has backed off. Nothing sent on the connection will be acknowledged.
+    pub fn failed(&mut self, idx: usize, error: impl Display) {
+        let state = &mut self.peers[idx];
+        if let Some((connection, _)) = state.link.take() {
+            connection.close(close::NORMAL.into(), b"reconnecting");
+        }
+        state.outstanding = 0;
+        state.failures += 1;
+        let failures = state.failures;
+        let retry_at = Instant::now() + self.reconnect.backoff(failures);
+        state.retry_at = Some(retry_at);
+        self.report(
+            idx,
+            ConnectionState::Down {
+                failures,
+                retry_at,
+                error: error.to_string(),
+            },
+        );
+
+        // Only worth moving once every peer has kept failing, if some are reachable our network is fine.
+        let fewest = self.peers.iter().map(|peer| peer.failures).min();
+        if fewest == Some(failures) && failures.is_multiple_of(REBIND_AFTER) {
+            match self.client.rebind() {
+                Ok(addr) => info!(
+                    "{}: no peers reachable, moved to a new socket on {}",
+                    self.name, addr
+                ),
+                Err(e) => warn!("{}: unable to move to a new socket: {:#}", self.name, e),
+            }
+        }
+    }
+
+    /// The next acknowledgement, lost connection or change of state.
+    pub async fn next_event(&mut self) -> PeerEvent {
+        loop {
+            // We hold a sender, so this never ends.
+            match self.signals.recv().await.unwrap() {
+                Signal::Event(event) => return event,
+                Signal::Closed { peer, id, error } => {
+                    let current = self.peers[peer]
+                        .link
+                        .as_ref()
+                        .is_some_and(|(connection, _)| connection.stable_id() == id);
+                    if current {
+                        // Queues the new state, to follow.
+                        self.failed(peer, error);
+                        return PeerEvent::Lost { peer, id };
+                    }
+                }
+            }
+        }
+    }
+
+    fn report(&self, peer: usize, state: ConnectionState) {
+        // We hold the receiver, so this never fails.
+        let _ = self
+            .signal_send
+            .send(Signal::Event(PeerEvent::State { peer, state }));
+    }
+
+    /// Says goodbye to every connected peer and closes the connections once they have had it.
+    pub async fn close(&mut self, reason: &str) {
+        let goodbye = Message::Goodbye {
+            reason: reason.to_string(),
+        };
+        for state in &mut self.peers {
+            if let Some((connection, mut control)) = state.link.take() {
+                // Closing discards anything not yet received, so wait for the peer to have the goodbye.
+                if write_message(&mut control, &goodbye).await.is_ok() && control.finish().is_ok() {
+                    let _ = control.stopped().await;
+                }
+                connection.close(close::NORMAL.into(), reason.as_bytes());
+            }
+        }
+        // Also gives up on any connection attempt that was abandoned part way through.
+        self.client
+            .endpoint
+            .close(close::NORMAL.into(), reason.as_bytes());
+        self.client.endpoint.wait_idle().await;
+    }
+}
+
+/// Forwards what peer `idx` says on the control stream as `PeerEvent`s, until the connection closes.
+async fn read_control(
+    name: Arc<str>,
+    idx: usize,
+    connection: Connection,
+    mut recv: RecvStream,
+    signals: UnboundedSender<Signal>,
+) {
+    while let Ok(Some(message)) = read_message(&mut recv, proto::MAX_CONTROL_LEN).await {
+        match message {
+            // Only fails once the pool is gone, and the connection with it.
+            Message::Ack { seq } => {
+                let _ = signals.send(Signal::Event(PeerEvent::Ack(seq)));
+            }
+            Message::Goodbye { reason } => {
+                info!("{}: peer is closing the connection: {}", name, reason);
+            }
+            _ => warn!("{}: unexpected control message from peer", name),
+        }
+    }
+    let error = connection.closed().await;
+    let _ = signals.send(Signal::Closed {
+        peer: idx,
+        id: connection.stable_id(),
+        error,
+    });
+}
+
+/// A hash that is the same on every agent, so that they all send the same keys to the same peers.
+fn hash(bytes: &[u8]) -> u64 {
+    let digest = digest::digest(&digest::SHA256, bytes);
+    u64::from_be_bytes(digest.as_ref()[..8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(balance: Balance) -> Pool {
+        pool_with(balance, ReconnectPolicy::default())
+    }
+
+    fn pool_with(balance: Balance, reconnect: ReconnectPolicy) -> Pool {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let client = Client::new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let peers = (1..=3)
+            .map(|port| Peer {
+                addr: SocketAddr::from(([127, 0, 0, 1], port)),
+                server_name: "localhost".into(),
+            })
+            .collect();
+        Pool::new("test".into(), client, peers, balance, reconnect).unwrap()
+    }
+
+    fn keys() -> Vec<u64> {
+        (0..1000)
+            .map(|host| hash(format!("host-{}", host).as_bytes()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn spreads_keys_over_peers() {
+        let mut pool = pool(Balance::ConsistentHash(EventKey::Host));
+        let mut owned = [0; 3];
+        for key in keys() {
+            let owner = pool.owner(key);
+            assert_eq!(pool.pick(Some(key)), Ok(owner));
+            owned[owner] += 1;
+        }
+        assert!(owned.iter().all(|owned| *owned > 200), "{:?}", owned);
+    }
+
+    #[tokio::test]
+    async fn only_moves_keys_of_failed_peer() {
+        let mut pool = pool(Balance::ConsistentHash(EventKey::Host));
+        pool.failed(1, "down");
+        let mut moved_to = [0; 3];
+        for key in keys() {
+            let picked = pool.pick(Some(key)).unwrap();
+            let owner = pool.owner(key);
+            if owner == 1 {
+                let next = pool.ring_from(key).nth(1).unwrap();
+                assert_eq!(picked, next);
+                moved_to[next] += 1;
+            } else {
+                assert_eq!(picked, owner);
+            }
+        }
+        // Spread over the other peers, rather than all landing on one of them.
+        assert!(moved_to[0] > 0 && moved_to[2] > 0, "{:?}", moved_to);
+    }
+
+    #[tokio::test]
+    async fn waits_for_first_retry_when_all_failed() {
+        let mut pool = pool(Balance::ConsistentHash(EventKey::Host));
+        for idx in 0..3 {
+            pool.failed(idx, "down");
+        }
+        let first = pool.peers.iter().filter_map(|peer| peer.retry_at).min();
+        assert_eq!(pool.pick(Some(keys()[0])).err(), first);
+        assert_eq!(pool.available(), 0);
+    }
+
+    #[tokio::test]
+    async fn round_robin_skips_failed_peers() {
+        let mut pool = pool(Balance::RoundRobin);
+        pool.failed(1, "down");
+        let picked: Vec<usize> = (0..4).map(|_| pool.pick(None).unwrap()).collect();
+        assert_eq!(picked, [0, 2, 0, 2]);
+    }
+
+    #[tokio::test]
+    async fn unkeyed_events_are_sent_round_robin() {
+        let mut pool = pool(Balance::ConsistentHash(EventKey::Tag("missing".into())));
+        let picked: Vec<usize> = (0..4).map(|_| pool.pick(None).unwrap()).collect();
+        assert_eq!(picked, [0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_max_with_jitter() {
+        let policy = ReconnectPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+        };
+        for (attempts, full) in [(1, 100), (2, 200), (4, 800), (5, 1000), (40, 1000)] {
+            let full = Duration::from_millis(full);
+            let waits: Vec<Duration> = (0..100).map(|_| policy.backoff(attempts)).collect();
+            assert!(
+                waits.iter().all(|wait| *wait >= full / 2 && *wait <= full),
+                "{} attempts: {:?}",
+                attempts,
+                waits
+            );
+            // Jittered, rather than every agent retrying at the same moment.
+            assert!(waits.iter().any(|wait| *wait != waits[0]), "{:?}", waits);
+        }
+    }
+
+    #[tokio::test]
+    async fn rebinds_once_every_peer_keeps_failing() {
+        let mut pool = pool_with(
+            Balance::RoundRobin,
+            ReconnectPolicy {
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(1),
+            },
+        );
+        let bound = pool.client().local_addr().unwrap();
+        // One peer still reachable means our network is fine, however often the others fail.
+        for _ in 0..REBIND_AFTER * 2 {
+            pool.failed(0, "down");
+            pool.failed(1, "down");
+        }
+        assert_eq!(pool.client().local_addr().unwrap(), bound);
+
+        for _ in 0..REBIND_AFTER - 1 {
+            pool.failed(2, "down");
+        }
+        assert_eq!(pool.client().local_addr().unwrap(), bound);
+        pool.failed(2, "down");
+        let rebound = pool.client().local_addr().unwrap();
+        assert_ne!(rebound, bound);
+        assert_eq!(rebound.ip(), bound.ip());
+    }
+}
@@ -0,0 +1,564 @@
+//! The protocol agents use to send events to each other over QUIC.
+//!
+//! Connections negotiate the `ALPN` identifier during the TLS handshake, so peers speaking something else
+//! entirely fail to connect. After that, every message is sent as a single frame (see `frame`) whose first
+//! byte is the message type:
+//!
+//! 1. The connecting agent opens a bidirectional control stream and sends `Hello` with the protocol
+//!    `VERSION` it speaks and its identity. The accepting agent replies with `Welcome`, or closes the
+//!    connection with `close::UNSUPPORTED_VERSION` and a reason if it doesn't speak that version. A peer that
+//!    authenticated with a certificate it doesn't accept is closed with `close::UNAUTHORIZED` beforehand.
+//! 2. Events are sent as `Batch` messages on unidirectional streams, any number of batches per stream. Each
+//!    batch has a sequence number chosen by the sender, which stays the same if the batch is resent.
+//! 3. The receiver sends `Ack` with a batch's sequence number on the control stream once the batch has been
+//!    accepted downstream, or closes the connection with `close::UNDELIVERED` if it couldn't be. The sender
+//!    keeps every batch until it has been acknowledged, resending them after reconnecting, so batches may be
+//!    received more than once but are never lost.
+//! 4. Either side may send `Goodbye` on the control stream before closing the connection, so that the other
+//!    side can tell a deliberate close from a lost connection.
+//!
+//! Integers are big endian. Strings and byte strings are a `u32` length followed by that many bytes.
+//! Optional fields are a `u8` of 0 (absent) or 1 (present) followed by the value if present. Each event in a
+//! batch is encoded as:
+//!
+//! | field         | encoding                                      |
+//! |---------------|-----------------------------------------------|
+//! | `payload`     | byte string                                   |
+//! | `source`      | string                                        |
+//! | `host`        | string                                        |
+//! | `path`        | optional byte string                          |
+//! | `offset`      | optional `u64`                                |
+//! | `ingested_at` | `u64` nanoseconds since the unix epoch        |
+//! | `tags`        | `u32` count followed by that many string pairs |
+
+use anyhow::{Result, bail};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use quinn::{Connection, RecvStream, SendStream};
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    comms::frame::{read_frame, write_frame},
+    event::{Event, Metadata},
+};
+
+/// Identifies the protocol during the TLS handshake.
+pub const ALPN: &[u8] = b"logga/1";
+
+/// The version of the protocol this agent speaks.
+pub const VERSION: u16 = 1;
+
+/// Error codes that connections are closed with.
+pub mod close {
+    /// Closed deliberately, e.g. the agent is shutting down.
+    pub const NORMAL: u32 = 0;
+    /// The peer asked for a protocol version we don't speak.
+    pub const UNSUPPORTED_VERSION: u32 = 1;
+    /// The peer sent something that doesn't follow the protocol.
+    pub const PROTOCOL_ERROR: u32 = 2;
+    /// The peer authenticated, but isn't one of the peers allowed to connect.
+    pub const UNAUTHORIZED: u32 = 3;
+    /// A batch couldn't be delivered downstream, the peer should send everything unacknowledged again.
+    pub const UNDELIVERED: u32 = 4;
+}
+
+const HELLO: u8 = 1;
+const WELCOME: u8 = 2;
+const BATCH: u8 = 3;
+const GOODBYE: u8 = 4;
+const ACK: u8 = 5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    /// First message from the connecting agent.
+    Hello {
+        version: u16,
+        agent: String,
+    },
+    /// Accepts a `Hello`.
+    Welcome {
+        version: u16,
+        agent: String,
+    },
+    Batch {
+        seq: u64,
+        events: Vec<Event>,
+    },
+    /// Acknowledges that the `Batch` with sequence number `seq` has been accepted.
+    Ack {
+        seq: u64,
+    },
+    /// Sent before deliberately closing the connection.
+    Goodbye {
+        reason: String,
+    },
+}
+
+impl Message {
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+        match self {
+            Self::Hello { version, agent } => {
+                buf.put_u8(HELLO);
+                buf.put_u16(*version);
+                put_bytes(&mut buf, agent.as_bytes());
+            }
+            Self::Welcome { version, agent } => {
+                buf.put_u8(WELCOME);
+                buf.put_u16(*version);
+                put_bytes(&mut buf, agent.as_bytes());
+            }
+            Self::Batch { seq, events } => {
+                buf.put_u8(BATCH);
+                buf.put_u64(*seq);
+                buf.put_u32(events.len() as u32);
+                for event in events {
+                    put_event(&mut buf, event);
+                }
+            }
+            Self::Ack { seq } => {
+                buf.put_u8(ACK);
+                buf.put_u64(*seq);
+            }
+            Self::Goodbye { reason } => {
+                buf.put_u8(GOODBYE);
+                put_bytes(&mut buf, reason.as_bytes());
+            }
+        }
+        buf.freeze()
+    }
+
+    /// Payloads in a decoded batch share `buf` rather than being copied out of it.
+    pub fn decode(mut buf: Bytes) -> Result<Self> {
+        let message = match get_u8(&mut buf)? {
+            HELLO => Self::Hello {
+                version: get_u16(&mut buf)?,
+                agent: get_string(&mut buf)?,
+            },
+            WELCOME => Self::Welcome {
+                version: get_u16(&mut buf)?,
+                agent: get_string(&mut buf)?,
+            },
+            BATCH => {
+                let seq = get_u64(&mut buf)?;
+                let count = get_u32(&mut buf)?;
+                // Every event takes at least a few bytes, so don't let a bogus count allocate much.
+                let mut events = Vec::with_capacity((count as usize).min(buf.remaining()));
+                for _ in 0..count {
+                    events.push(get_event(&mut buf)?);
+                }
+                Self::Batch { seq, events }
+            }
+            ACK => Self::Ack {
+                seq: get_u64(&mut buf)?,
+            },
+            GOODBYE => Self::Goodbye {
+                reason: get_string(&mut buf)?,
+            },
+            kind => bail!("unknown message type {}", kind),
+        };
+        if buf.has_remaining() {
+            bail!("{} unexpected byte(s) after message", buf.remaining());
+        }
+        Ok(message)
+    }
+}
+
+/// How many bytes `event` takes up in an encoded `Batch`, so that senders can keep batches under the
+/// receiver's maximum frame length.
+pub fn encoded_len(event: &Event) -> usize {
+    let metadata = &event.metadata;
+    let string = |len: usize| 4 + len;
+    string(event.payload.len())
+        + string(metadata.source.len())
+        + string(metadata.host.len())
+        + 1
+        + metadata
+            .path
+            .as_ref()
+            .map_or(0, |path| string(path.as_os_str().len()))
+        + 1
+        + metadata.offset.map_or(0, |_| 8)
+        + 8
+        + 4
+        + metadata
+            .tags
+            .iter()
+            .map(|(key, value)| string(key.len()) + string(value.len()))
+            .sum::<usize>()
+}
+
+/// The overhead of a `Batch` on top of the `encoded_len` of its events.
+pub const BATCH_HEADER_LEN: usize = 1 + 8 + 4;
+
+/// Control messages are small, anything longer than this is a broken or malicious peer.
+pub const MAX_CONTROL_LEN: usize = 64 * 1024;
+
+/// The control stream of a connection that has completed the handshake. Its halves are read with
+/// `read_message` and written with `write_message`, usually by different tasks.
+pub struct Control {
+    /// The identity the peer gave in its `Hello` or `Welcome`.
+    pub peer_agent: String,
+    pub send: SendStream,
+    pub recv: RecvStream,
+}
+
+impl Control {
+    /// Opens the control stream on a new connection and introduces ourselves as `agent`. Fails if the peer
+    /// rejects the handshake.
+    pub async fn connect(connection: &Connection, agent: &str) -> Result<Self> {
+        let (mut send, mut recv) = connection.open_bi().await?;
+        let hello = Message::Hello {
+            version: VERSION,
+            agent: agent.to_string(),
+        };
+        write_message(&mut send, &hello).await?;
+        match read_message(&mut recv, MAX_CONTROL_LEN).await {
+            Ok(Some(Message::Welcome { version, agent })) if version == VERSION => Ok(Self {
+                peer_agent: agent,
+                send,
+                recv,
+            }),
+            Ok(Some(Message::Welcome { version, .. })) => {
+                bail!("peer answered with protocol version {}", version)
+            }
+            Ok(Some(_)) => bail!("peer answered the handshake with something other than Welcome"),
+            Ok(None) => bail!("peer closed the control stream during the handshake"),
+            // A rejection closes the connection, the close reason says why.
+            Err(e) => match connection.close_reason() {
+                Some(reason) => bail!("peer rejected the handshake: {}", reason),
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Accepts the control stream on a new connection, introducing ourselves as `agent`. Peers speaking an
+    /// unsupported version are rejected with `close::UNSUPPORTED_VERSION`.
+    pub async fn accept(connection: &Connection, agent: &str) -> Result<Self> {
+        let (mut send, mut recv) = connection.accept_bi().await?;
+        let peer_agent = match read_message(&mut recv, MAX_CONTROL_LEN).await? {
+            Some(Message::Hello { version, agent }) if version == VERSION => agent,
+            Some(Message::Hello { version, .. }) => {
+                let reason = format!(
+                    "unsupported protocol version {}, expected {}",
+                    version, VERSION
+                );
+                connection.close(close::UNSUPPORTED_VERSION.into(), reason.as_bytes());
+                bail!(reason);
+            }
+            _ => {
+                connection.close(close::PROTOCOL_ERROR.into(), b"expected Hello");
+                bail!("peer didn't start with Hello");
+            }
+        };
+        let welcome = Message::Welcome {
+            version: VERSION,
+            agent: agent.to_string(),
+        };
+        write_message(&mut send, &welcome).await?;
+        Ok(Self {
+            peer_agent,
+            send,
+            recv,
+        })
+    }
+}
+
+pub async fn write_message(send: &mut SendStream, message: &Message) -> Result<()> {
+    write_frame(send, &message.encode()).await
+}
+
+/// Reads the next message from `recv`. Returns `None` once the stream has finished cleanly.
+pub async fn read_message(recv: &mut RecvStream, max_len: usize) -> Result<Option<Message>> {
+    read_frame(recv, max_len)
+        .await?
+        .map(Message::decode)
+        .transpose()
+}
+
+fn put_bytes(buf: &mut BytesMut, bytes: &[u8]) {
+    buf.put_u32(bytes.len() as u32);
+    buf.put_slice(bytes);
+}
+
+fn put_event(buf: &mut BytesMut, event: &Event) {
+    let metadata = &event.metadata;
+    put_bytes(buf, &event.payload);
+    put_bytes(buf, metadata.source.as_bytes());
+    put_bytes(buf, metadata.host.as_bytes());
+    match &metadata.path {
+        Some(path) => {
+            buf.put_u8(1);
+            put_bytes(buf, path.as_os_str().as_bytes());
+        }
+        None => buf.put_u8(0),
+    }
+    match metadata.offset {
+        Some(offset) => {
+            buf.put_u8(1);
+            buf.put_u64(offset);
+        }
+        None => buf.put_u8(0),
+    }
+    let ingested_at = metadata
+        .ingested_at
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    buf.put_u64(ingested_at.as_nanos() as u64);
+    buf.put_u32(metadata.tags.len() as u32);
+    for (key, value) in &metadata.tags {
+        put_bytes(buf, key.as_bytes());
+        put_bytes(buf, value.as_bytes());
+    }
+}
+
+fn get_event(buf: &mut Bytes) -> Result<Event> {
+    let payload = get_bytes(buf)?;
+    let source: Arc<str> = get_string(buf)?.into();
+    let host = get_string(buf)?.into();
+    let path = match get_u8(buf)? {
+        0 => None,
+        _ => Some(Path::new(OsStr::from_bytes(&get_bytes(buf)?)).into()),
+    };
+    let offset = match get_u8(buf)? {
+        0 => None,
+        _ => Some(get_u64(buf)?),
+    };
+    let ingested_at = SystemTime::UNIX_EPOCH + Duration::from_nanos(get_u64(buf)?);
+    let mut tags = BTreeMap::new();
+    for _ in 0..get_u32(buf)? {
+        tags.insert(get_string(buf)?, get_string(buf)?);
+    }
+    Ok(Event::new(
+        payload,
+        Metadata {
+            source,
+            path,
+            offset,
+            host,
+            ingested_at,
+            tags,
+        },
+    ))
+}
+
+fn ensure_remaining(buf: &Bytes, len: usize) -> Result<()> {
+    if buf.remaining() < len {
+        bail!("message ended early");
+    }
+    Ok(())
+}
+
+fn get_u8(buf: &mut Bytes) -> Result<u8> {
+    ensure_remaining(buf, 1)?;
+    Ok(buf.get_u8())
+}
+
+fn get_u16(buf: &mut Bytes) -> Result<u16> {
+    ensure_remaining(buf, 2)?;
+    Ok(buf.get_u16())
+}
+
+fn get_u32(buf: &mut Bytes) -> Result<u32> {
+    ensure_remaining(buf, 4)?;
+    Ok(buf.get_u32())
+}
+
+fn get_u64(buf: &mut Bytes) -> Result<u64> {
+    ensure_remaining(buf, 8)?;
+    Ok(buf.get_u64())
+}
+
+fn get_bytes(buf: &mut Bytes) -> Result<Bytes> {
+    let len = get_u32(buf)? as usize;
+    ensure_remaining(buf, len)?;
+    Ok(buf.split_to(len))
+}
+
+fn get_string(buf: &mut Bytes) -> Result<String> {
+    Ok(String::from_utf8(get_bytes(buf)?.into())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comms::{frame::write_frame, recv::Server, send::Client};
+    use quinn::ConnectionError;
+
+    fn batch() -> Message {
+        let mut tagged = Metadata::new("app".into());
+        tagged.path = Some(Path::new("/var/log/app.log").into());
+        tagged.offset = Some(42);
+        tagged.tags.insert("env".into(), "prod".into());
+        Message::Batch {
+            seq: 7,
+            events: vec![
+                Event::new("first", tagged),
+                Event::new("", Metadata::new("other".into())),
+            ],
+        }
+    }
+
+    /// A client connection and the server's end of it, over localhost.
+    async fn connection() -> (Connection, Connection) {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let server = Server::new(None, None, None).unwrap();
+        let endpoint = server.listen("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut client = Client::new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        client.trust_cert(server.get_cert()).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        let accept = async { endpoint.accept().await.unwrap().await.unwrap() };
+        let (client, server) = tokio::join!(client.connect(addr, "localhost"), accept);
+        (client.unwrap(), server)
+    }
+
+    #[test]
+    fn round_trip() {
+        let messages = [
+            Message::Hello {
+                version: VERSION,
+                agent: "edge".into(),
+            },
+            Message::Welcome {
+                version: VERSION,
+                agent: "central".into(),
+            },
+            batch(),
+            Message::Batch {
+                seq: 8,
+                events: vec![],
+            },
+            Message::Ack { seq: 7 },
+            Message::Goodbye {
+                reason: "shutting down".into(),
+            },
+        ];
+        for message in messages {
+            assert_eq!(Message::decode(message.encode()).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn encoded_len_matches() {
+        let Message::Batch { events, .. } = batch() else {
+            unreachable!();
+        };
+        let len: usize = events.iter().map(encoded_len).sum();
+        let message = Message::Batch { seq: 7, events };
+        assert_eq!(message.encode().len(), BATCH_HEADER_LEN + len);
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        let encoded = batch().encode();
+        for len in 0..encoded.len() {
+            assert!(Message::decode(encoded.slice(..len)).is_err(), "{}", len);
+        }
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let mut encoded = BytesMut::from(&Message::Ack { seq: 1 }.encode()[..]);
+        encoded.put_u8(0);
+        let e = Message::decode(encoded.freeze()).unwrap_err();
+        assert_eq!(e.to_string(), "1 unexpected byte(s) after message");
+    }
+
+    #[test]
+    fn rejects_unknown_message_type() {
+        let e = Message::decode(Bytes::from_static(&[42, 0, 0])).unwrap_err();
+        assert_eq!(e.to_string(), "unknown message type 42");
+    }
+
+    #[tokio::test]
+    async fn reads_written_messages() {
+        let (client, server) = connection().await;
+        let batch = batch();
+        let mut send = client.open_uni().await.unwrap();
+        write_message(&mut send, &batch).await.unwrap();
+        write_message(&mut send, &Message::Ack { seq: 3 })
+            .await
+            .unwrap();
+        send.finish().unwrap();
+        let mut recv = server.accept_uni().await.unwrap();
+        let max_len = batch.encode().len();
+        let read = read_message(&mut recv, max_len).await.unwrap();
+        assert_eq!(read, Some(batch));
+        let read = read_message(&mut recv, max_len).await.unwrap();
+        assert_eq!(read, Some(Message::Ack { seq: 3 }));
+        assert_eq!(read_message(&mut recv, max_len).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_frames() {
+        let (client, server) = connection().await;
+        let mut send = client.open_uni().await.unwrap();
+        let encoded = batch().encode();
+        write_frame(&mut send, &encoded).await.unwrap();
+        send.finish().unwrap();
+        let mut recv = server.accept_uni().await.unwrap();
+        let e = read_message(&mut recv, encoded.len() - 1)
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("longer than the maximum"), "{}", e);
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_frames() {
+        let (client, server) = connection().await;
+        let mut send = client.open_uni().await.unwrap();
+        let encoded = batch().encode();
+        // The length of the whole message, but only half of it before the stream finishes.
+        send.write_all(&(encoded.len() as u32).to_be_bytes())
+            .await
+            .unwrap();
+        send.write_all(&encoded[..encoded.len() / 2]).await.unwrap();
+        send.finish().unwrap();
+        let mut recv = server.accept_uni().await.unwrap();
+        assert!(read_message(&mut recv, MAX_CONTROL_LEN).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn handshake() {
+        let (client, server) = connection().await;
+        let (connected, accepted) = tokio::join!(
+            Control::connect(&client, "edge"),
+            Control::accept(&server, "central")
+        );
+        assert_eq!(connected.unwrap().peer_agent, "central");
+        assert_eq!(accepted.unwrap().peer_agent, "edge");
+    }
+
+    #[tokio::test]
+    async fn rejects_other_versions() {
+        let (client, server) = connection().await;
+        let (mut send, mut recv) = client.open_bi().await.unwrap();
+        let hello = Message::Hello {
+            version: VERSION + 1,
+            agent: "edge".into(),
+        };
+        write_message(&mut send, &hello).await.unwrap();
+        let Err(e) = Control::accept(&server, "central").await else {
+            panic!("expected the handshake to be rejected");
+        };
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "unsupported protocol version {}, expected {}",
+                VERSION + 1,
+                VERSION
+            )
+        );
+        assert!(read_message(&mut recv, MAX_CONTROL_LEN).await.is_err());
+        let Some(ConnectionError::ApplicationClosed(closed)) = client.close_reason() else {
+            panic!("expected the server to close the connection");
+        };
+        assert_eq!(closed.error_code, close::UNSUPPORTED_VERSION.into());
+    }
+}
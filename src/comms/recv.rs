@@ -1,96 +1,451 @@
-use anyhow::{Result, anyhow};
+use anyhow::{Result, bail};
 
-use quinn::{Endpoint, ServerConfig, VarInt};
-use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
-use std::{net::SocketAddr, str, sync::Arc};
-use tracing::info;
+use quinn::{Connection, Endpoint, Incoming, ServerConfig, crypto::rustls::QuicServerConfig};
+use rustls::{
+    DigitallySignedStruct, DistinguishedName, SignatureScheme,
+    client::danger::HandshakeSignatureValid,
+    pki_types::{CertificateDer, UnixTime},
+    server::{
+        WebPkiClientVerifier,
+        danger::{ClientCertVerified, ClientCertVerifier},
+    },
+};
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+use tracing::{info, warn};
 use uuid::Uuid;
 
+use crate::comms::{
+    proto,
+    tls::{self, CertResolver, Identity, IdentityFiles, PeerIdentity, Watcher},
+    transport::TransportOptions,
+};
+
 pub struct Server {
     id: uuid::Uuid,
     config: ServerConfig,
-    cert: CertificateDer<'static>,
+    max_connections: Option<usize>,
+    // Both can be replaced while the server is running, see `watch`.
+    resolver: Arc<CertResolver>,
+    client_verifier: Option<Arc<ClientCaVerifier>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerOptions {
+    pub transport: TransportOptions,
+    /// Connections to have open at once, beyond which peers are refused until some close. No limit if `None`.
+    pub max_connections: Option<usize>,
 }
 
 impl Server {
-    /// `max_connections` defaults to 1024 if unspecified
-    pub fn new(max_connections: Option<VarInt>) -> Result<Self> {
-        let (config, cert) = Self::configure_server(max_connections.unwrap_or(1024_u32.into()))?;
+    /// `options` defaults to `ServerOptions::default()` if unspecified
+    /// `identity` defaults to a new self-signed certificate for "localhost" if unspecified, which peers
+    /// can only trust by being given `get_cert`.
+    /// `client_ca` turns on mutual TLS: peers must present a certificate issued by one of these, or be one of
+    /// them, to connect. Anyone can connect if unspecified.
+    pub fn new(
+        options: Option<ServerOptions>,
+        identity: Option<Identity>,
+        client_ca: Option<Vec<CertificateDer<'static>>>,
+    ) -> Result<Self> {
+        let identity = match identity {
+            Some(identity) => identity,
+            None => Identity::self_signed(vec!["localhost".into()])?,
+        };
+        let resolver = Arc::new(CertResolver::new(Some(identity))?);
+        let client_verifier = client_ca
+            .map(|client_ca| ClientCaVerifier::new(client_ca).map(Arc::new))
+            .transpose()?;
+        let options = options.unwrap_or_default();
+        let config = Self::configure_server(
+            &options.transport,
+            resolver.clone(),
+            client_verifier.clone(),
+        )?;
         info!("Created server");
         Ok(Server {
             id: Uuid::new_v4(),
             config,
-            cert,
+            max_connections: options.max_connections,
+            resolver,
+            client_verifier,
         })
     }
 
-    pub fn get_cert(&self) -> &CertificateDer<'static> {
-        &self.cert
+    /// The certificate currently presented to peers.
+    pub fn get_cert(&self) -> CertificateDer<'static> {
+        self.resolver
+            .leaf()
+            .expect("the server always has a certificate")
     }
 
     fn configure_server(
-        max_connections: VarInt,
-    ) -> Result<(ServerConfig, CertificateDer<'static>)> {
+        transport: &TransportOptions,
+        resolver: Arc<CertResolver>,
+        client_verifier: Option<Arc<ClientCaVerifier>>,
+    ) -> Result<ServerConfig> {
         // Code taken from: https://github.com/quinn-rs/quinn/blob/e05a8e5a7e4d068a98ec861ed722999f90f28a43/quinn/examples/common/mod.rs
-        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
-        let cert_der = CertificateDer::from(cert.cert);
-        let priv_key = PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der());
+        let builder = rustls::ServerConfig::builder();
+        let mutual = client_verifier.is_some();
+        let builder = match client_verifier {
+            Some(client_verifier) => builder.with_client_cert_verifier(client_verifier),
+            None => builder.with_no_client_auth(),
+        };
+        let mut crypto = builder.with_cert_resolver(resolver);
+        if mutual {
+            // Resuming a session skips verifying the peer's certificate, which would let a peer keep
+            // connecting after the CAs are replaced with ones that no longer vouch for it.
+            crypto.send_tls13_tickets = 0;
+        }
+        crypto.alpn_protocols = vec![proto::ALPN.to_vec()];
 
         let mut server_config =
-            ServerConfig::with_single_cert(vec![cert_der.clone()], priv_key.into())?;
-        let transport_config = Arc::get_mut(&mut server_config.transport).unwrap();
-        transport_config.max_concurrent_uni_streams(max_connections);
-        transport_config.max_concurrent_bidi_streams(max_connections);
+            ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto)?));
+        server_config.transport_config(Arc::new(transport.config()?));
 
-        Ok((server_config, cert_der))
+        Ok(server_config)
     }
 
-    pub async fn serve(&self, addr: SocketAddr) -> Result<()> {
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Starts accepting connections on `addr`.
+    pub fn listen(&self, addr: SocketAddr) -> Result<Endpoint> {
         // Don't mind cloning here because we should only do this on startup.
         let endpoint = Endpoint::server(self.config.clone(), addr)?;
-        let server_id = self.id;
-        // Start iterating over incoming connections.
-        while let Some(conn) = endpoint.accept().await {
-            // Going to keep each connection in its own thread.
-
-            tokio::spawn(async move {
-                let connection = conn
-                    .await
-                    .map_err(|e| anyhow!("failed to connect: {}", e))?;
-                println!(
-                    "Connection from remote address {}",
-                    connection.remote_address()
-                );
-
-                let (mut send, mut recv) = connection
-                    .accept_bi()
-                    .await
-                    .map_err(|e| anyhow!("failed to open stream: {}", e))?;
-
-                // Recv first, then send data
-                let msg = recv
-                    .read_to_end(usize::MAX)
-                    .await
-                    .map_err(|e| anyhow!("failed to read request: {}", e))?;
-                // We don't escape the content sent from the client because this is a demo/poc
-                println!(
-                    "Server {} reporting message: {}",
-                    &server_id,
-                    str::from_utf8(&msg)?
-                );
-
-                // Send response
-                send.write_all(format!("Hello from server {}", server_id).as_bytes())
-                    .await?;
-                // Tell the client we are finished writing
-                send.finish()?;
-
-                // Make sure we wait for the client to receive all the info
-                send.stopped().await?;
-                Ok::<(), anyhow::Error>(())
-            });
+        info!("Server {} listening on {}", self.id, endpoint.local_addr()?);
+        Ok(endpoint)
+    }
+
+    /// The next peer to connect to `endpoint`, which must come from `listen`, refusing any beyond
+    /// `ServerOptions::max_connections`. `None` once the endpoint has been closed.
+    pub async fn accept(&self, endpoint: &Endpoint) -> Option<Incoming> {
+        loop {
+            let incoming = endpoint.accept().await?;
+            match self.max_connections {
+                Some(max) if endpoint.open_connections() >= max => {
+                    warn!(
+                        "Server {} refused {}, already at {} connections",
+                        self.id,
+                        incoming.remote_address(),
+                        max
+                    );
+                    incoming.refuse();
+                }
+                _ => return Some(incoming),
+            }
+        }
+    }
+
+    /// Replaces the certificate presented to peers. Established connections are unaffected.
+    pub fn set_identity(&self, identity: Identity) -> Result<()> {
+        self.resolver.set(identity)
+    }
+
+    /// Replaces the CAs that peers' certificates must be issued by. Established connections are unaffected.
+    /// Fails if the server wasn't created with a `client_ca`.
+    pub fn set_client_ca(&self, client_ca: Vec<CertificateDer<'static>>) -> Result<()> {
+        match &self.client_verifier {
+            Some(client_verifier) => client_verifier.set(client_ca),
+            None => bail!("mutual TLS isn't enabled for this server"),
         }
+    }
+
+    /// Reloads the certificate presented to peers from `identity`, and the CAs that peers' certificates must
+    /// be issued by from `client_ca`, whenever those files change, until the `Watcher` is dropped.
+    pub fn watch(&self, identity: Option<IdentityFiles>, client_ca: Option<PathBuf>) -> Watcher {
+        let resolver = self.resolver.clone();
+        let client_verifier = self.client_verifier.clone();
+        let client_ca = client_ca.filter(|_| client_verifier.is_some());
+        let paths = identity
+            .iter()
+            .flat_map(|files| [files.cert_path.clone(), files.key_path.clone()])
+            .chain(client_ca.clone())
+            .collect();
+        tls::watch(paths, move || {
+            if let Some(files) = &identity {
+                resolver.set(files.load()?)?;
+                info!("Reloaded certificate from {}", files.cert_path.display());
+            }
+            if let (Some(path), Some(client_verifier)) = (&client_ca, &client_verifier) {
+                client_verifier.set(tls::load_certs(path)?)?;
+                info!("Reloaded client CAs from {}", path.display());
+            }
+            Ok(())
+        })
+    }
+}
 
+/// Identifies the peer on the other end of `connection` by the certificate it authenticated with, `None` if
+/// it didn't present one. Only peers whose certificate was verified get this far, see `Server::new`.
+pub fn peer_identity(connection: &Connection) -> Option<PeerIdentity> {
+    let certs = connection
+        .peer_identity()?
+        .downcast::<Vec<CertificateDer<'static>>>()
+        .ok()?;
+    PeerIdentity::from_cert(certs.first()?).ok()
+}
+
+/// Verifies peers' certificates against CAs that can be replaced while the server is running.
+#[derive(Debug)]
+struct ClientCaVerifier {
+    current: RwLock<Arc<dyn ClientCertVerifier>>,
+}
+
+impl ClientCaVerifier {
+    fn new(client_ca: Vec<CertificateDer<'static>>) -> Result<Self> {
+        Ok(Self {
+            current: RwLock::new(Self::build(client_ca)?),
+        })
+    }
+
+    fn set(&self, client_ca: Vec<CertificateDer<'static>>) -> Result<()> {
+        *self.current.write().unwrap() = Self::build(client_ca)?;
         Ok(())
     }
+
+    fn build(client_ca: Vec<CertificateDer<'static>>) -> Result<Arc<dyn ClientCertVerifier>> {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in client_ca {
+            roots.add(cert)?;
+        }
+        Ok(WebPkiClientVerifier::builder(Arc::new(roots)).build()?)
+    }
+
+    fn current(&self) -> Arc<dyn ClientCertVerifier> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+impl ClientCertVerifier for ClientCaVerifier {
+    fn offer_client_auth(&self) -> bool {
+        self.current().offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        self.current().client_auth_mandatory()
+    }
+
+    // The hints can't be borrowed from a verifier that may be replaced at any moment. Without them peers
+    // present whichever certificate they have, which is all a `Client` can do anyway.
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.current()
+            .verify_client_cert(end_entity, intermediates, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.current().verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.current().verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.current().supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comms::{
+        send::Client,
+        tls::{
+            self, RELOAD_INTERVAL,
+            testing::{Ca, TempDir},
+        },
+    };
+    use std::time::Duration;
+    use tokio::task::JoinHandle;
+
+    /// Listens on localhost, keeping every connection it accepts open until the returned task is aborted.
+    fn serve(server: Server) -> (SocketAddr, CertificateDer<'static>, JoinHandle<()>) {
+        let endpoint = server.listen("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        let cert = server.get_cert();
+        let task = tokio::spawn(async move {
+            let mut open = vec![];
+            while let Some(incoming) = server.accept(&endpoint).await {
+                if let Ok(connection) = incoming.await {
+                    open.push(connection);
+                }
+            }
+        });
+        (addr, cert, task)
+    }
+
+    /// Connects `client` to `server` over localhost, returning how the server saw it. Only the server's side
+    /// tells whether the client was accepted, the client is done with its side of the handshake before the
+    /// server has checked its certificate.
+    async fn handshake(server: &Server, client: &mut Client) -> Result<Connection> {
+        let endpoint = server.listen("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        let accept = async { Ok::<_, anyhow::Error>(endpoint.accept().await.unwrap().await?) };
+        let (_, accepted) = tokio::join!(client.connect(addr, "localhost"), accept);
+        accepted
+    }
+
+    /// A server for "localhost" that requires clients to present a certificate issued by `client_ca`, and a
+    /// client that trusts it.
+    fn mutual(client_ca: &Ca) -> (Server, Client) {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let server_ca = Ca::new("server-ca");
+        let server = Server::new(
+            None,
+            Some(server_ca.issue("localhost").identity()),
+            Some(vec![client_ca.cert()]),
+        )
+        .unwrap();
+        let mut client = Client::new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        client.trust_cert(server_ca.cert()).unwrap();
+        (server, client)
+    }
+
+    fn client(cert: CertificateDer<'static>) -> Client {
+        let mut client = Client::new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        client.trust_cert(cert).unwrap();
+        client
+    }
+
+    #[tokio::test]
+    async fn refuses_connections_beyond_the_max() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let options = ServerOptions {
+            max_connections: Some(1),
+            ..ServerOptions::default()
+        };
+        let (addr, cert, _task) = serve(Server::new(Some(options), None, None).unwrap());
+        let first = client(cert.clone())
+            .connect(addr, "localhost")
+            .await
+            .unwrap();
+        let refused = client(cert.clone()).connect(addr, "localhost").await;
+        assert!(refused.is_err());
+
+        // Room again once the first has gone, which takes the server a moment to notice.
+        first.close(0u32.into(), b"done");
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while client(cert.clone())
+                .connect(addr, "localhost")
+                .await
+                .is_err()
+            {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("still refused once the first connection closed");
+    }
+
+    #[tokio::test]
+    async fn applies_transport_options() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let options = ServerOptions {
+            transport: TransportOptions {
+                max_concurrent_streams: 1,
+                ..TransportOptions::default()
+            },
+            ..ServerOptions::default()
+        };
+        let (addr, cert, _task) = serve(Server::new(Some(options), None, None).unwrap());
+        let connection = client(cert).connect(addr, "localhost").await.unwrap();
+        let _first = connection.open_uni().await.unwrap();
+        let second = tokio::time::timeout(Duration::from_millis(300), connection.open_uni()).await;
+        assert!(
+            second.is_err(),
+            "opened more streams than the server allows"
+        );
+    }
+
+    #[tokio::test]
+    async fn accepts_clients_issued_by_the_client_ca() {
+        let client_ca = Ca::new("client-ca");
+        let (server, mut client) = mutual(&client_ca);
+        client
+            .set_identity(client_ca.issue("edge-01").identity())
+            .unwrap();
+        let connection = handshake(&server, &mut client).await.unwrap();
+        let identity = peer_identity(&connection).unwrap();
+        assert_eq!(identity.name(), "edge-01");
+        assert_eq!(identity.common_name.as_deref(), Some("edge-01"));
+    }
+
+    #[tokio::test]
+    async fn rejects_clients_without_a_certificate() {
+        let (server, mut client) = mutual(&Ca::new("client-ca"));
+        assert!(handshake(&server, &mut client).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_clients_issued_by_another_ca() {
+        let (server, mut client) = mutual(&Ca::new("client-ca"));
+        client
+            .set_identity(Ca::new("other-ca").issue("edge-01").identity())
+            .unwrap();
+        assert!(handshake(&server, &mut client).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn anyone_may_connect_without_a_client_ca() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let server = Server::new(None, None, None).unwrap();
+        let mut client = client(server.get_cert());
+        let connection = handshake(&server, &mut client).await.unwrap();
+        assert_eq!(peer_identity(&connection), None);
+    }
+
+    #[tokio::test]
+    async fn reloads_identity_and_client_ca() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let dir = TempDir::new("reloads_identity_and_client_ca");
+        let server_ca = Ca::new("server-ca");
+        let (old_ca, new_ca) = (Ca::new("old-client-ca"), Ca::new("new-client-ca"));
+        let client_ca = dir.0.join("client-ca.pem");
+        std::fs::write(&client_ca, old_ca.pem()).unwrap();
+        let files = server_ca.issue("localhost").write(&dir.0);
+        let server = Server::new(
+            None,
+            Some(files.load().unwrap()),
+            Some(tls::load_certs(&client_ca).unwrap()),
+        )
+        .unwrap();
+        let _watcher = server.watch(Some(files.clone()), Some(client_ca.clone()));
+        let mut client = Client::new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        client.trust_cert(server_ca.cert()).unwrap();
+        client
+            .set_identity(new_ca.issue("edge-01").identity())
+            .unwrap();
+        assert!(handshake(&server, &mut client).await.is_err());
+
+        let reissued = server_ca.issue("localhost");
+        reissued.write(&dir.0);
+        std::fs::write(&client_ca, new_ca.pem()).unwrap();
+        tokio::time::sleep(RELOAD_INTERVAL + Duration::from_secs(1)).await;
+        assert_eq!(server.get_cert(), reissued.cert());
+        handshake(&server, &mut client).await.unwrap();
+    }
 }
@@ -1,49 +1,140 @@
-use anyhow::Result;
+use anyhow::{Context, Result, bail};
 use quinn::crypto::rustls::QuicClientConfig;
 use quinn::{Connection, Endpoint};
-use rustls::pki_types::CertificateDer;
-use std::net::SocketAddr;
-use std::sync::Arc;
+use rustls::client::WebPkiServerVerifier;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{CertificateError, DigitallySignedStruct, SignatureScheme};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use tracing::{info, warn};
+
+use crate::comms::{
+    proto,
+    tls::{self, CertResolver, Identity, IdentityFiles, Pin, Watcher},
+    transport::TransportOptions,
+};
 
 pub struct Client {
+    // Rebinding keeps the address but not the port, see `rebind`.
+    bind_addr: SocketAddr,
     // Need to track all the certs we trust.
-    trusted_certs: rustls::RootCertStore,
+    verifier: Arc<TrustVerifier>,
+    // Presented to servers that ask for a client certificate.
+    resolver: Arc<CertResolver>,
     // The endpoint contains the ClientConfig.
     pub endpoint: Endpoint,
 }
 
+/// Which servers a `Client` trusts.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trust {
+    /// PEM files, or directories of them, with the servers' own certificates or the CAs that issue them.
+    pub ca_paths: Vec<PathBuf>,
+    /// Also trust the CAs in the system's trust store.
+    pub system_roots: bool,
+    /// Trust servers whose certificate matches one of these, and no others. Can't be combined with CAs, and
+    /// neither the server name nor expiry are checked, as the pin already says exactly who the server is.
+    pub pins: Vec<Pin>,
+}
+
+impl Trust {
+    pub fn is_empty(&self) -> bool {
+        self.ca_paths.is_empty() && !self.system_roots && self.pins.is_empty()
+    }
+}
+
 impl Client {
-    pub fn new(bind_addr: SocketAddr) -> Result<Self> {
+    /// `transport` defaults to `TransportOptions::default()` if unspecified
+    pub fn new(bind_addr: SocketAddr, transport: Option<TransportOptions>) -> Result<Self> {
         let mut endpoint = Endpoint::client(bind_addr)?;
-        let client_crypto = rustls::ClientConfig::builder()
-            .with_root_certificates(rustls::RootCertStore::empty())
-            .with_no_client_auth();
-
-        let client_config =
-            quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(client_crypto)?));
-        endpoint.set_default_client_config(client_config.clone());
+        let verifier = Arc::new(TrustVerifier::new()?);
+        let resolver = Arc::new(CertResolver::new(None)?);
+        endpoint.set_default_client_config(Self::client_config(
+            verifier.clone(),
+            resolver.clone(),
+            &transport.unwrap_or_default(),
+        )?);
         Ok(Client {
-            trusted_certs: rustls::RootCertStore::empty(),
-            endpoint: endpoint,
+            bind_addr,
+            verifier,
+            resolver,
+            endpoint,
         })
     }
 
     pub fn trust_cert(&mut self, cert: CertificateDer<'static>) -> Result<()> {
-        // To trust a new cert we need to set a new default client config with the updated cert store
-        // This default config is then used for each new connection.
-        self.trusted_certs.add(cert)?;
+        // The verifier in the config is swapped for a new one, which is then used for each new connection.
+        self.verifier.update(|trusted| trusted.certs.push(cert))
+    }
 
-        let client_crypto = rustls::ClientConfig::builder()
-            .with_root_certificates(self.trusted_certs.clone())
-            .with_no_client_auth();
+    /// Trusts everything in `trust`, in addition to what is already trusted.
+    pub fn trust(&mut self, trust: &Trust) -> Result<()> {
+        self.verifier.update(|trusted| {
+            trusted
+                .trust
+                .ca_paths
+                .extend(trust.ca_paths.iter().cloned());
+            trusted.trust.system_roots |= trust.system_roots;
+            trusted.trust.pins.extend(&trust.pins);
+        })
+    }
 
-        let client_config =
+    /// Authenticates us to servers that require mutual TLS, see `Server::new`.
+    pub fn set_identity(&mut self, identity: Identity) -> Result<()> {
+        self.resolver.set(identity)
+    }
+
+    /// Reloads the certificates from `Trust::ca_paths`, and the identity from `identity`, whenever those files
+    /// change, until the `Watcher` is dropped. Established connections are unaffected.
+    pub fn watch(&self, identity: Option<IdentityFiles>) -> Watcher {
+        let verifier = self.verifier.clone();
+        let resolver = self.resolver.clone();
+        let paths = identity
+            .iter()
+            .flat_map(|files| [files.cert_path.clone(), files.key_path.clone()])
+            .chain(verifier.trusted.lock().unwrap().trust.ca_paths.clone())
+            .collect();
+        tls::watch(paths, move || {
+            if let Some(files) = &identity {
+                resolver.set(files.load()?)?;
+                info!("Reloaded certificate from {}", files.cert_path.display());
+            }
+            verifier.update(|_| {})?;
+            Ok(())
+        })
+    }
+
+    fn client_config(
+        verifier: Arc<TrustVerifier>,
+        resolver: Arc<CertResolver>,
+        transport: &TransportOptions,
+    ) -> Result<quinn::ClientConfig> {
+        let mut client_crypto = rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(verifier)
+            .with_client_cert_resolver(resolver);
+        client_crypto.alpn_protocols = vec![proto::ALPN.to_vec()];
+
+        let mut client_config =
             quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(client_crypto)?));
+        client_config.transport_config(Arc::new(transport.config()?));
+        Ok(client_config)
+    }
 
-        self.endpoint
-            .set_default_client_config(client_config.clone());
+    /// Moves to a new socket on the address `new` was given, with a new port, e.g. after our network changed
+    /// under us. Established connections migrate to it. Returns the new local address.
+    pub fn rebind(&self) -> Result<SocketAddr> {
+        let socket = UdpSocket::bind(SocketAddr::new(self.bind_addr.ip(), 0))?;
+        self.endpoint.rebind(socket)?;
+        Ok(self.endpoint.local_addr()?)
+    }
 
-        Ok(())
+    /// The address of the socket currently in use, which changes with `rebind`.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.endpoint.local_addr()?)
     }
 
     pub async fn connect(
@@ -55,3 +146,386 @@ impl Client {
         Ok(conn)
     }
 }
+
+/// Everything a `Client` has been told to trust, kept so that the files can be loaded again.
+#[derive(Debug, Clone, Default)]
+struct Trusted {
+    certs: Vec<CertificateDer<'static>>,
+    trust: Trust,
+}
+
+/// Verifies servers' certificates against what the `Client` trusts, which can change while it is running.
+#[derive(Debug)]
+struct TrustVerifier {
+    trusted: Mutex<Trusted>,
+    // `None` until something is trusted, until then every server is rejected.
+    current: RwLock<Option<Arc<dyn ServerCertVerifier>>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl TrustVerifier {
+    fn new() -> Result<Self> {
+        Ok(Self {
+            trusted: Mutex::new(Trusted::default()),
+            current: RwLock::new(None),
+            provider: tls::crypto_provider()?,
+        })
+    }
+
+    /// Changes what is trusted with `change`, then loads it all again. Nothing changes if that fails.
+    fn update(&self, change: impl FnOnce(&mut Trusted)) -> Result<()> {
+        let mut trusted = self.trusted.lock().unwrap();
+        let mut changed = trusted.clone();
+        change(&mut changed);
+        let verifier = self.build(&changed)?;
+        *trusted = changed;
+        *self.current.write().unwrap() = verifier;
+        Ok(())
+    }
+
+    fn build(&self, trusted: &Trusted) -> Result<Option<Arc<dyn ServerCertVerifier>>> {
+        let trust = &trusted.trust;
+        if !trust.pins.is_empty() {
+            if !trust.ca_paths.is_empty() || trust.system_roots || !trusted.certs.is_empty() {
+                bail!("pinned certificates can't be combined with certificate authorities");
+            }
+            return Ok(Some(Arc::new(PinnedVerifier {
+                pins: trust.pins.clone(),
+                provider: self.provider.clone(),
+            })));
+        }
+
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in &trusted.certs {
+            roots.add(cert.clone())?;
+        }
+        for path in &trust.ca_paths {
+            for cert in tls::load_certs(path)? {
+                roots
+                    .add(cert)
+                    .with_context(|| format!("invalid certificate in {}", path.display()))?;
+            }
+        }
+        if trust.system_roots {
+            let native = rustls_native_certs::load_native_certs();
+            for e in &native.errors {
+                warn!("Unable to load some of the system's certificates: {}", e);
+            }
+            let (added, _) = roots.add_parsable_certificates(native.certs);
+            if added == 0 {
+                bail!("no certificates found in the system's trust store");
+            }
+        }
+        if roots.is_empty() {
+            return Ok(None);
+        }
+        let verifier =
+            WebPkiServerVerifier::builder_with_provider(Arc::new(roots), self.provider.clone())
+                .build()?;
+        Ok(Some(verifier))
+    }
+
+    fn current(&self) -> Result<Arc<dyn ServerCertVerifier>, rustls::Error> {
+        self.current
+            .read()
+            .unwrap()
+            .clone()
+            .ok_or(rustls::Error::InvalidCertificate(
+                CertificateError::UnknownIssuer,
+            ))
+    }
+}
+
+impl ServerCertVerifier for TrustVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.current()?.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.current()?.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.current()?.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Accepts servers whose certificate matches one of the pins, still checking that they hold its key.
+#[derive(Debug)]
+struct PinnedVerifier {
+    pins: Vec<Pin>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.pins.iter().any(|pin| pin.matches(end_entity)) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "certificate doesn't match any pin".into(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comms::{
+        recv::Server,
+        tls::{
+            RELOAD_INTERVAL,
+            testing::{Ca, Issued, TempDir},
+        },
+    };
+    use std::time::Duration;
+    use tokio::task::JoinHandle;
+
+    /// Serves `issued` on localhost, keeping every connection open, until the task is aborted.
+    fn serve(issued: &Issued) -> (SocketAddr, JoinHandle<()>) {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let server = Server::new(None, Some(issued.identity()), None).unwrap();
+        let endpoint = server.listen("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        let task = tokio::spawn(async move {
+            let mut open = vec![];
+            while let Some(incoming) = server.accept(&endpoint).await {
+                if let Ok(connection) = incoming.await {
+                    open.push(connection);
+                }
+            }
+        });
+        (addr, task)
+    }
+
+    fn trusting(trust: &Trust) -> Result<Client> {
+        let mut client = Client::new("127.0.0.1:0".parse().unwrap(), None)?;
+        client.trust(trust)?;
+        Ok(client)
+    }
+
+    #[tokio::test]
+    async fn trusts_servers_issued_by_a_ca_bundle() {
+        let dir = TempDir::new("trusts_servers_issued_by_a_ca_bundle");
+        let (ca, other) = (Ca::new("ca"), Ca::new("other"));
+        let bundle = dir.0.join("bundle.pem");
+        std::fs::write(&bundle, other.pem() + &ca.pem()).unwrap();
+        let (addr, _server) = serve(&ca.issue("localhost"));
+        let trust = Trust {
+            ca_paths: vec![bundle],
+            ..Trust::default()
+        };
+        let mut client = trusting(&trust).unwrap();
+        client.connect(addr, "localhost").await.unwrap();
+        // The certificate is still checked against the name.
+        assert!(client.connect(addr, "elsewhere").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn trusts_servers_issued_by_a_ca_directory() {
+        let dir = TempDir::new("trusts_servers_issued_by_a_ca_directory");
+        let (ca, other) = (Ca::new("ca"), Ca::new("other"));
+        std::fs::write(dir.0.join("ca.pem"), ca.pem()).unwrap();
+        std::fs::write(dir.0.join("other.pem"), other.pem()).unwrap();
+        let (addr, _server) = serve(&ca.issue("localhost"));
+        let trust = Trust {
+            ca_paths: vec![dir.0.clone()],
+            ..Trust::default()
+        };
+        let mut client = trusting(&trust).unwrap();
+        client.connect(addr, "localhost").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_untrusted_servers() {
+        let dir = TempDir::new("rejects_untrusted_servers");
+        let ca = Ca::new("ca");
+        std::fs::write(dir.0.join("ca.pem"), ca.pem()).unwrap();
+        let (addr, _server) = serve(&Ca::new("other").issue("localhost"));
+        let trust = Trust {
+            ca_paths: vec![dir.0.join("ca.pem")],
+            ..Trust::default()
+        };
+        let mut client = trusting(&trust).unwrap();
+        assert!(client.connect(addr, "localhost").await.is_err());
+        // Nor is anything trusted by default.
+        let mut client = trusting(&Trust::default()).unwrap();
+        assert!(client.connect(addr, "localhost").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn trusts_pinned_certificates() {
+        let issued = Ca::new("ca").issue("localhost");
+        let (addr, _server) = serve(&issued);
+        let pinned = Trust {
+            pins: vec![Pin::certificate(&tls::fingerprint(&issued.cert())).unwrap()],
+            ..Trust::default()
+        };
+        let mut client = trusting(&pinned).unwrap();
+        // Neither the CA nor the name matter once pinned.
+        client.connect(addr, "elsewhere").await.unwrap();
+
+        let wrong = Trust {
+            pins: vec![Pin::certificate(&"ab".repeat(32)).unwrap()],
+            ..Trust::default()
+        };
+        let mut client = trusting(&wrong).unwrap();
+        assert!(client.connect(addr, "localhost").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn trusts_pinned_public_keys() {
+        let issued = Ca::new("ca").issue("localhost");
+        let reissued = Ca::new("other").issue_with_key("localhost", issued.key());
+        let (addr, _server) = serve(&reissued);
+        let pinned = Trust {
+            pins: vec![Pin::public_key(&issued.public_key_sha256()).unwrap()],
+            ..Trust::default()
+        };
+        let mut client = trusting(&pinned).unwrap();
+        client.connect(addr, "elsewhere").await.unwrap();
+
+        let other = Ca::new("ca").issue("localhost");
+        let wrong = Trust {
+            pins: vec![Pin::public_key(&other.public_key_sha256()).unwrap()],
+            ..Trust::default()
+        };
+        let mut client = trusting(&wrong).unwrap();
+        assert!(client.connect(addr, "localhost").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn pins_cant_be_combined_with_cas() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let dir = TempDir::new("pins_cant_be_combined_with_cas");
+        let ca = Ca::new("ca");
+        std::fs::write(dir.0.join("ca.pem"), ca.pem()).unwrap();
+        let pin = Pin::certificate(&"ab".repeat(32)).unwrap();
+        let both = Trust {
+            ca_paths: vec![dir.0.join("ca.pem")],
+            pins: vec![pin],
+            ..Trust::default()
+        };
+        let err = trusting(&both).err().unwrap();
+        assert!(err.to_string().contains("can't be combined"), "{}", err);
+
+        // Nor added to a client that already trusts a certificate.
+        let mut client = Client::new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        client.trust_cert(ca.cert()).unwrap();
+        let pinned = Trust {
+            pins: vec![pin],
+            ..Trust::default()
+        };
+        assert!(client.trust(&pinned).is_err());
+    }
+
+    #[tokio::test]
+    async fn reloads_trust_and_identity() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let dir = TempDir::new("reloads_trust_and_identity");
+        let (old_ca, new_ca, client_ca) =
+            (Ca::new("old-ca"), Ca::new("new-ca"), Ca::new("client-ca"));
+        let server = Server::new(
+            None,
+            Some(new_ca.issue("localhost").identity()),
+            Some(vec![client_ca.cert()]),
+        )
+        .unwrap();
+        let endpoint = server.listen("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        // Both sides have to be happy with the other, so the server's side of the handshake is what counts.
+        let accept = async || Ok::<_, anyhow::Error>(endpoint.accept().await.unwrap().await?);
+
+        let ca_path = dir.0.join("ca.pem");
+        std::fs::write(&ca_path, old_ca.pem()).unwrap();
+        let unknown = Ca::new("unknown-ca").issue("edge-01");
+        let files = unknown.write(&dir.0);
+        let mut client = trusting(&Trust {
+            ca_paths: vec![ca_path.clone()],
+            ..Trust::default()
+        })
+        .unwrap();
+        client.set_identity(unknown.identity()).unwrap();
+        let _watcher = client.watch(Some(files));
+        let (connected, accepted) = tokio::join!(client.connect(addr, "localhost"), accept());
+        assert!(connected.is_err() && accepted.is_err());
+
+        std::fs::write(&ca_path, new_ca.pem()).unwrap();
+        client_ca.issue("edge-01").write(&dir.0);
+        tokio::time::sleep(RELOAD_INTERVAL + Duration::from_secs(1)).await;
+        let (connected, accepted) = tokio::join!(client.connect(addr, "localhost"), accept());
+        connected.unwrap();
+        accepted.unwrap();
+    }
+}
@@ -0,0 +1,638 @@
+use anyhow::{Context, Result, anyhow, bail};
+use aws_lc_rs::digest;
+use rustls::{
+    SignatureScheme,
+    client::ResolvesClientCert,
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, pem::PemObject},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use std::{
+    fmt::Write as _,
+    fs::{self, OpenOptions},
+    io::Write,
+    net::IpAddr,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::{task::JoinHandle, time::MissedTickBehavior};
+use tracing::{info, warn};
+use x509_parser::extensions::GeneralName;
+
+/// How often watched certificate files are checked for changes, see `Server::watch` and `Client::watch`.
+pub const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A certificate chain, leaf first, and the private key of the leaf.
+#[derive(Debug)]
+pub struct Identity {
+    pub cert_chain: Vec<CertificateDer<'static>>,
+    pub key: PrivateKeyDer<'static>,
+}
+
+impl Clone for Identity {
+    fn clone(&self) -> Self {
+        Self {
+            cert_chain: self.cert_chain.clone(),
+            key: self.key.clone_key(),
+        }
+    }
+}
+
+impl Identity {
+    /// Loads the certificate chain and private key from PEM files.
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Self> {
+        let cert_chain = load_certs(cert_path)?;
+        let key = PrivateKeyDer::from_pem_file(key_path)
+            .with_context(|| format!("failed to read a private key from {}", key_path.display()))?;
+        Ok(Self { cert_chain, key })
+    }
+
+    /// Generates a self-signed certificate for `subject_alt_names`, which may be DNS names or IP addresses.
+    pub fn self_signed(subject_alt_names: Vec<String>) -> Result<Self> {
+        let generated = rcgen::generate_simple_self_signed(subject_alt_names)?;
+        Ok(Self {
+            cert_chain: vec![generated.cert.der().clone()],
+            key: PrivatePkcs8KeyDer::from(generated.signing_key.serialize_der()).into(),
+        })
+    }
+
+    /// Loads the identity from `cert_path` and `key_path` if they exist. Otherwise a self-signed certificate
+    /// is generated for `subject_alt_names` and written to them, so that the same one is used from then on.
+    pub fn load_or_generate(
+        cert_path: &Path,
+        key_path: &Path,
+        subject_alt_names: Vec<String>,
+    ) -> Result<Self> {
+        if cert_path.exists() || key_path.exists() {
+            return Self::load(cert_path, key_path);
+        }
+
+        let generated = rcgen::generate_simple_self_signed(subject_alt_names)?;
+        // Written first, so that a failure part way through leaves the key without its certificate and the
+        // next load fails loudly rather than generating a new key over the top of it.
+        write_new(
+            key_path,
+            generated.signing_key.serialize_pem().as_bytes(),
+            0o600,
+        )?;
+        write_new(cert_path, generated.cert.pem().as_bytes(), 0o644)?;
+        info!(
+            "Generated a self-signed certificate in {}",
+            cert_path.display()
+        );
+        Ok(Self {
+            cert_chain: vec![generated.cert.der().clone()],
+            key: PrivatePkcs8KeyDer::from(generated.signing_key.serialize_der()).into(),
+        })
+    }
+
+    /// The certificate identifying us, as opposed to the rest of the chain.
+    pub fn leaf(&self) -> &CertificateDer<'static> {
+        &self.cert_chain[0]
+    }
+}
+
+/// Where an `Identity` is loaded from, so that it can be loaded again when the files change.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdentityFiles {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl IdentityFiles {
+    pub fn load(&self) -> Result<Identity> {
+        Identity::load(&self.cert_path, &self.key_path)
+    }
+}
+
+/// Hands out the certificate we present to peers, which can be replaced without affecting the connections
+/// that are using the old one.
+#[derive(Debug)]
+pub(crate) struct CertResolver {
+    current: RwLock<Option<Arc<CertifiedKey>>>,
+}
+
+impl CertResolver {
+    pub(crate) fn new(identity: Option<Identity>) -> Result<Self> {
+        let resolver = Self {
+            current: RwLock::new(None),
+        };
+        if let Some(identity) = identity {
+            resolver.set(identity)?;
+        }
+        Ok(resolver)
+    }
+
+    /// Fails if the key doesn't belong to the certificate, e.g. because only one of them has been replaced
+    /// so far.
+    pub(crate) fn set(&self, identity: Identity) -> Result<()> {
+        let key = CertifiedKey::from_der(identity.cert_chain, identity.key, &*crypto_provider()?)?;
+        *self.current.write().unwrap() = Some(Arc::new(key));
+        Ok(())
+    }
+
+    pub(crate) fn leaf(&self) -> Option<CertificateDer<'static>> {
+        let current = self.current.read().unwrap();
+        current.as_ref().map(|key| key.cert[0].clone())
+    }
+
+    fn current(&self) -> Option<Arc<CertifiedKey>> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.current()
+    }
+}
+
+impl ResolvesClientCert for CertResolver {
+    fn resolve(
+        &self,
+        _root_hint_subjects: &[&[u8]],
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>> {
+        self.current()
+    }
+
+    fn has_certs(&self) -> bool {
+        self.current.read().unwrap().is_some()
+    }
+}
+
+/// The crypto provider installed for the process, which rustls uses for everything else too.
+pub(crate) fn crypto_provider() -> Result<Arc<CryptoProvider>> {
+    CryptoProvider::get_default()
+        .cloned()
+        .context("no rustls crypto provider has been installed")
+}
+
+/// Stops watching files once dropped, see `watch`.
+#[must_use = "files are only watched until the `Watcher` is dropped"]
+pub struct Watcher(Option<JoinHandle<()>>);
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        if let Some(task) = &self.0 {
+            task.abort();
+        }
+    }
+}
+
+/// Calls `reload` every time any of `paths` change, checking every `RELOAD_INTERVAL`. A failed reload is tried
+/// again when the files next change, as files that are replaced together (e.g. a certificate and its key)
+/// aren't replaced at exactly the same time.
+pub(crate) fn watch(
+    paths: Vec<PathBuf>,
+    mut reload: impl FnMut() -> Result<()> + Send + 'static,
+) -> Watcher {
+    if paths.is_empty() {
+        return Watcher(None);
+    }
+    let modified_times = move || paths.iter().map(|path| modified(path)).collect::<Vec<_>>();
+    let mut loaded = modified_times();
+    Watcher(Some(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // When reloading last failed, so that each change is only warned about once.
+        let mut failed = None;
+        loop {
+            interval.tick().await;
+            let modified = modified_times();
+            if modified == loaded || failed.as_ref() == Some(&modified) {
+                continue;
+            }
+            match reload() {
+                Ok(()) => {
+                    loaded = modified;
+                    failed = None;
+                }
+                Err(e) => {
+                    warn!("Unable to reload certificates, will retry: {:#}", e);
+                    failed = Some(modified);
+                }
+            }
+        }
+    })))
+}
+
+/// When `path` was last modified, or anything in it if it's a directory.
+fn modified(path: &Path) -> Option<SystemTime> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    if !path.is_dir() {
+        return Some(modified);
+    }
+    let entries = fs::read_dir(path).ok()?;
+    entries
+        .filter_map(|entry| {
+            fs::metadata(entry.ok()?.path())
+                .and_then(|m| m.modified())
+                .ok()
+        })
+        .chain([modified])
+        .max()
+}
+
+/// Loads every certificate in a PEM file, or in every file in a directory of them, failing if there aren't
+/// any.
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let mut certs = vec![];
+    if path.is_dir() {
+        let mut files = fs::read_dir(path)
+            .and_then(|entries| {
+                entries
+                    .map(|entry| entry.map(|entry| entry.path()))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .with_context(|| format!("failed to list {}", path.display()))?;
+        files.sort();
+        for file in files {
+            let hidden = file
+                .file_name()
+                .is_some_and(|name| name.as_encoded_bytes().starts_with(b"."));
+            if file.is_file() && !hidden {
+                certs.extend(read_certs(&file)?);
+            }
+        }
+    } else {
+        certs = read_certs(path)?;
+    }
+    if certs.is_empty() {
+        bail!("no certificates found in {}", path.display());
+    }
+    Ok(certs)
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("failed to read certificates from {}", path.display()))
+}
+
+/// Writes `contents` to a file that must not already exist.
+fn write_new(path: &Path, contents: &[u8], mode: u32) -> Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(path)
+        .and_then(|mut file| file.write_all(contents))
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// Who the peer on the other end of a connection authenticated as, taken from the certificate it presented.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerIdentity {
+    /// The certificate's subject, e.g. "CN=edge-01, O=Example".
+    pub subject: String,
+    pub common_name: Option<String>,
+    /// DNS names, IP addresses, email addresses and URIs the certificate was issued for.
+    pub subject_alt_names: Vec<String>,
+    /// Hex encoded SHA-256 of the certificate.
+    pub fingerprint: String,
+}
+
+impl PeerIdentity {
+    pub fn from_cert(cert: &CertificateDer<'_>) -> Result<Self> {
+        let (_, parsed) = x509_parser::parse_x509_certificate(cert)
+            .map_err(|e| anyhow!("invalid certificate: {}", e))?;
+        let common_name = parsed
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(str::to_string);
+        let subject_alt_names = match parsed.subject_alternative_name() {
+            Ok(Some(sans)) => sans
+                .value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name)
+                    | GeneralName::RFC822Name(name)
+                    | GeneralName::URI(name) => Some(name.to_string()),
+                    GeneralName::IPAddress(ip) => ip_to_string(ip),
+                    _ => None,
+                })
+                .collect(),
+            _ => vec![],
+        };
+        Ok(Self {
+            subject: parsed.subject().to_string(),
+            common_name,
+            subject_alt_names,
+            fingerprint: fingerprint(cert),
+        })
+    }
+
+    /// The name that best identifies the peer: its first subject alternative name, or failing that its common
+    /// name, or failing that its fingerprint.
+    pub fn name(&self) -> &str {
+        self.subject_alt_names
+            .first()
+            .or(self.common_name.as_ref())
+            .unwrap_or(&self.fingerprint)
+    }
+
+    /// Whether the certificate was issued for `name`, as its common name or any subject alternative name.
+    pub fn matches(&self, name: &str) -> bool {
+        self.common_name.as_deref() == Some(name)
+            || self.subject_alt_names.iter().any(|san| san == name)
+    }
+}
+
+fn ip_to_string(ip: &[u8]) -> Option<String> {
+    match ip.len() {
+        4 => Some(IpAddr::from(<[u8; 4]>::try_from(ip).ok()?).to_string()),
+        16 => Some(IpAddr::from(<[u8; 16]>::try_from(ip).ok()?).to_string()),
+        _ => None,
+    }
+}
+
+/// Hex encoded SHA-256 of `cert`.
+pub fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    let hash = digest::digest(&digest::SHA256, cert);
+    hash.as_ref().iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    })
+}
+
+/// The SHA-256 of a certificate we expect a peer to present, either of the whole certificate or of just its
+/// public key (the DER encoded SubjectPublicKeyInfo), which stays the same if it is reissued for the same key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pin {
+    Certificate([u8; 32]),
+    PublicKey([u8; 32]),
+}
+
+impl Pin {
+    /// Parses the hex encoded fingerprint of a certificate, as in the `peer_fingerprint` tag or the output of
+    /// `openssl x509 -fingerprint -sha256`.
+    pub fn certificate(hex: &str) -> Result<Self> {
+        Ok(Self::Certificate(decode_sha256(hex)?))
+    }
+
+    /// Parses the hex encoded SHA-256 of a public key, e.g. from
+    /// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | sha256sum`.
+    pub fn public_key(hex: &str) -> Result<Self> {
+        Ok(Self::PublicKey(decode_sha256(hex)?))
+    }
+
+    pub fn matches(&self, cert: &CertificateDer<'_>) -> bool {
+        match self {
+            Self::Certificate(hash) => digest::digest(&digest::SHA256, cert).as_ref() == hash,
+            Self::PublicKey(hash) => {
+                x509_parser::parse_x509_certificate(cert).is_ok_and(|(_, cert)| {
+                    digest::digest(&digest::SHA256, cert.public_key().raw).as_ref() == hash
+                })
+            }
+        }
+    }
+}
+
+/// Decodes a hex encoded SHA-256, which may have its bytes separated by colons.
+fn decode_sha256(hex: &str) -> Result<[u8; 32]> {
+    let digits: Vec<u32> = hex
+        .chars()
+        .filter(|c| *c != ':')
+        .map(|c| c.to_digit(16))
+        .collect::<Option<_>>()
+        .with_context(|| format!("`{}` isn't a SHA-256, expected hex digits", hex))?;
+    if digits.len() != 64 {
+        bail!("`{}` isn't a SHA-256, expected 64 hex digits", hex);
+    }
+    let mut hash = [0; 32];
+    for (byte, pair) in hash.iter_mut().zip(digits.chunks(2)) {
+        *byte = (pair[0] * 16 + pair[1]) as u8;
+    }
+    Ok(hash)
+}
+
+/// Certificates for tests, issued by CAs made up on the spot.
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use rcgen::{
+        BasicConstraints, CertificateParams, CertifiedIssuer, DnType, ExtendedKeyUsagePurpose,
+        IsCa, KeyPair, KeyUsagePurpose, PublicKeyData,
+    };
+
+    pub(crate) struct Ca(CertifiedIssuer<'static, KeyPair>);
+
+    impl Ca {
+        pub(crate) fn new(name: &str) -> Self {
+            let mut params = CertificateParams::default();
+            params.distinguished_name.push(DnType::CommonName, name);
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+            Self(CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap())
+        }
+
+        pub(crate) fn cert(&self) -> CertificateDer<'static> {
+            self.0.der().clone()
+        }
+
+        pub(crate) fn pem(&self) -> String {
+            self.0.pem()
+        }
+
+        /// A certificate for `name`, as both its common name and its only subject alternative name, that
+        /// servers and clients alike can use.
+        pub(crate) fn issue(&self, name: &str) -> Issued {
+            self.issue_with_key(name, KeyPair::generate().unwrap())
+        }
+
+        pub(crate) fn issue_with_key(&self, name: &str, key: KeyPair) -> Issued {
+            let mut params = CertificateParams::new(vec![name.to_string()]).unwrap();
+            params.distinguished_name.push(DnType::CommonName, name);
+            params.extended_key_usages = vec![
+                ExtendedKeyUsagePurpose::ServerAuth,
+                ExtendedKeyUsagePurpose::ClientAuth,
+            ];
+            let cert = params.signed_by(&key, &self.0).unwrap();
+            Issued {
+                cert_pem: cert.pem(),
+                key_pem: key.serialize_pem(),
+            }
+        }
+    }
+
+    pub(crate) struct Issued {
+        pub(crate) cert_pem: String,
+        pub(crate) key_pem: String,
+    }
+
+    impl Issued {
+        pub(crate) fn identity(&self) -> Identity {
+            Identity {
+                cert_chain: vec![self.cert()],
+                key: PrivateKeyDer::from_pem_slice(self.key_pem.as_bytes()).unwrap(),
+            }
+        }
+
+        pub(crate) fn cert(&self) -> CertificateDer<'static> {
+            CertificateDer::from_pem_slice(self.cert_pem.as_bytes()).unwrap()
+        }
+
+        pub(crate) fn key(&self) -> KeyPair {
+            KeyPair::from_pem(&self.key_pem).unwrap()
+        }
+
+        /// The hex encoded SHA-256 of the public key, as `Pin::public_key` expects it.
+        pub(crate) fn public_key_sha256(&self) -> String {
+            let spki = self.key().subject_public_key_info();
+            let hash = digest::digest(&digest::SHA256, &spki);
+            hash.as_ref().iter().fold(String::new(), |mut s, b| {
+                let _ = write!(s, "{:02x}", b);
+                s
+            })
+        }
+
+        /// Writes the certificate and key to `cert.pem` and `key.pem` in `dir`, replacing whatever was there.
+        pub(crate) fn write(&self, dir: &Path) -> IdentityFiles {
+            let files = IdentityFiles {
+                cert_path: dir.join("cert.pem"),
+                key_path: dir.join("key.pem"),
+            };
+            fs::write(&files.cert_path, &self.cert_pem).unwrap();
+            fs::write(&files.key_path, &self.key_pem).unwrap();
+            files
+        }
+    }
+
+    /// A directory of its own, removed once the test is done with it.
+    pub(crate) struct TempDir(pub(crate) PathBuf);
+
+    impl TempDir {
+        pub(crate) fn new(test: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("logga-tls-{}-{}", std::process::id(), test));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::{Ca, TempDir};
+
+    #[test]
+    fn parses_pins() {
+        let hex = "ab".repeat(32);
+        assert_eq!(
+            Pin::certificate(&hex).unwrap(),
+            Pin::Certificate([0xab; 32])
+        );
+        let colons = vec!["AB"; 32].join(":");
+        assert_eq!(
+            Pin::public_key(&colons).unwrap(),
+            Pin::PublicKey([0xab; 32])
+        );
+        assert!(Pin::certificate(&"ab".repeat(31)).is_err());
+        assert!(Pin::certificate(&format!("{}zz", "ab".repeat(31))).is_err());
+    }
+
+    #[test]
+    fn pins_match_certificates() {
+        let ca = Ca::new("ca");
+        let issued = ca.issue("edge-01");
+        let other = ca.issue("edge-01");
+        let pin = Pin::certificate(&fingerprint(&issued.cert())).unwrap();
+        assert!(pin.matches(&issued.cert()));
+        assert!(!pin.matches(&other.cert()));
+    }
+
+    #[test]
+    fn public_key_pins_match_reissued_certificates() {
+        let ca = Ca::new("ca");
+        let issued = ca.issue("edge-01");
+        let reissued = Ca::new("another-ca").issue_with_key("edge-02", issued.key());
+        let pin = Pin::public_key(&issued.public_key_sha256()).unwrap();
+        assert!(pin.matches(&issued.cert()));
+        assert!(pin.matches(&reissued.cert()));
+        assert!(!pin.matches(&ca.issue("edge-01").cert()));
+    }
+
+    #[test]
+    fn loads_every_certificate_in_a_file() {
+        let dir = TempDir::new("loads_every_certificate_in_a_file");
+        let (first, second) = (Ca::new("first"), Ca::new("second"));
+        let path = dir.0.join("bundle.pem");
+        fs::write(&path, first.pem() + &second.pem()).unwrap();
+        assert_eq!(load_certs(&path).unwrap(), [first.cert(), second.cert()]);
+    }
+
+    #[test]
+    fn loads_certificates_from_a_directory() {
+        let dir = TempDir::new("loads_certificates_from_a_directory");
+        let (first, second, hidden) = (Ca::new("first"), Ca::new("second"), Ca::new("hidden"));
+        fs::write(dir.0.join("b.pem"), second.pem()).unwrap();
+        fs::write(dir.0.join("a.pem"), first.pem()).unwrap();
+        fs::write(dir.0.join(".hidden.pem"), hidden.pem()).unwrap();
+        fs::create_dir(dir.0.join("nested")).unwrap();
+        fs::write(dir.0.join("nested/c.pem"), hidden.pem()).unwrap();
+        assert_eq!(load_certs(&dir.0).unwrap(), [first.cert(), second.cert()]);
+    }
+
+    #[test]
+    fn loading_no_certificates_fails() {
+        let dir = TempDir::new("loading_no_certificates_fails");
+        assert!(load_certs(&dir.0).is_err());
+        let empty = dir.0.join("empty.pem");
+        fs::write(&empty, "").unwrap();
+        assert!(load_certs(&empty).is_err());
+        assert!(load_certs(&dir.0.join("missing.pem")).is_err());
+    }
+
+    #[test]
+    fn peer_identity_from_cert() {
+        let cert = Ca::new("ca").issue("edge-01").cert();
+        let identity = PeerIdentity::from_cert(&cert).unwrap();
+        assert_eq!(identity.subject, "CN=edge-01");
+        assert_eq!(identity.common_name.as_deref(), Some("edge-01"));
+        assert_eq!(identity.subject_alt_names, ["edge-01"]);
+        assert_eq!(identity.fingerprint, fingerprint(&cert));
+        assert!(identity.matches("edge-01"));
+        assert!(!identity.matches("edge-02"));
+    }
+
+    #[test]
+    fn peer_identity_name_falls_back() {
+        let mut identity = PeerIdentity {
+            subject: "CN=edge-01".into(),
+            common_name: Some("edge-01".into()),
+            subject_alt_names: vec!["edge-01.example.com".into(), "10.0.0.1".into()],
+            fingerprint: "ab".repeat(32),
+        };
+        assert_eq!(identity.name(), "edge-01.example.com");
+        assert!(identity.matches("10.0.0.1"));
+        identity.subject_alt_names.clear();
+        assert_eq!(identity.name(), "edge-01");
+        identity.common_name = None;
+        assert_eq!(identity.name(), "ab".repeat(32));
+        assert!(!identity.matches("edge-01"));
+    }
+
+    #[test]
+    fn peer_identity_formats_ip_addresses() {
+        assert_eq!(ip_to_string(&[10, 0, 0, 1]).as_deref(), Some("10.0.0.1"));
+        let mut v6 = [0; 16];
+        v6[15] = 1;
+        assert_eq!(ip_to_string(&v6).as_deref(), Some("::1"));
+        assert_eq!(ip_to_string(&[1, 2, 3]), None);
+    }
+}
@@ -0,0 +1,162 @@
+//! Tuning for the QUIC connections between agents, shared by `Server` and `Client`. The defaults suit a LAN or
+//! a short WAN hop. For long, fat links raise the windows to at least bandwidth × round trip time, and
+//! consider BBR, which copes better with loss that isn't caused by congestion.
+
+use anyhow::{Context, Result, bail};
+use quinn::{
+    MtuDiscoveryConfig, TransportConfig, VarInt,
+    congestion::{BbrConfig, CubicConfig, NewRenoConfig},
+};
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+/// Algorithms to control how fast to send.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Congestion {
+    #[default]
+    Cubic,
+    NewReno,
+    Bbr,
+}
+
+impl FromStr for Congestion {
+    type Err = anyhow::Error;
+
+    /// One of "cubic", "new_reno" or "bbr".
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "cubic" => Self::Cubic,
+            "new_reno" => Self::NewReno,
+            "bbr" => Self::Bbr,
+            _ => bail!(
+                "unknown congestion controller `{}`, expected cubic, new_reno or bbr",
+                s
+            ),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransportOptions {
+    /// How long without hearing from the peer before a connection is considered lost, or a handshake failed.
+    /// The shorter of the two ends' applies, zero never times out.
+    pub idle_timeout: Duration,
+    /// How often to check that an otherwise idle connection is still there, never if `None`. Must be shorter
+    /// than the idle timeout to keep idle connections open.
+    pub keep_alive_interval: Option<Duration>,
+    /// Bytes the peer may send on each stream before we have read them.
+    pub stream_receive_window: u64,
+    /// Bytes the peer may send on all streams together before we have read them, only limited by
+    /// `stream_receive_window` if `None`.
+    pub receive_window: Option<u64>,
+    /// Bytes we may have sent but not had acknowledged, across all streams.
+    pub send_window: u64,
+    pub congestion: Congestion,
+    /// Probe for a larger packet size than the minimum every path supports.
+    pub mtu_discovery: bool,
+    /// Streams the peer may have open at once, in each direction.
+    pub max_concurrent_streams: u32,
+}
+
+impl Default for TransportOptions {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Duration::from_secs(15),
+            keep_alive_interval: Some(Duration::from_secs(5)),
+            // quinn's defaults, enough for 100 Mbit/s at a 100ms round trip.
+            stream_receive_window: 1_250_000,
+            receive_window: None,
+            send_window: 10_000_000,
+            congestion: Congestion::default(),
+            mtu_discovery: true,
+            max_concurrent_streams: 1024,
+        }
+    }
+}
+
+impl TransportOptions {
+    pub fn config(&self) -> Result<TransportConfig> {
+        let mut config = TransportConfig::default();
+        config
+            .max_idle_timeout(Some(
+                self.idle_timeout
+                    .try_into()
+                    .context("idle timeout is too long")?,
+            ))
+            .keep_alive_interval(self.keep_alive_interval)
+            .stream_receive_window(
+                VarInt::from_u64(self.stream_receive_window)
+                    .context("stream receive window is too large")?,
+            )
+            .receive_window(match self.receive_window {
+                Some(window) => VarInt::from_u64(window).context("receive window is too large")?,
+                None => VarInt::MAX,
+            })
+            .send_window(self.send_window)
+            .mtu_discovery_config(self.mtu_discovery.then(MtuDiscoveryConfig::default))
+            .max_concurrent_uni_streams(self.max_concurrent_streams.into())
+            .max_concurrent_bidi_streams(self.max_concurrent_streams.into());
+        match self.congestion {
+            Congestion::Cubic => {
+                config.congestion_controller_factory(Arc::new(CubicConfig::default()))
+            }
+            Congestion::NewReno => {
+                config.congestion_controller_factory(Arc::new(NewRenoConfig::default()))
+            }
+            Congestion::Bbr => config.congestion_controller_factory(Arc::new(BbrConfig::default())),
+        };
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_to_quinn_settings() {
+        let options = TransportOptions {
+            idle_timeout: Duration::from_millis(1500),
+            keep_alive_interval: None,
+            stream_receive_window: 1000,
+            receive_window: Some(5000),
+            send_window: 7000,
+            congestion: Congestion::Bbr,
+            mtu_discovery: false,
+            max_concurrent_streams: 3,
+        };
+        // quinn has no getters, only its `Debug` shows what was set.
+        let config = format!("{:?}", options.config().unwrap());
+        for setting in [
+            "max_concurrent_bidi_streams: 3,",
+            "max_concurrent_uni_streams: 3,",
+            "max_idle_timeout: Some(1500),",
+            "stream_receive_window: 1000,",
+            "receive_window: 5000,",
+            "send_window: 7000,",
+            "mtu_discovery_config: None,",
+            "keep_alive_interval: None,",
+        ] {
+            assert!(config.contains(setting), "{} not in {}", setting, config);
+        }
+
+        let config = format!("{:?}", TransportOptions::default().config().unwrap());
+        for setting in [
+            "max_idle_timeout: Some(15000),",
+            // No limit beyond each stream's own.
+            &format!("receive_window: {},", VarInt::MAX),
+            "mtu_discovery_config: Some(",
+            "keep_alive_interval: Some(5s),",
+        ] {
+            assert!(config.contains(setting), "{} not in {}", setting, config);
+        }
+    }
+
+    #[test]
+    fn rejects_windows_quic_cant_express() {
+        let options = TransportOptions {
+            stream_receive_window: u64::MAX,
+            ..TransportOptions::default()
+        };
+        assert!(options.config().is_err());
+    }
+}
@@ -0,0 +1,1194 @@
+use anyhow::{Result as AnyResult, bail};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Deserializer, de::DeserializeOwned, de::Error as _};
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    fmt,
+    fmt::Display,
+    net::{Ipv4Addr, SocketAddr},
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::mpsc::Sender;
+use toml::{
+    Spanned,
+    de::{DeTable, DeValue, ValueDeserializer},
+};
+
+use crate::{
+    agent::Config,
+    checkpoint::CheckpointStore,
+    comms::{
+        pool::{Balance, EventKey, Peer, ReconnectPolicy},
+        recv::ServerOptions,
+        send::Trust,
+        tls::{Identity, IdentityFiles, Pin},
+        transport::{Congestion, TransportOptions},
+    },
+    event::Event,
+    module::{
+        DiscoveryOptions, FileDiscovery, FileSink, FileSource, FileSourceOptions, Filter,
+        FlushPolicy, Input, QUICSink, QUICSinkOptions, QUICSource, QUICSourceOptions, Sink, Source,
+        Transform,
+    },
+};
+
+type PathError = serde_path_to_error::Error<toml::de::Error>;
+/// Deserializes a component's fields in to the config of the type it was registered as.
+type DeserializeComponent<T> = fn(Spanned<DeValue>) -> Result<T, PathError>;
+
+/// Every type of component that can be used in a config, keyed by `type`. Adding a type only takes
+/// implementing `BuildSource`, `BuildTransform` or `BuildSink` for its config and registering it on the
+/// `Registry` given to `Config::load` and `Agent::new`, which other crates can do as well as this one.
+#[derive(Clone)]
+pub struct Registry {
+    sources: BTreeMap<&'static str, DeserializeComponent<SourceConfig>>,
+    transforms: BTreeMap<&'static str, DeserializeComponent<TransformConfig>>,
+    sinks: BTreeMap<&'static str, DeserializeComponent<SinkConfig>>,
+}
+
+impl Default for Registry {
+    /// The built in types.
+    fn default() -> Self {
+        let mut registry = Self {
+            sources: BTreeMap::new(),
+            transforms: BTreeMap::new(),
+            sinks: BTreeMap::new(),
+        };
+        registry
+            // Follows a single file, see `FileSource`.
+            .source::<FileSourceConfig>("file")
+            // Follows every file matching a set of glob patterns, see `FileDiscovery`.
+            .source::<FileGlobSourceConfig>("file_glob")
+            // Receives events from remote agents, see `QUICSource`.
+            .source::<QuicSourceConfig>("quic")
+            // Only lets through records containing `pattern` (or not containing it, if `invert` is set).
+            .transform::<FilterTransformConfig>("filter")
+            // Appends records to a file, see `FileSink`.
+            .sink::<FileSinkConfig>("file")
+            // Ships records to remote agents' `quic` sources, see `QUICSink`.
+            .sink::<QuicSinkConfig>("quic");
+        registry
+    }
+}
+
+impl Registry {
+    /// Sources with `type = kind` are configured by a `T`, replacing any type already registered as `kind`.
+    pub fn source<T: BuildSource + DeserializeOwned>(&mut self, kind: &'static str) -> &mut Self {
+        self.sources.insert(kind, |value| {
+            deserialize::<T>(value).map(|config| SourceConfig(Arc::new(config)))
+        });
+        self
+    }
+
+    /// Transforms with `type = kind` are configured by a `T`, replacing any type already registered as `kind`.
+    pub fn transform<T: BuildTransform + DeserializeOwned>(
+        &mut self,
+        kind: &'static str,
+    ) -> &mut Self {
+        self.transforms.insert(kind, |value| {
+            deserialize::<T>(value).map(|config| TransformConfig(Arc::new(config)))
+        });
+        self
+    }
+
+    /// Sinks with `type = kind` are configured by a `T`, replacing any type already registered as `kind`.
+    pub fn sink<T: BuildSink + DeserializeOwned>(&mut self, kind: &'static str) -> &mut Self {
+        self.sinks.insert(kind, |value| {
+            deserialize::<T>(value).map(|config| SinkConfig(Arc::new(config)))
+        });
+        self
+    }
+}
+
+/// Lets configs of different types be compared, to tell whether a component's config changed on reload.
+pub trait DynEq: Any {
+    fn dyn_eq(&self, other: &dyn Any) -> bool;
+}
+
+impl<T: PartialEq + Any> DynEq for T {
+    fn dyn_eq(&self, other: &dyn Any) -> bool {
+        other.downcast_ref::<T>() == Some(self)
+    }
+}
+
+/// The config of a type of source, see `Registry`.
+pub trait BuildSource: DynEq + fmt::Debug + Send + Sync {
+    /// Constructs the source, sending its records to `outputs`.
+    fn build<'a>(
+        &'a self,
+        name: &'a str,
+        outputs: Vec<Sender<Event>>,
+        checkpoints: &'a CheckpointStore,
+    ) -> BoxFuture<'a, AnyResult<Box<dyn Source>>>;
+}
+
+/// The config of a type of transform, see `Registry`.
+pub trait BuildTransform: DynEq + fmt::Debug + Send + Sync {
+    /// Names of the components the transform reads from.
+    fn inputs(&self) -> &[String];
+
+    fn build(&self, name: &str) -> Box<dyn Transform>;
+}
+
+/// The config of a type of sink, see `Registry`.
+pub trait BuildSink: DynEq + fmt::Debug + Send + Sync {
+    /// Names of the components the sink reads from.
+    fn inputs(&self) -> &[String];
+
+    /// Constructs the sink, reading its records from `input`.
+    fn build<'a>(&'a self, name: &'a str, input: Input) -> BoxFuture<'a, AnyResult<Box<dyn Sink>>>;
+}
+
+/// Where to find a source's data and how to split it in to records, as configured for whichever type the
+/// source is.
+#[derive(Debug, Clone)]
+pub struct SourceConfig(Arc<dyn BuildSource>);
+
+impl SourceConfig {
+    pub async fn build(
+        &self,
+        name: &str,
+        outputs: Vec<Sender<Event>>,
+        checkpoints: &CheckpointStore,
+    ) -> AnyResult<Box<dyn Source>> {
+        self.0.build(name, outputs, checkpoints).await
+    }
+}
+
+impl PartialEq for SourceConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.dyn_eq(&*other.0 as &dyn Any)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileSourceConfig {
+    pub path: PathBuf,
+    #[serde(default = "default_delimiter", deserialize_with = "delimiter")]
+    pub delimiter: String,
+    /// Records longer than this many bytes are split in to several.
+    #[serde(default, deserialize_with = "optional_non_zero")]
+    pub max_record_len: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileGlobSourceConfig {
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default = "default_delimiter", deserialize_with = "delimiter")]
+    pub delimiter: String,
+    #[serde(default, deserialize_with = "optional_non_zero")]
+    pub rescan_interval_ms: Option<u64>,
+    #[serde(default)]
+    pub max_open_files: Option<usize>,
+    /// Records longer than this many bytes are split in to several.
+    #[serde(default, deserialize_with = "optional_non_zero")]
+    pub max_record_len: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuicSourceConfig {
+    #[serde(default = "default_listen_addr")]
+    pub listen_addr: Ipv4Addr,
+    pub listen_port: u16,
+    /// Without this, the source uses a new self-signed certificate on every start that no sink can trust.
+    #[serde(default)]
+    pub tls: Option<TlsIdentityConfig>,
+    /// PEM file with the certificates that peers' certificates must be issued by. Anyone can connect without
+    /// this, with it peers must authenticate with a certificate of their own.
+    #[serde(default)]
+    pub client_ca_path: Option<PathBuf>,
+    /// Names (common names or subject alternative names) a peer's certificate must have been issued for.
+    /// Any peer the `client_ca_path` certificates vouch for may connect if empty.
+    #[serde(default)]
+    pub allowed_peers: Vec<String>,
+    /// Connections to have open at once, further peers are refused until some close. No limit if unspecified.
+    #[serde(default, deserialize_with = "optional_non_zero")]
+    pub max_connections: Option<usize>,
+    #[serde(default)]
+    pub transport: QuicTransportConfig,
+    /// Longest batch accepted, in bytes.
+    #[serde(default, deserialize_with = "optional_non_zero")]
+    pub max_frame_len: Option<usize>,
+    #[serde(default, deserialize_with = "optional_non_zero")]
+    pub max_batch_events: Option<usize>,
+    #[serde(default, deserialize_with = "optional_non_zero")]
+    pub peer_events_per_sec: Option<usize>,
+    #[serde(default, deserialize_with = "optional_non_zero")]
+    pub peer_bytes_per_sec: Option<usize>,
+    /// Bytes of received events that can be waiting to be acknowledged at once, across all peers.
+    #[serde(default, deserialize_with = "optional_non_zero")]
+    pub max_in_flight_bytes: Option<usize>,
+}
+
+impl QuicSourceConfig {
+    pub fn options(&self) -> QUICSourceOptions {
+        let defaults = QUICSourceOptions::default();
+        QUICSourceOptions {
+            server: ServerOptions {
+                transport: self.transport.options(),
+                max_connections: self.max_connections,
+            },
+            max_frame_len: self.max_frame_len.unwrap_or(defaults.max_frame_len),
+            max_batch_events: self.max_batch_events.unwrap_or(defaults.max_batch_events),
+            peer_events_per_sec: self.peer_events_per_sec.map(|rate| rate as u64),
+            peer_bytes_per_sec: self.peer_bytes_per_sec.map(|rate| rate as u64),
+            max_in_flight_bytes: self
+                .max_in_flight_bytes
+                .unwrap_or(defaults.max_in_flight_bytes),
+        }
+    }
+}
+
+/// Tuning for QUIC connections, see `TransportOptions`. Each defaults to the same if unspecified.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuicTransportConfig {
+    /// 0 never times out.
+    #[serde(default)]
+    pub idle_timeout_ms: Option<u64>,
+    /// 0 sends no keep-alives.
+    #[serde(default)]
+    pub keep_alive_interval_ms: Option<u64>,
+    #[serde(default, deserialize_with = "optional_non_zero")]
+    pub stream_receive_window: Option<usize>,
+    #[serde(default, deserialize_with = "optional_non_zero")]
+    pub receive_window: Option<usize>,
+    #[serde(default, deserialize_with = "optional_non_zero")]
+    pub send_window: Option<usize>,
+    /// One of "cubic", "new_reno" or "bbr".
+    #[serde(default, deserialize_with = "optional_parsed")]
+    pub congestion_controller: Option<Congestion>,
+    #[serde(default)]
+    pub mtu_discovery: Option<bool>,
+    #[serde(default, deserialize_with = "optional_non_zero")]
+    pub max_concurrent_streams: Option<usize>,
+}
+
+impl QuicTransportConfig {
+    pub fn options(&self) -> TransportOptions {
+        let defaults = TransportOptions::default();
+        TransportOptions {
+            idle_timeout: self
+                .idle_timeout_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.idle_timeout),
+            keep_alive_interval: match self.keep_alive_interval_ms {
+                Some(0) => None,
+                Some(ms) => Some(Duration::from_millis(ms)),
+                None => defaults.keep_alive_interval,
+            },
+            stream_receive_window: self
+                .stream_receive_window
+                .map_or(defaults.stream_receive_window, |window| window as u64),
+            receive_window: self
+                .receive_window
+                .map(|window| window as u64)
+                .or(defaults.receive_window),
+            send_window: self
+                .send_window
+                .map_or(defaults.send_window, |window| window as u64),
+            congestion: self.congestion_controller.unwrap_or(defaults.congestion),
+            mtu_discovery: self.mtu_discovery.unwrap_or(defaults.mtu_discovery),
+            max_concurrent_streams: self
+                .max_concurrent_streams
+                .map_or(defaults.max_concurrent_streams, |max| {
+                    u32::try_from(max).unwrap_or(u32::MAX)
+                }),
+        }
+    }
+}
+
+/// The certificate an agent presents to its peers.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsIdentityConfig {
+    /// PEM file with the certificate chain, leaf first.
+    pub cert_path: PathBuf,
+    /// PEM file with the private key of the leaf certificate.
+    pub key_path: PathBuf,
+    /// Generate a self-signed certificate and key in to `cert_path` and `key_path` if neither exists yet.
+    /// Peers can then trust `cert_path`.
+    #[serde(default)]
+    pub generate: bool,
+    /// Names (or IP addresses) the generated certificate is valid for.
+    #[serde(default = "default_subject_alt_names")]
+    pub subject_alt_names: Vec<String>,
+}
+
+impl TlsIdentityConfig {
+    /// Loads the identity, generating it first if asked to and it doesn't exist yet, along with the files to
+    /// reload it from.
+    pub fn load(&self) -> AnyResult<(Identity, IdentityFiles)> {
+        let identity = if self.generate {
+            Identity::load_or_generate(
+                &self.cert_path,
+                &self.key_path,
+                self.subject_alt_names.clone(),
+            )?
+        } else {
+            Identity::load(&self.cert_path, &self.key_path)?
+        };
+        let files = IdentityFiles {
+            cert_path: self.cert_path.clone(),
+            key_path: self.key_path.clone(),
+        };
+        Ok((identity, files))
+    }
+}
+
+fn default_subject_alt_names() -> Vec<String> {
+    vec![default_server_name()]
+}
+
+fn default_listen_addr() -> Ipv4Addr {
+    Ipv4Addr::UNSPECIFIED
+}
+
+impl FileGlobSourceConfig {
+    pub fn discovery_options(&self) -> DiscoveryOptions {
+        let defaults = DiscoveryOptions::default();
+        DiscoveryOptions {
+            rescan_interval: self
+                .rescan_interval_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.rescan_interval),
+            max_open_files: self.max_open_files.unwrap_or(defaults.max_open_files),
+            source: file_source_options(self.max_record_len),
+        }
+    }
+}
+
+impl FileSourceConfig {
+    pub fn options(&self) -> FileSourceOptions {
+        file_source_options(self.max_record_len)
+    }
+}
+
+fn file_source_options(max_record_len: Option<usize>) -> FileSourceOptions {
+    let defaults = FileSourceOptions::default();
+    FileSourceOptions {
+        max_record_len: max_record_len.unwrap_or(defaults.max_record_len),
+    }
+}
+
+impl BuildSource for FileSourceConfig {
+    fn build<'a>(
+        &'a self,
+        name: &'a str,
+        outputs: Vec<Sender<Event>>,
+        checkpoints: &'a CheckpointStore,
+    ) -> BoxFuture<'a, AnyResult<Box<dyn Source>>> {
+        Box::pin(async move {
+            let source = FileSource::new(
+                name.to_string(),
+                self.path.clone(),
+                self.delimiter.clone().into_bytes(),
+                outputs,
+                Some(checkpoints.clone()),
+                Some(self.options()),
+            )
+            .await?;
+            Ok(Box::new(source) as Box<dyn Source>)
+        })
+    }
+}
+
+impl BuildSource for FileGlobSourceConfig {
+    fn build<'a>(
+        &'a self,
+        name: &'a str,
+        outputs: Vec<Sender<Event>>,
+        checkpoints: &'a CheckpointStore,
+    ) -> BoxFuture<'a, AnyResult<Box<dyn Source>>> {
+        Box::pin(async move {
+            let source = FileDiscovery::new(
+                name.to_string(),
+                self.include.clone(),
+                &self.exclude,
+                self.delimiter.clone().into_bytes(),
+                outputs,
+                Some(checkpoints.clone()),
+                Some(self.discovery_options()),
+            )?;
+            Ok(Box::new(source) as Box<dyn Source>)
+        })
+    }
+}
+
+impl BuildSource for QuicSourceConfig {
+    fn build<'a>(
+        &'a self,
+        name: &'a str,
+        outputs: Vec<Sender<Event>>,
+        _checkpoints: &'a CheckpointStore,
+    ) -> BoxFuture<'a, AnyResult<Box<dyn Source>>> {
+        Box::pin(async move {
+            let source = QUICSource::new(
+                name.to_string(),
+                SocketAddr::from((self.listen_addr, self.listen_port)),
+                outputs,
+                self.tls.as_ref().map(TlsIdentityConfig::load).transpose()?,
+                self.client_ca_path.clone(),
+                self.allowed_peers.clone(),
+                Some(self.options()),
+            )?;
+            Ok(Box::new(source) as Box<dyn Source>)
+        })
+    }
+}
+
+/// Something that sits between sources and sinks and changes the records passing through it, as configured
+/// for whichever type the transform is.
+#[derive(Debug, Clone)]
+pub struct TransformConfig(Arc<dyn BuildTransform>);
+
+impl TransformConfig {
+    pub fn inputs(&self) -> &[String] {
+        self.0.inputs()
+    }
+
+    pub fn build(&self, name: &str) -> Box<dyn Transform> {
+        self.0.build(name)
+    }
+}
+
+impl PartialEq for TransformConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.dyn_eq(&*other.0 as &dyn Any)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilterTransformConfig {
+    pub inputs: Vec<String>,
+    pub pattern: String,
+    #[serde(default)]
+    pub invert: bool,
+}
+
+impl BuildTransform for FilterTransformConfig {
+    fn inputs(&self) -> &[String] {
+        &self.inputs
+    }
+
+    fn build(&self, name: &str) -> Box<dyn Transform> {
+        Box::new(Filter::new(
+            name.to_string(),
+            self.pattern.clone().into_bytes(),
+            self.invert,
+        ))
+    }
+}
+
+/// Where records end up, as configured for whichever type the sink is.
+#[derive(Debug, Clone)]
+pub struct SinkConfig(Arc<dyn BuildSink>);
+
+impl SinkConfig {
+    pub fn inputs(&self) -> &[String] {
+        self.0.inputs()
+    }
+
+    pub async fn build(&self, name: &str, input: Input) -> AnyResult<Box<dyn Sink>> {
+        self.0.build(name, input).await
+    }
+}
+
+impl PartialEq for SinkConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.dyn_eq(&*other.0 as &dyn Any)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileSinkConfig {
+    pub inputs: Vec<String>,
+    pub path: PathBuf,
+    #[serde(default = "default_delimiter", deserialize_with = "delimiter")]
+    pub delimiter: String,
+    #[serde(default, deserialize_with = "optional_non_zero")]
+    pub flush_interval_ms: Option<u64>,
+    #[serde(default, deserialize_with = "optional_non_zero")]
+    pub flush_batch_size: Option<usize>,
+}
+
+impl FileSinkConfig {
+    pub fn flush_policy(&self) -> FlushPolicy {
+        flush_policy(self.flush_interval_ms, self.flush_batch_size)
+    }
+}
+
+impl BuildSink for FileSinkConfig {
+    fn inputs(&self) -> &[String] {
+        &self.inputs
+    }
+
+    fn build<'a>(&'a self, name: &'a str, input: Input) -> BoxFuture<'a, AnyResult<Box<dyn Sink>>> {
+        Box::pin(async move {
+            let sink = FileSink::new(
+                name.to_string(),
+                self.path.clone(),
+                self.delimiter.clone().into_bytes(),
+                input,
+                Some(self.flush_policy()),
+            )
+            .await?;
+            Ok(Box::new(sink) as Box<dyn Sink>)
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuicSinkConfig {
+    pub inputs: Vec<String>,
+    /// The peer to send to, unless `peers` is given instead.
+    #[serde(default)]
+    pub peer_addr: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub peer_port: Option<u16>,
+    /// The peers to spread batches over, see `balance`.
+    #[serde(default)]
+    pub peers: Vec<PeerConfig>,
+    #[serde(default)]
+    pub balance: BalanceConfig,
+    /// The event field that picks the peer when balancing by `consistent_hash`, see `EventKey`.
+    #[serde(default, deserialize_with = "optional_parsed")]
+    pub hash_key: Option<EventKey>,
+    /// PEM file, or directory of them, with the certificates to trust the peer with: its own certificate, e.g.
+    /// the `cert_path` of the peer's `quic` source, or the CA that issued it.
+    #[serde(default)]
+    pub server_cert: Option<PathBuf>,
+    /// Also trust the CAs in the system's trust store.
+    #[serde(default)]
+    pub system_roots: bool,
+    /// Hex encoded SHA-256 fingerprints of certificates the peer may present, trusted instead of any CA.
+    #[serde(default)]
+    pub pinned_certs: Vec<String>,
+    /// Hex encoded SHA-256 of the DER encoded public keys the peer's certificate may have, trusted instead of
+    /// any CA.
+    #[serde(default)]
+    pub pinned_public_keys: Vec<String>,
+    /// The name the peers' certificates were issued for, unless given per peer.
+    #[serde(default = "default_server_name")]
+    pub server_name: String,
+    /// The certificate to authenticate with, for peers whose source has a `client_ca_path`.
+    #[serde(default)]
+    pub tls: Option<TlsIdentityConfig>,
+    /// How long to wait before sending a partial batch.
+    #[serde(default, deserialize_with = "optional_non_zero")]
+    pub flush_interval_ms: Option<u64>,
+    #[serde(default, deserialize_with = "optional_non_zero")]
+    pub flush_batch_size: Option<usize>,
+    /// How long to wait before retrying a peer after the first failure, doubling with each failure after.
+    #[serde(default, deserialize_with = "optional_non_zero")]
+    pub reconnect_initial_backoff_ms: Option<u64>,
+    /// Longest to wait before retrying a peer, at least `reconnect_initial_backoff_ms`.
+    #[serde(default, deserialize_with = "optional_non_zero")]
+    pub reconnect_max_backoff_ms: Option<u64>,
+    #[serde(default, deserialize_with = "optional_non_zero")]
+    pub max_unacked_batches: Option<usize>,
+    #[serde(default)]
+    pub transport: QuicTransportConfig,
+    /// Longest batch to send, in bytes, at most the peers' `max_frame_len`.
+    #[serde(default, deserialize_with = "optional_non_zero")]
+    pub max_frame_len: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PeerConfig {
+    pub addr: SocketAddr,
+    #[serde(default)]
+    pub server_name: Option<String>,
+}
+
+/// See `Balance`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceConfig {
+    #[default]
+    RoundRobin,
+    LeastOutstanding,
+    ConsistentHash,
+}
+
+impl QuicSinkConfig {
+    /// Either the single `peer_addr` and `peer_port`, or `peers`.
+    pub fn peers(&self) -> AnyResult<Vec<Peer>> {
+        match (self.peer_addr, self.peer_port, self.peers.is_empty()) {
+            (Some(addr), Some(port), true) => Ok(vec![Peer {
+                addr: SocketAddr::from((addr, port)),
+                server_name: self.server_name.clone(),
+            }]),
+            (None, None, false) => Ok(self
+                .peers
+                .iter()
+                .map(|peer| Peer {
+                    addr: peer.addr,
+                    server_name: peer
+                        .server_name
+                        .clone()
+                        .unwrap_or_else(|| self.server_name.clone()),
+                })
+                .collect()),
+            (None, None, true) => {
+                bail!("either `peer_addr` and `peer_port` or `peers` is required")
+            }
+            (_, _, false) => bail!("`peers` can't be combined with `peer_addr` and `peer_port`"),
+            _ => bail!("`peer_addr` and `peer_port` must be given together"),
+        }
+    }
+
+    pub fn balance(&self) -> AnyResult<Balance> {
+        Ok(match (self.balance, &self.hash_key) {
+            (BalanceConfig::ConsistentHash, Some(key)) => Balance::ConsistentHash(key.clone()),
+            (BalanceConfig::ConsistentHash, None) => {
+                bail!("`hash_key` is required to balance by `consistent_hash`")
+            }
+            (_, Some(_)) => bail!("`hash_key` only applies to `consistent_hash`"),
+            (BalanceConfig::RoundRobin, None) => Balance::RoundRobin,
+            (BalanceConfig::LeastOutstanding, None) => Balance::LeastOutstanding,
+        })
+    }
+
+    pub fn trust(&self) -> AnyResult<Trust> {
+        let certs = self.pinned_certs.iter().map(|hex| Pin::certificate(hex));
+        let keys = self
+            .pinned_public_keys
+            .iter()
+            .map(|hex| Pin::public_key(hex));
+        Ok(Trust {
+            ca_paths: self.server_cert.iter().cloned().collect(),
+            system_roots: self.system_roots,
+            pins: certs.chain(keys).collect::<AnyResult<_>>()?,
+        })
+    }
+
+    pub fn reconnect_policy(&self) -> AnyResult<ReconnectPolicy> {
+        let defaults = ReconnectPolicy::default();
+        let policy = ReconnectPolicy {
+            initial_backoff: self
+                .reconnect_initial_backoff_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.initial_backoff),
+            max_backoff: self
+                .reconnect_max_backoff_ms
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.max_backoff),
+        };
+        if policy.max_backoff < policy.initial_backoff {
+            bail!(
+                "`reconnect_max_backoff_ms` ({}ms) must be at least `reconnect_initial_backoff_ms` ({}ms)",
+                policy.max_backoff.as_millis(),
+                policy.initial_backoff.as_millis()
+            );
+        }
+        Ok(policy)
+    }
+
+    pub fn options(&self) -> AnyResult<QUICSinkOptions> {
+        let defaults = QUICSinkOptions::default();
+        Ok(QUICSinkOptions {
+            flush_policy: flush_policy(self.flush_interval_ms, self.flush_batch_size),
+            reconnect_policy: self.reconnect_policy()?,
+            max_unacked_batches: self
+                .max_unacked_batches
+                .unwrap_or(defaults.max_unacked_batches),
+            balance: self.balance()?,
+            transport: self.transport.options(),
+            max_frame_len: self.max_frame_len.unwrap_or(defaults.max_frame_len),
+        })
+    }
+}
+
+impl BuildSink for QuicSinkConfig {
+    fn inputs(&self) -> &[String] {
+        &self.inputs
+    }
+
+    fn build<'a>(&'a self, name: &'a str, input: Input) -> BoxFuture<'a, AnyResult<Box<dyn Sink>>> {
+        Box::pin(async move {
+            let sink = QUICSink::new(
+                name.to_string(),
+                self.peers()?,
+                &self.trust()?,
+                self.tls.as_ref().map(TlsIdentityConfig::load).transpose()?,
+                input,
+                Some(self.options()?),
+            )
+            .await?;
+            Ok(Box::new(sink) as Box<dyn Sink>)
+        })
+    }
+}
+
+/// `FlushPolicy::default()`, overridden by whichever limits are specified.
+fn flush_policy(interval_ms: Option<u64>, batch_size: Option<usize>) -> FlushPolicy {
+    let defaults = FlushPolicy::default();
+    FlushPolicy {
+        interval: interval_ms
+            .map(Duration::from_millis)
+            .unwrap_or(defaults.interval),
+        batch_size: batch_size.unwrap_or(defaults.batch_size),
+    }
+}
+
+/// The name `Server` issues its certificate for, unless told otherwise.
+fn default_server_name() -> String {
+    "localhost".to_string()
+}
+
+fn default_delimiter() -> String {
+    "\n".to_string()
+}
+
+fn delimiter<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let delimiter = String::deserialize(deserializer)?;
+    if delimiter.is_empty() {
+        return Err(D::Error::custom("delimiter must not be empty"));
+    }
+    Ok(delimiter)
+}
+
+pub(crate) fn non_zero<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default + PartialEq,
+{
+    let value = T::deserialize(deserializer)?;
+    if value == T::default() {
+        return Err(D::Error::custom("must be greater than 0"));
+    }
+    Ok(value)
+}
+
+fn optional_parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    let value = String::deserialize(deserializer)?;
+    value.parse().map(Some).map_err(D::Error::custom)
+}
+
+fn optional_non_zero<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default + PartialEq,
+{
+    non_zero(deserializer).map(Some)
+}
+
+/// Parses a whole config file, with components of the types in `registry`. `path` is only used for error
+/// messages.
+///
+/// Components are dispatched on their `type` key by hand rather than with `#[serde(tag = "type")]`, because
+/// serde buffers internally tagged enums which loses the location of any error inside the component.
+pub fn parse(path: &Path, contents: &str, registry: &Registry) -> Result<Config, ConfigError> {
+    let document =
+        DeTable::parse(contents).map_err(|e| ConfigError::toml(path, contents, None, &e))?;
+    let mut config = Config::default();
+    for (key, value) in document.into_inner() {
+        match key.get_ref().as_ref() {
+            "agent" => {
+                config.agent = deserialize(value).map_err(|e| {
+                    ConfigError::toml(path, contents, Some(join_key("agent", &e)), e.inner())
+                })?;
+            }
+            "sources" => {
+                config.sources =
+                    parse_section(path, contents, "sources", value, &registry.sources)?;
+            }
+            "transforms" => {
+                config.transforms =
+                    parse_section(path, contents, "transforms", value, &registry.transforms)?;
+            }
+            "sinks" => {
+                config.sinks = parse_section(path, contents, "sinks", value, &registry.sinks)?;
+            }
+            other => {
+                return Err(ConfigError::at(
+                    path,
+                    contents,
+                    Some(key.span()),
+                    Some(other.to_string()),
+                    "unknown section, expected one of `agent`, `sources`, `transforms`, `sinks`",
+                ));
+            }
+        }
+    }
+    Ok(config)
+}
+
+/// Parses every component in a section such as `[sources]`.
+fn parse_section<T>(
+    path: &Path,
+    contents: &str,
+    section: &str,
+    value: Spanned<DeValue>,
+    types: &BTreeMap<&'static str, DeserializeComponent<T>>,
+) -> Result<BTreeMap<String, T>, ConfigError> {
+    let span = value.span();
+    let DeValue::Table(table) = value.into_inner() else {
+        return Err(ConfigError::at(
+            path,
+            contents,
+            Some(span),
+            Some(section.to_string()),
+            "expected a table of components",
+        ));
+    };
+
+    let mut components = BTreeMap::new();
+    for (name, value) in table {
+        let key = format!("{}.{}", section, name.get_ref());
+        let span = value.span();
+        let DeValue::Table(mut fields) = value.into_inner() else {
+            return Err(ConfigError::at(
+                path,
+                contents,
+                Some(span),
+                Some(key),
+                "expected a table",
+            ));
+        };
+
+        let Some(kind) = fields.remove("type") else {
+            return Err(ConfigError::at(
+                path,
+                contents,
+                Some(span),
+                Some(key),
+                "missing field `type`",
+            ));
+        };
+        let kind_span = kind.span();
+        let DeValue::String(kind) = kind.into_inner() else {
+            return Err(ConfigError::at(
+                path,
+                contents,
+                Some(kind_span),
+                Some(format!("{}.type", key)),
+                "expected a string",
+            ));
+        };
+
+        let Some(deserialize_type) = types.get(kind.as_ref()) else {
+            let expected = types.keys().map(|t| format!("`{}`", t)).collect::<Vec<_>>();
+            return Err(ConfigError::at(
+                path,
+                contents,
+                Some(kind_span),
+                Some(format!("{}.type", key)),
+                &format!(
+                    "unknown type `{}`, expected one of {}",
+                    kind,
+                    expected.join(", ")
+                ),
+            ));
+        };
+        let component = deserialize_type(Spanned::new(span, DeValue::Table(fields)))
+            .map_err(|e| ConfigError::toml(path, contents, Some(join_key(&key, &e)), e.inner()))?;
+        components.insert(name.into_inner().into_owned(), component);
+    }
+    Ok(components)
+}
+
+fn deserialize<T: DeserializeOwned>(value: Spanned<DeValue>) -> Result<T, PathError> {
+    serde_path_to_error::deserialize(ValueDeserializer::from(value))
+}
+
+/// Appends the path `e` occurred at to `prefix`, e.g. `sinks.out` and `path` become `sinks.out.path`.
+fn join_key(prefix: &str, e: &PathError) -> String {
+    match e.path().to_string().as_str() {
+        "." => prefix.to_string(),
+        inner => format!("{}.{}", prefix, inner),
+    }
+}
+
+/// A config file that couldn't be loaded, pointing at the offending part of the file where possible.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub path: PathBuf,
+    /// 1-based line and column.
+    pub location: Option<(usize, usize)>,
+    /// Dotted path to the offending key, e.g. `sinks.out.path`.
+    pub key: Option<String>,
+    pub message: String,
+}
+
+impl ConfigError {
+    pub fn io(path: &Path, err: std::io::Error) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            location: None,
+            key: None,
+            message: err.to_string(),
+        }
+    }
+
+    pub fn toml(path: &Path, contents: &str, key: Option<String>, err: &toml::de::Error) -> Self {
+        Self::at(path, contents, err.span(), key, err.message().trim_end())
+    }
+
+    pub fn at(
+        path: &Path,
+        contents: &str,
+        span: Option<Range<usize>>,
+        key: Option<String>,
+        message: &str,
+    ) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            location: span.map(|span| line_column(contents, span.start)),
+            key,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.display())?;
+        if let Some((line, column)) = self.location {
+            write!(f, ":{}:{}", line, column)?;
+        }
+        if let Some(key) = &self.key {
+            write!(f, ": {}", key)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn line_column(contents: &str, offset: usize) -> (usize, usize) {
+    let before = &contents[..offset.min(contents.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map_or(0, |idx| idx + 1) + 1;
+    (line, column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(contents: &str) -> ConfigError {
+        parse(Path::new("logga.toml"), contents, &Registry::default()).unwrap_err()
+    }
+
+    fn assert_error(contents: &str, location: (usize, usize), key: &str, message: &str) {
+        let e = error(contents);
+        assert_eq!(e.location, Some(location), "{}", e);
+        assert_eq!(e.key.as_deref(), Some(key), "{}", e);
+        assert!(e.message.contains(message), "{}", e);
+    }
+
+    const FILE_SINK: &str =
+        "[sinks.out]\ntype = \"file\"\ninputs = [\"app\"]\npath = \"out.log\"\n";
+
+    #[test]
+    fn example_parses() {
+        let config = parse(
+            Path::new("logga.toml"),
+            include_str!("../logga.toml"),
+            &Registry::default(),
+        )
+        .unwrap();
+        assert!(config.sources.contains_key("app"));
+        assert!(config.transforms.contains_key("no_debug"));
+        assert!(config.sinks.contains_key("out"));
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct StdinSourceConfig {
+        delimiter: String,
+    }
+
+    impl BuildSource for StdinSourceConfig {
+        fn build<'a>(
+            &'a self,
+            _name: &'a str,
+            _outputs: Vec<Sender<Event>>,
+            _checkpoints: &'a CheckpointStore,
+        ) -> BoxFuture<'a, AnyResult<Box<dyn Source>>> {
+            Box::pin(async { bail!("not implemented") })
+        }
+    }
+
+    #[test]
+    fn registered_types() {
+        let mut registry = Registry::default();
+        registry.source::<StdinSourceConfig>("stdin");
+        let parse = |contents: &str| parse(Path::new("logga.toml"), contents, &registry).unwrap();
+        let stdin = parse("[sources.app]\ntype = \"stdin\"\ndelimiter = \";\"\n");
+        assert_eq!(
+            stdin,
+            parse("[sources.app]\ntype = \"stdin\"\ndelimiter = \";\"\n")
+        );
+        assert_ne!(
+            stdin,
+            parse("[sources.app]\ntype = \"stdin\"\ndelimiter = \",\"\n")
+        );
+        // Same fields, different type.
+        assert_ne!(
+            parse("[sources.app]\ntype = \"file\"\npath = \"a.log\"\n"),
+            parse("[sources.app]\ntype = \"file_glob\"\ninclude = [\"a.log\"]\n")
+        );
+    }
+
+    #[test]
+    fn unknown_type() {
+        assert_error(
+            "[sources.app]\ntype = \"nope\"\npath = \"a.log\"\n",
+            (2, 8),
+            "sources.app.type",
+            "unknown type `nope`, expected one of `file`, `file_glob`, `quic`",
+        );
+    }
+
+    #[test]
+    fn unknown_field() {
+        assert_error(
+            &format!("{}colour = 1\n", FILE_SINK),
+            (5, 1),
+            "sinks.out.colour",
+            "unknown field `colour`",
+        );
+    }
+
+    #[test]
+    fn wrong_value_type() {
+        assert_error(
+            &format!("{}flush_interval_ms = \"soon\"\n", FILE_SINK),
+            (5, 21),
+            "sinks.out.flush_interval_ms",
+            "invalid type",
+        );
+    }
+
+    #[test]
+    fn unknown_section() {
+        assert_error("[sauces]\n", (1, 2), "sauces", "unknown section");
+    }
+
+    #[test]
+    fn zero_intervals() {
+        assert_error(
+            &format!("{}flush_interval_ms = 0\n", FILE_SINK),
+            (5, 21),
+            "sinks.out.flush_interval_ms",
+            "must be greater than 0",
+        );
+        assert_error(
+            "[agent]\ncheckpoint_interval_ms = 0\n",
+            (2, 26),
+            "agent.checkpoint_interval_ms",
+            "must be greater than 0",
+        );
+        assert_error(
+            "[sources.logs]\ntype = \"file_glob\"\ninclude = [\"*.log\"]\nrescan_interval_ms = 0\n",
+            (4, 22),
+            "sources.logs.rescan_interval_ms",
+            "must be greater than 0",
+        );
+        assert_error(
+            "[sinks.out]\ntype = \"quic\"\ninputs = [\"app\"]\npeer_port = 5997\nflush_interval_ms = 0\n",
+            (5, 21),
+            "sinks.out.flush_interval_ms",
+            "must be greater than 0",
+        );
+    }
+
+    #[test]
+    fn reconnect_backoff() {
+        let quic_sink = |backoff: &str| {
+            toml::from_str::<QuicSinkConfig>(&format!(
+                "inputs = [\"app\"]\npeer_addr = \"127.0.0.1\"\npeer_port = 5997\n{}",
+                backoff
+            ))
+            .unwrap()
+        };
+        let policy =
+            quic_sink("reconnect_initial_backoff_ms = 100\nreconnect_max_backoff_ms = 100\n")
+                .reconnect_policy()
+                .unwrap();
+        assert_eq!(policy.initial_backoff, Duration::from_millis(100));
+        assert_eq!(policy.max_backoff, Duration::from_millis(100));
+
+        let e = quic_sink("reconnect_initial_backoff_ms = 200\nreconnect_max_backoff_ms = 100\n")
+            .reconnect_policy()
+            .unwrap_err();
+        assert!(e.to_string().contains("must be at least"), "{}", e);
+        // Compared with the default when only one of them is given.
+        assert!(
+            quic_sink("reconnect_initial_backoff_ms = 60000\n")
+                .reconnect_policy()
+                .is_err()
+        );
+
+        for key in ["reconnect_initial_backoff_ms", "reconnect_max_backoff_ms"] {
+            assert_error(
+                &format!(
+                    "[sinks.out]\ntype = \"quic\"\ninputs = [\"app\"]\npeer_port = 5997\n{} = 0\n",
+                    key
+                ),
+                (5, key.len() + 4),
+                &format!("sinks.out.{}", key),
+                "must be greater than 0",
+            );
+        }
+    }
+
+    #[test]
+    fn generated_identity_is_the_one_written() {
+        let dir = std::env::temp_dir().join(format!(
+            "logga-config-{}-generated_identity_is_the_one_written",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = TlsIdentityConfig {
+            cert_path: dir.join("cert.pem"),
+            key_path: dir.join("key.pem"),
+            generate: true,
+            subject_alt_names: default_subject_alt_names(),
+        };
+        let (generated, files) = config.load().unwrap();
+        assert_eq!(files.load().unwrap().leaf(), generated.leaf());
+        // Loaded rather than generated again from then on.
+        let (loaded, _) = config.load().unwrap();
+        assert_eq!(loaded.leaf(), generated.leaf());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn zero_max_record_len() {
+        assert_error(
+            "[sources.app]\ntype = \"file\"\npath = \"app.log\"\nmax_record_len = 0\n",
+            (4, 18),
+            "sources.app.max_record_len",
+            "must be greater than 0",
+        );
+        assert_error(
+            "[sources.logs]\ntype = \"file_glob\"\ninclude = [\"*.log\"]\nmax_record_len = 0\n",
+            (4, 18),
+            "sources.logs.max_record_len",
+            "must be greater than 0",
+        );
+    }
+}
@@ -0,0 +1,286 @@
+use bytes::Bytes;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::Path,
+    sync::{
+        Arc, OnceLock,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::SystemTime,
+};
+
+/// Number of `Event`s that currently exist, see `in_flight`.
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns how many events have been created, or cloned, and not yet dropped. Sinks drop events once they
+/// have written them, so this is the number of events still making their way through the pipeline.
+pub fn in_flight() -> usize {
+    IN_FLIGHT.load(Ordering::Relaxed)
+}
+
+/// A single record flowing between components.
+///
+/// Cloning only bumps reference counts, so an event can be sent to any number of channels without copying
+/// its payload or metadata.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub payload: Bytes,
+    pub metadata: Arc<Metadata>,
+    ack: Option<Ack>,
+    in_flight: InFlight,
+}
+
+impl Event {
+    pub fn new(payload: impl Into<Bytes>, metadata: Metadata) -> Self {
+        Self {
+            payload: payload.into(),
+            metadata: Arc::new(metadata),
+            ack: None,
+            in_flight: InFlight::new(),
+        }
+    }
+
+    /// Attaches `ack`, so that it isn't acknowledged until this event (and every clone of it) has been delivered.
+    pub fn with_ack(mut self, ack: Ack) -> Self {
+        self.ack = Some(ack);
+        self
+    }
+
+    pub fn ack(&self) -> Option<&Ack> {
+        self.ack.as_ref()
+    }
+
+    /// Detaches the event's `Ack`, leaving whoever takes it responsible for acknowledging it.
+    pub fn take_ack(&mut self) -> Option<Ack> {
+        self.ack.take()
+    }
+
+    /// A copy of the event without its `Ack`, e.g. to encode it, that doesn't need acknowledging.
+    pub fn without_ack(&self) -> Self {
+        Self {
+            payload: self.payload.clone(),
+            metadata: self.metadata.clone(),
+            ack: None,
+            in_flight: self.in_flight.clone(),
+        }
+    }
+
+    /// Acknowledges this copy of the event, once it has been written out or dropped on purpose.
+    pub fn delivered(mut self) {
+        if let Some(ack) = self.ack.take() {
+            ack.delivered();
+        }
+    }
+}
+
+/// Tells a source whether the events it attached this to were delivered.
+///
+/// Sources that receive events from somewhere able to resend them attach an `Ack` to each event, so that
+/// delivery is only acknowledged once the pipeline is done with every event. Every clone has to be
+/// acknowledged with `delivered`: by sinks once the event has been written out (sinks that buffer their
+/// writes hold on to the `Ack`s of buffered events until they have been flushed), and by transforms for
+/// events they drop on purpose. A clone dropped without that, e.g. because writing its event failed, means
+/// the events weren't delivered.
+///
+/// The callback runs once every clone is gone, with whether they were all acknowledged.
+pub struct Ack {
+    shared: Arc<Shared>,
+    delivered: bool,
+}
+
+impl Ack {
+    pub fn new(on_done: impl FnOnce(bool) + Send + Sync + 'static) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                on_done: Some(Box::new(on_done)),
+                undelivered: AtomicBool::new(false),
+            }),
+            delivered: false,
+        }
+    }
+
+    /// Acknowledges this clone.
+    pub fn delivered(mut self) {
+        self.delivered = true;
+    }
+}
+
+impl Clone for Ack {
+    /// The clone has to be acknowledged separately.
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            delivered: false,
+        }
+    }
+}
+
+impl Drop for Ack {
+    fn drop(&mut self) {
+        if !self.delivered {
+            self.shared.undelivered.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+impl fmt::Debug for Ack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // How many events are still holding on to it.
+        f.debug_tuple("Ack")
+            .field(&Arc::strong_count(&self.shared))
+            .finish()
+    }
+}
+
+impl PartialEq for Ack {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+type Callback = Box<dyn FnOnce(bool) + Send + Sync>;
+
+/// Shared by every clone of an `Ack`, runs the callback once the last one has gone.
+struct Shared {
+    on_done: Option<Callback>,
+    /// Set by any clone dropped without being acknowledged.
+    undelivered: AtomicBool,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        if let Some(on_done) = self.on_done.take() {
+            on_done(!*self.undelivered.get_mut());
+        }
+    }
+}
+
+/// Counts towards `in_flight` for as long as the event holding it exists.
+#[derive(Debug)]
+struct InFlight;
+
+impl InFlight {
+    fn new() -> Self {
+        IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+        Self
+    }
+}
+
+impl Clone for InFlight {
+    fn clone(&self) -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl PartialEq for InFlight {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+/// Where and when an event came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metadata {
+    /// Name of the source component that produced the event.
+    pub source: Arc<str>,
+    /// File the event was read from, for file based sources.
+    pub path: Option<Arc<Path>>,
+    /// Offset in to `path` that the event started at.
+    pub offset: Option<u64>,
+    /// Host that the event was ingested on.
+    pub host: Arc<str>,
+    pub ingested_at: SystemTime,
+    /// Anything else a source wants to attach to its events.
+    pub tags: BTreeMap<String, String>,
+}
+
+impl Metadata {
+    /// Metadata for an event produced by `source` on this host, just now.
+    pub fn new(source: Arc<str>) -> Self {
+        Self {
+            source,
+            path: None,
+            offset: None,
+            host: hostname(),
+            ingested_at: SystemTime::now(),
+            tags: BTreeMap::new(),
+        }
+    }
+}
+
+/// The name of this host, looked up once.
+pub fn hostname() -> Arc<str> {
+    static HOSTNAME: OnceLock<Arc<str>> = OnceLock::new();
+    HOSTNAME
+        .get_or_init(|| {
+            std::fs::read_to_string("/proc/sys/kernel/hostname")
+                .ok()
+                .or_else(|| std::env::var("HOSTNAME").ok())
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .unwrap_or_else(|| "localhost".to_string())
+                .into()
+        })
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// An `Ack` and where its callback records whether everything was delivered.
+    fn ack() -> (Ack, Arc<Mutex<Option<bool>>>) {
+        let result = Arc::new(Mutex::new(None));
+        let done = result.clone();
+        let ack = Ack::new(move |delivered| *done.lock().unwrap() = Some(delivered));
+        (ack, result)
+    }
+
+    fn event(ack: &Ack) -> Event {
+        Event::new("payload", Metadata::new("test".into())).with_ack(ack.clone())
+    }
+
+    #[test]
+    fn delivered_once_every_clone_is() {
+        let (ack, result) = ack();
+        let first = event(&ack);
+        let second = first.clone();
+        ack.delivered();
+        first.delivered();
+        assert_eq!(*result.lock().unwrap(), None);
+        second.delivered();
+        assert_eq!(*result.lock().unwrap(), Some(true));
+    }
+
+    #[test]
+    fn dropping_a_clone_is_undelivered() {
+        let (ack, result) = ack();
+        let first = event(&ack);
+        let second = first.clone();
+        ack.delivered();
+        drop(first);
+        second.delivered();
+        assert_eq!(*result.lock().unwrap(), Some(false));
+    }
+
+    #[test]
+    fn copies_without_ack_need_no_acknowledging() {
+        let (ack, result) = ack();
+        let mut event = event(&ack);
+        drop(event.without_ack());
+        let taken = event.take_ack().unwrap();
+        drop(event);
+        ack.delivered();
+        assert_eq!(*result.lock().unwrap(), None);
+        taken.delivered();
+        assert_eq!(*result.lock().unwrap(), Some(true));
+    }
+}
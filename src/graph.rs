@@ -0,0 +1,506 @@
+use anyhow::{Result, bail};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use tokio::sync::mpsc::{self, Sender};
+
+use crate::{
+    agent::Config,
+    checkpoint::CheckpointStore,
+    event::Event,
+    module::{Component, Input, TransformTask},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Source,
+    Transform,
+    Sink,
+}
+
+impl Kind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::Source => "source",
+            Kind::Transform => "transform",
+            Kind::Sink => "sink",
+        }
+    }
+}
+
+struct Node {
+    kind: Kind,
+    inputs: Vec<String>,
+    // Filled in from the other nodes' `inputs`.
+    outputs: Vec<String>,
+}
+
+/// The flow of records between the components in a `Config`, from sources through transforms to sinks.
+///
+/// Creating a `Graph` validates the topology, `build` then constructs the components and the channels
+/// between them.
+pub struct Graph {
+    nodes: BTreeMap<String, Node>,
+    /// Every component, ordered so that each component comes after all of its inputs.
+    order: Vec<String>,
+}
+
+impl Graph {
+    pub fn new(config: &Config) -> Result<Self> {
+        let mut nodes = BTreeMap::new();
+        let sources = config
+            .sources
+            .keys()
+            .map(|name| (name, Kind::Source, vec![]));
+        let transforms = config
+            .transforms
+            .iter()
+            .map(|(name, transform)| (name, Kind::Transform, transform.inputs().to_vec()));
+        let sinks = config
+            .sinks
+            .iter()
+            .map(|(name, sink)| (name, Kind::Sink, sink.inputs().to_vec()));
+        for (name, kind, inputs) in sources.chain(transforms).chain(sinks) {
+            let node = Node {
+                kind,
+                inputs,
+                outputs: vec![],
+            };
+            if let Some(existing) = nodes.insert(name.clone(), node) {
+                bail!(
+                    "`{}` is the name of both a {} and a {}, component names must be unique",
+                    name,
+                    existing.kind.as_str(),
+                    kind.as_str()
+                );
+            }
+        }
+
+        let mut edges = vec![];
+        for (name, node) in &nodes {
+            if node.kind != Kind::Source && node.inputs.is_empty() {
+                bail!("{} `{}` has no inputs", node.kind.as_str(), name);
+            }
+            for input in &node.inputs {
+                match nodes.get(input) {
+                    None => bail!(
+                        "{} `{}` has input `{}`, which doesn't exist",
+                        node.kind.as_str(),
+                        name,
+                        input
+                    ),
+                    Some(upstream) if upstream.kind == Kind::Sink => bail!(
+                        "{} `{}` has input `{}`, which is a sink and can't be used as an input",
+                        node.kind.as_str(),
+                        name,
+                        input
+                    ),
+                    Some(_) => edges.push((input.clone(), name.clone())),
+                }
+            }
+            let mut unique = node.inputs.clone();
+            unique.sort();
+            unique.dedup();
+            if unique.len() != node.inputs.len() {
+                bail!(
+                    "{} `{}` lists the same input twice",
+                    node.kind.as_str(),
+                    name
+                );
+            }
+        }
+        for (from, to) in edges {
+            nodes.get_mut(&from).unwrap().outputs.push(to);
+        }
+
+        for (name, node) in &nodes {
+            if node.kind != Kind::Sink && node.outputs.is_empty() {
+                bail!(
+                    "{} `{}` isn't an input to any transform or sink",
+                    node.kind.as_str(),
+                    name
+                );
+            }
+        }
+
+        let order = topological_order(&nodes)?;
+        Ok(Self { nodes, order })
+    }
+
+    /// Constructs the components named in `names`, or every component if `names` is `None`. Channels are
+    /// created from sinks back to sources, so that each component is given its receiver and the senders for
+    /// everything downstream of it when it is constructed.
+    ///
+    /// Components downstream of those being built must already have a channel in `channels`. A transform or
+    /// sink with an entry in `inputs` reads from that rather than a new channel, this is how a component that
+    /// is being replaced hands its queued records on to its replacement.
+    pub async fn build(
+        &self,
+        config: &Config,
+        checkpoints: &CheckpointStore,
+        channels: &mut Channels,
+        names: Option<&BTreeSet<String>>,
+        mut inputs: BTreeMap<String, Input>,
+    ) -> Vec<(String, Result<Box<dyn Component>>)> {
+        let mut components = vec![];
+
+        for name in self.order.iter().rev() {
+            if names.is_some_and(|names| !names.contains(name)) {
+                continue;
+            }
+            let component = self
+                .build_component(name, config, checkpoints, channels, inputs.remove(name))
+                .await;
+            components.push((name.clone(), component));
+        }
+        components
+    }
+
+    /// Constructs a single component. A transform or sink's channel is only added to `channels` once it has
+    /// been constructed, so that nothing upstream is given a sender for a channel nobody reads from.
+    async fn build_component(
+        &self,
+        name: &str,
+        config: &Config,
+        checkpoints: &CheckpointStore,
+        channels: &mut Channels,
+        input: Option<Input>,
+    ) -> Result<Box<dyn Component>> {
+        let node = &self.nodes[name];
+        let outputs = node
+            .outputs
+            .iter()
+            .map(|output| channels.sender(output))
+            .collect::<Result<Vec<_>>>()?;
+        if node.kind == Kind::Source {
+            channels.senders.remove(name);
+            return Ok(config.sources[name]
+                .build(name, outputs, checkpoints)
+                .await?);
+        }
+
+        // Reusing the input, the sender for it is already in `channels`.
+        let (sender, receiver) = match input {
+            Some(input) => (None, input),
+            None => {
+                let (send, recv) = mpsc::channel(channels.capacity);
+                (Some(send), Input::from(recv))
+            }
+        };
+        let component: Box<dyn Component> = match node.kind {
+            Kind::Transform => Box::new(TransformTask::new(
+                config.transforms[name].build(name),
+                receiver,
+                outputs,
+            )),
+            _ => config.sinks[name].build(name, receiver).await?,
+        };
+        if let Some(sender) = sender {
+            channels.senders.insert(name.to_string(), sender);
+        }
+        Ok(component)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.nodes.contains_key(name)
+    }
+
+    pub fn is_source(&self, name: &str) -> bool {
+        self.nodes
+            .get(name)
+            .is_some_and(|node| node.kind == Kind::Source)
+    }
+
+    /// Every component, ordered so that each component comes after all of its inputs.
+    pub fn order(&self) -> &[String] {
+        &self.order
+    }
+
+    /// Compares this graph with `new`, to find what needs to be done to go from one to the other.
+    /// A component has changed if its config did, or if it now sends to a different set of components.
+    pub fn diff(&self, config: &Config, new: &Graph, new_config: &Config) -> Diff {
+        let mut diff = Diff::default();
+        for (name, node) in &self.nodes {
+            let Some(new_node) = new.nodes.get(name) else {
+                diff.removed.insert(name.clone());
+                continue;
+            };
+            let same_config = config.sources.get(name) == new_config.sources.get(name)
+                && config.transforms.get(name) == new_config.transforms.get(name)
+                && config.sinks.get(name) == new_config.sinks.get(name);
+            if !same_config || node.outputs != new_node.outputs {
+                diff.changed.insert(name.clone());
+            }
+        }
+        for name in new.nodes.keys() {
+            if !self.nodes.contains_key(name) {
+                diff.added.insert(name.clone());
+            }
+        }
+        diff
+    }
+
+    /// Names of the components that `name` reads from.
+    pub fn inputs(&self, name: &str) -> &[String] {
+        self.nodes
+            .get(name)
+            .map(|node| node.inputs.as_slice())
+            .unwrap_or_default()
+    }
+}
+
+/// The difference between two graphs, see `Graph::diff`.
+#[derive(Debug, Default)]
+pub struct Diff {
+    pub added: BTreeSet<String>,
+    pub changed: BTreeSet<String>,
+    pub removed: BTreeSet<String>,
+}
+
+/// A sender for the channel feeding each running transform and sink, kept so that components can be replaced
+/// without their upstream components noticing.
+///
+/// These have to be dropped for the pipeline to drain, as channels only close once every sender has gone.
+pub struct Channels {
+    capacity: usize,
+    senders: BTreeMap<String, Sender<Event>>,
+}
+
+impl Channels {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            senders: BTreeMap::new(),
+        }
+    }
+
+    fn sender(&self, name: &str) -> Result<Sender<Event>> {
+        match self.senders.get(name) {
+            Some(sender) => Ok(sender.clone()),
+            None => bail!("`{}` has no input channel", name),
+        }
+    }
+
+    /// Drops our sender for `name`, so that its channel closes once everything upstream of it has stopped.
+    pub fn remove(&mut self, name: &str) {
+        self.senders.remove(name);
+    }
+
+    pub fn clear(&mut self) {
+        self.senders.clear();
+    }
+}
+
+/// Kahn's algorithm, failing if the graph contains a cycle.
+fn topological_order(nodes: &BTreeMap<String, Node>) -> Result<Vec<String>> {
+    let mut remaining_inputs: BTreeMap<&str, usize> = nodes
+        .iter()
+        .map(|(name, node)| (name.as_str(), node.inputs.len()))
+        .collect();
+    let mut ready: VecDeque<&str> = remaining_inputs
+        .iter()
+        .filter(|(_, count)| **count == 0)
+        .map(|(name, _)| *name)
+        .collect();
+
+    let mut order = vec![];
+    while let Some(name) = ready.pop_front() {
+        order.push(name.to_string());
+        for output in &nodes[name].outputs {
+            let count = remaining_inputs.get_mut(output.as_str()).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                ready.push_back(output);
+            }
+        }
+    }
+
+    if order.len() != nodes.len() {
+        let cycle: Vec<&str> = remaining_inputs
+            .into_iter()
+            .filter(|(_, count)| *count > 0)
+            .map(|(name, _)| name)
+            .collect();
+        bail!(
+            "components {} are in or depend on a cycle, records must flow from sources to sinks",
+            cycle.join(", ")
+        );
+    }
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Registry;
+    use std::path::Path;
+
+    fn config(contents: &str) -> Config {
+        Config::parse(Path::new("logga.toml"), contents, &Registry::default()).unwrap()
+    }
+
+    fn graph(contents: &str) -> Result<Graph> {
+        Graph::new(&config(contents))
+    }
+
+    fn error(contents: &str) -> String {
+        match graph(contents) {
+            Ok(_) => panic!("expected an invalid graph"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    const SOURCE: &str = r#"
+        [sources.app]
+        type = "file"
+        path = "app.log"
+    "#;
+
+    fn filter(name: &str, inputs: &str) -> String {
+        format!(
+            "[transforms.{}]\ntype = \"filter\"\ninputs = {}\npattern = \"DEBUG\"\n",
+            name, inputs
+        )
+    }
+
+    fn sink(name: &str, inputs: &str) -> String {
+        format!(
+            "[sinks.{}]\ntype = \"file\"\ninputs = {}\npath = \"{}.log\"\n",
+            name, inputs, name
+        )
+    }
+
+    #[test]
+    fn orders_inputs_first() {
+        let graph = graph(
+            &[
+                SOURCE,
+                &sink("out", r#"["second"]"#),
+                &filter("second", r#"["first"]"#),
+                &filter("first", r#"["app"]"#),
+            ]
+            .concat(),
+        )
+        .unwrap();
+        assert_eq!(graph.order(), ["app", "first", "second", "out"]);
+        assert_eq!(graph.inputs("second"), ["first"]);
+        assert!(graph.is_source("app"));
+        assert!(!graph.is_source("out"));
+    }
+
+    #[test]
+    fn rejects_cycles() {
+        let e = error(
+            &[
+                SOURCE,
+                &filter("a", r#"["app", "b"]"#),
+                &filter("b", r#"["a"]"#),
+                &sink("out", r#"["b"]"#),
+            ]
+            .concat(),
+        );
+        assert!(
+            e.contains("components a, b, out are in or depend on a cycle"),
+            "{}",
+            e
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_inputs() {
+        let e = error(&[SOURCE, &sink("out", r#"["app", "missing"]"#)].concat());
+        assert_eq!(e, "sink `out` has input `missing`, which doesn't exist");
+    }
+
+    #[test]
+    fn rejects_duplicate_inputs() {
+        let e = error(&[SOURCE, &sink("out", r#"["app", "app"]"#)].concat());
+        assert_eq!(e, "sink `out` lists the same input twice");
+    }
+
+    #[test]
+    fn rejects_sinks_as_inputs() {
+        let e = error(
+            &[
+                SOURCE,
+                &sink("out", r#"["app"]"#),
+                &sink("copy", r#"["out"]"#),
+            ]
+            .concat(),
+        );
+        assert_eq!(
+            e,
+            "sink `copy` has input `out`, which is a sink and can't be used as an input"
+        );
+    }
+
+    #[test]
+    fn rejects_duplicate_names() {
+        let e = error(&[SOURCE, &sink("app", r#"["app"]"#)].concat());
+        assert!(e.contains("`app` is the name of both"), "{}", e);
+    }
+
+    #[test]
+    fn rejects_unused_sources() {
+        let e = error(
+            &[
+                SOURCE,
+                &filter("unused", r#"["app"]"#),
+                &sink("out", r#"["app"]"#),
+            ]
+            .concat(),
+        );
+        assert_eq!(
+            e,
+            "transform `unused` isn't an input to any transform or sink"
+        );
+    }
+
+    #[test]
+    fn diff_marks_upstream_of_new_outputs() {
+        let old = config(
+            &[
+                SOURCE,
+                &filter("keep", r#"["app"]"#),
+                &sink("out", r#"["keep"]"#),
+            ]
+            .concat(),
+        );
+        let new = config(
+            &[
+                SOURCE,
+                &filter("keep", r#"["app"]"#),
+                &sink("out", r#"["keep"]"#),
+                &sink("raw", r#"["app"]"#),
+            ]
+            .concat(),
+        );
+        let diff = Graph::new(&old)
+            .unwrap()
+            .diff(&old, &Graph::new(&new).unwrap(), &new);
+        assert_eq!(diff.added, BTreeSet::from(["raw".to_string()]));
+        // `app` now also sends to `raw`, the rest are untouched.
+        assert_eq!(diff.changed, BTreeSet::from(["app".to_string()]));
+        assert!(diff.removed.is_empty());
+    }
+
+    #[test]
+    fn diff_marks_changed_config_and_removed() {
+        let old = config(
+            &[
+                SOURCE,
+                &filter("keep", r#"["app"]"#),
+                &sink("out", r#"["keep"]"#),
+            ]
+            .concat(),
+        );
+        let new = config(&[SOURCE, &sink("out", r#"["app"]"#)].concat());
+        let diff = Graph::new(&old)
+            .unwrap()
+            .diff(&old, &Graph::new(&new).unwrap(), &new);
+        assert!(diff.added.is_empty());
+        assert_eq!(
+            diff.changed,
+            BTreeSet::from(["app".to_string(), "out".to_string()])
+        );
+        assert_eq!(diff.removed, BTreeSet::from(["keep".to_string()]));
+    }
+}
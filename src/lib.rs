@@ -1 +1,7 @@
+pub mod agent;
+pub mod checkpoint;
 pub mod comms;
+pub mod config;
+pub mod event;
+mod graph;
+pub mod module;
@@ -1,109 +1,1344 @@
-use anyhow::Result;
-use std::{net::Ipv4Addr, path::PathBuf};
+use anyhow::{Result, bail};
+use bytes::{Buf, BytesMut};
+use futures::future::BoxFuture;
+use std::{
+    collections::VecDeque,
+    fs::Metadata,
+    io::{ErrorKind, SeekFrom},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use tokio::{
-    fs::File,
-    io::{AsyncBufRead, AsyncReadExt, BufReader},
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter},
     sync::mpsc::{Receiver, Sender},
 };
-trait Module<I, O> {
-    fn read(&self, inp: I) -> O;
+use tracing::{info, warn};
+
+use crate::{
+    checkpoint::{Checkpoint, CheckpointStore, Fingerprint, validate_source_name},
+    event::{self, Ack, Event},
+    module::watch::FileWatcher,
+};
+
+mod discovery;
+mod quic;
+mod watch;
+
+pub use discovery::{DiscoveryOptions, FileDiscovery};
+pub use quic::{QUICSink, QUICSinkOptions, QUICSource, QUICSourceOptions};
+
+/// Size of each read from a file. Records longer than this are still handled, they just take more than one read.
+const FILE_READ_SIZE: usize = 64 * 1024;
+
+/// Anything that can be run as part of a pipeline.
+///
+/// The lifecycle is `start`, which may be called again after it returns an error to restart the component,
+/// followed by a single call to `shutdown` once the component won't be started again.
+pub trait Component: Send {
+    fn name(&self) -> &str;
+
+    /// Runs the component until it finishes, fails or `shutdown` is triggered.
+    fn start(&mut self, shutdown: Shutdown) -> BoxFuture<'_, Result<()>>;
+
+    /// `Unhealthy` means restarting the component won't help, e.g. because everything downstream of it has gone,
+    /// while `Degraded` means it is struggling with something that may well pass, e.g. an unreachable peer.
+    fn health(&self) -> Health {
+        Health::Healthy
+    }
+
+    /// Releases anything the component holds on to, e.g. flushing buffered output. Dropping its output
+    /// channels here lets downstream components finish once they have drained their input.
+    fn shutdown(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Hands back the channel the component reads from, along with any events it had read but not finished
+    /// with, so that whatever replaces it can carry on from the same point. Only called once the component
+    /// has stopped.
+    fn take_input(&mut self) -> Option<Input> {
+        None
+    }
 }
 
-// Make this module implement whatever trait is "Input" as it provides a stream to be read from
-trait Source {}
+/// Produces records, sending each one to every output channel it was constructed with.
+pub trait Source: Component {}
 
+/// Changes, or drops, the records passing between sources and sinks. Run by a `TransformTask`.
 // When implementing processing, might be cool to use `rayon` for things like dedupe or
+pub trait Transform: Send {
+    fn name(&self) -> &str;
 
-// Make his module implement whatever trais is "Output" as it provides a stream to
-trait Sink {}
+    /// Returns what should be sent on in place of `record`, or `None` to drop it.
+    fn transform(&mut self, record: Event) -> Option<Event>;
+}
+
+/// Consumes records from a single input channel, which is fed by every component it lists as an input.
+///
+/// Sinks keep running until all of their senders have been dropped, so that everything sent before the sources
+/// stopped still makes it out. `shutdown` is only triggered for a sink that is being replaced, which should
+/// stop promptly and leave anything it hasn't finished with in its input for `take_input`.
+pub trait Sink: Component {
+    /// Pushes anything buffered to wherever the sink writes to.
+    fn flush(&mut self) -> BoxFuture<'_, Result<()>>;
+}
 
-pub struct FileSource<'a, T> {
-    // Going to start with an implementation that should be simple and
-    // Need to decide how to handle the case where the file moves, both when we are in the middle or reading or not currently reading.
-    // https://docs.rs/inotify/latest/inotify/ can maybe use this to have really efficient reading of files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Health {
+    Healthy,
+    /// Not working as it should, but may recover on its own or by being restarted.
+    Degraded(String),
+    Unhealthy(String),
+}
 
+/// Tells components when the agent is stopping. Cheap to clone, every clone is triggered together.
+#[derive(Clone)]
+pub struct Shutdown(tokio::sync::watch::Receiver<bool>);
+
+impl Shutdown {
+    /// Sending `true` on the returned sender triggers every `Shutdown` cloned from the one returned.
+    pub fn channel() -> (tokio::sync::watch::Sender<bool>, Self) {
+        let (send, recv) = tokio::sync::watch::channel(false);
+        (send, Self(recv))
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once shutdown has been triggered.
+    pub async fn wait(&mut self) {
+        // The sender being dropped means nothing can trigger us any more, so wait forever rather than stop.
+        if self.0.wait_for(|stop| *stop).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Runs a `Transform` between its input channel and its output channels.
+pub struct TransformTask {
+    transform: Box<dyn Transform>,
+    inp_chan: Input,
+    out_chans: Vec<Sender<Event>>,
+}
+
+impl TransformTask {
+    pub fn new(
+        transform: Box<dyn Transform>,
+        recv: Input,
+        channels: impl IntoIterator<Item = Sender<Event>>,
+    ) -> Self {
+        Self {
+            transform,
+            inp_chan: recv,
+            out_chans: channels.into_iter().collect(),
+        }
+    }
+
+    /// Transforms records until all senders have been dropped. Like a sink, `shutdown` is only triggered when
+    /// the transform is being replaced.
+    async fn run(&mut self, mut shutdown: Shutdown) -> Result<()> {
+        loop {
+            let record = tokio::select! {
+                record = self.inp_chan.recv() => record,
+                _ = shutdown.wait() => {
+                    info!("{}: stopping to be replaced", self.transform.name());
+                    return Ok(());
+                }
+            };
+            let Some(record) = record else {
+                break;
+            };
+            let mut record = record;
+            let ack = record.take_ack();
+            match (self.transform.transform(record), ack) {
+                (Some(record), Some(ack)) => {
+                    send_all(
+                        self.transform.name(),
+                        &mut self.out_chans,
+                        record.with_ack(ack),
+                    )
+                    .await?
+                }
+                (Some(record), None) => {
+                    send_all(self.transform.name(), &mut self.out_chans, record).await?
+                }
+                // Dropped on purpose, so as far as the source is concerned it has been delivered.
+                (None, Some(ack)) => ack.delivered(),
+                (None, None) => {}
+            }
+        }
+        info!("{}: all senders closed, stopping", self.transform.name());
+        Ok(())
+    }
+}
+
+impl Component for TransformTask {
+    fn name(&self) -> &str {
+        self.transform.name()
+    }
+
+    fn start(&mut self, shutdown: Shutdown) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.run(shutdown))
+    }
+
+    fn health(&self) -> Health {
+        outputs_health(&self.out_chans)
+    }
+
+    fn shutdown(&mut self) -> BoxFuture<'_, Result<()>> {
+        self.out_chans.clear();
+        Box::pin(async { Ok(()) })
+    }
+
+    fn take_input(&mut self) -> Option<Input> {
+        Some(self.inp_chan.take())
+    }
+}
+
+pub struct FileSource {
     // This should only be used for logging or showing where this struct was constructed from in a config module. May also want to have another method for this.
     // Careful using this, as path will not change if the file at the path at the time of initialisation is moved.
-    // This means it is possible for `FileSource.path` and `FileSource.file` to be referring to different files.
-    name: String,
-    path: PathBuf,
+    // This means it is possible for `FileSource.path` and `FileSource.file` to be referring to different files
+    // until `start` notices the rotation and reopens `path`.
+    name: Arc<str>,
+    path: Arc<Path>,
     file: File,
-    delimiter: &'a [u8],
-    out_chans: Vec<Sender<T>>,
+    delimiter: Vec<u8>,
+    out_chans: Vec<Sender<Event>>,
+    identity: FileIdentity,
+    // Calculated once the file is long enough, see `Fingerprint::of`.
+    fingerprint: Option<Fingerprint>,
+    // How far in to `file` we have read.
+    offset: u64,
+    // Bytes that have been read but not yet terminated by a delimiter.
+    pending: BytesMut,
+    // How much of `pending` has already been searched for a delimiter, so that each read only searches
+    // what it added.
+    scanned: usize,
+    // Set while splitting a record longer than `max_record_len`, so that it is only warned about once.
+    oversized: bool,
+    options: FileSourceOptions,
+    // Commits to the checkpoint store, if there is one, once what was read has been delivered.
+    progress: Option<Arc<Mutex<Progress>>>,
+    // Follow the file we opened wherever it is renamed to, stopping once it has been deleted and read to
+    // the end, rather than following whatever file is at `path`.
+    follow_file: bool,
 }
 
-pub struct FileSink<'a, T> {
+pub struct FileSink {
     name: String,
     path: PathBuf,
-    file: File,
-    delimiter: &'a [u8],
-    inp_chan: Receiver<T>,
+    writer: BufWriter<File>,
+    delimiter: Vec<u8>,
+    inp_chan: Input,
+    flush_policy: FlushPolicy,
+    // Records written to `writer` since it was last flushed.
+    unflushed: usize,
+    // Acks of the unflushed records, only acknowledged once the records have been flushed.
+    acks: Vec<Ack>,
 }
 
-impl<'a, T> FileSource<'a, T> {
-    pub async fn new(name: String, path: PathBuf, delimiter: &'a [u8]) -> Result<Self> {
-        Self::new_with_channels(name, path, delimiter, vec![]).await
+/// Controls how often a `FileSink` flushes the records it has written to disk.
+/// Whichever limit is hit first triggers the flush.
+#[derive(Debug, Clone, Copy)]
+pub struct FlushPolicy {
+    /// Flush at least this often while there are unflushed records.
+    pub interval: Duration,
+    /// Flush once this many records have been written since the last flush.
+    pub batch_size: usize,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            batch_size: 1024,
+        }
     }
+}
+
+/// Controls how a `FileSource` splits a file in to records.
+#[derive(Debug, Clone, Copy)]
+pub struct FileSourceOptions {
+    /// Longest record to send, in bytes. Longer records are split in to several of this length, otherwise a
+    /// file missing its delimiters would be held in memory until it ends.
+    pub max_record_len: usize,
+}
 
-    pub async fn new_with_channels(
+impl Default for FileSourceOptions {
+    fn default() -> Self {
+        Self {
+            max_record_len: 1024 * 1024,
+        }
+    }
+}
+
+impl FileSource {
+    /// If `checkpoints` has an entry for this source and the file at `path`, reading resumes from there.
+    /// `options` defaults to `FileSourceOptions::default()` if unspecified.
+    pub async fn new(
         name: String,
         path: PathBuf,
-        delimiter: &'a [u8],
-        channels: impl IntoIterator<Item = tokio::sync::mpsc::Sender<T>>,
+        delimiter: Vec<u8>,
+        channels: impl IntoIterator<Item = Sender<Event>>,
+        checkpoints: Option<CheckpointStore>,
+        options: Option<FileSourceOptions>,
     ) -> Result<Self> {
+        validate_source_name(&name)?;
         // Open this here, because we want to stop
-        let file = File::open(&path).await?;
+        let mut file = File::open(&path).await?;
+        let meta = file.metadata().await?;
+        let identity = FileIdentity::from(&meta);
+        let fingerprint = Fingerprint::of(&file).await?;
+
+        let offset = checkpoints
+            .as_ref()
+            .and_then(|store| store.get(&name, identity, fingerprint, meta.len()))
+            .unwrap_or(0);
+        if offset > 0 {
+            info!(
+                "{}: resuming {} from offset {}",
+                name,
+                path.display(),
+                offset
+            );
+            file.seek(SeekFrom::Start(offset)).await?;
+        }
+
+        let name: Arc<str> = name.into();
         Ok(Self {
-            name,
-            path,
+            name: name.clone(),
+            path: path.into(),
             file,
             delimiter,
             out_chans: channels.into_iter().collect(),
+            identity,
+            fingerprint,
+            offset,
+            pending: BytesMut::new(),
+            scanned: 0,
+            oversized: false,
+            options: options.unwrap_or_default(),
+            progress: checkpoints.map(|store| Arc::new(Mutex::new(Progress::new(name, store)))),
+            follow_file: false,
         })
     }
 
-    pub fn register_channel(&mut self, channel: Sender<T>) -> Result<()> {
-        self.out_chans.push(channel);
-        Ok(())
+    /// By default we follow whatever file is at `path` (i.e. `tail -F`). Setting this follows the file that
+    /// was opened instead, wherever it is renamed to (i.e. `tail -f`), and makes `start` return once it has
+    /// been deleted and fully read, dropping its checkpoint. Its checkpoint is kept alongside any others under
+    /// the same name, so several of these can share one name.
+    pub fn set_follow_file(&mut self, follow: bool) {
+        self.follow_file = follow;
+        if let Some(progress) = &self.progress {
+            progress.lock().unwrap().shared = follow;
+        }
     }
 
-    pub async fn start(self) -> Result<()> {
-        let mut reader = BufReader::new(self.file.try_clone().await?);
+    /// Reads the file, splitting it in to records on `delimiter` and sending each record to every channel.
+    /// Once EOF is reached we keep following the file for newly appended data (i.e. `tail -F`), so this
+    /// only returns on error, on shutdown, once every channel has been closed or, if `set_follow_file`
+    /// was used, once the file has been deleted.
+    ///
+    /// Rotation is handled for both of the common logrotate strategies:
+    /// - rename and recreate: the old handle is drained before the new file at `path` is opened.
+    /// - copytruncate: the file shrinking below our offset means it was truncated, so we go back to the start.
+    async fn run(&mut self, mut shutdown: Shutdown) -> Result<()> {
+        if self.delimiter.is_empty() {
+            bail!("{}: delimiter must not be empty", self.name);
+        }
+        if self.options.max_record_len == 0 {
+            bail!("{}: max_record_len must be greater than 0", self.name);
+        }
+        info!("{}: reading from {}", self.name, self.path.display());
+
+        let mut watcher = FileWatcher::new(&self.path);
         loop {
-            // Keep reading from the file until EOF.
-            // TODO: impl
+            // Any partial record in `pending` is after the last checkpoint, so it is read again next time.
+            if shutdown.is_triggered() {
+                info!("{}: shutting down", self.name);
+                return Ok(());
+            }
+            if self.read_records().await? > 0 {
+                continue;
+            }
+
+            // Hit EOF, a partial record stays in `pending` until the rest of it is written.
+            match self.check_rotation().await? {
+                Rotation::None => {
+                    tokio::select! {
+                        _ = watcher.wait() => {}
+                        _ = shutdown.wait() => {}
+                        _ = outputs_closed(&self.out_chans) => {
+                            info!("{}: all output channels are closed, stopping", self.name);
+                            return Ok(());
+                        }
+                    }
+                }
+                Rotation::Truncated => {
+                    warn!(
+                        "{}: {} was truncated, reading from the start",
+                        self.name,
+                        self.path.display()
+                    );
+                    self.flush_partial().await?;
+                    self.file.seek(SeekFrom::Start(0)).await?;
+                    self.offset = 0;
+                    self.fingerprint = None;
+                    self.commit_sent(0).await?;
+                }
+                Rotation::Replaced(file) => {
+                    info!(
+                        "{}: {} was rotated, draining the old file",
+                        self.name,
+                        self.path.display()
+                    );
+                    // The writer may have appended more before it moved on to the new file.
+                    while self.read_records().await? > 0 {}
+                    self.flush_partial().await?;
+                    self.identity = FileIdentity::from(&file.metadata().await?);
+                    self.file = file;
+                    self.offset = 0;
+                    self.fingerprint = None;
+                    self.commit_sent(0).await?;
+                    watcher.watch_file(&self.path);
+                }
+                Rotation::Removed => {
+                    while self.read_records().await? > 0 {}
+                    self.flush_partial().await?;
+                    if let Some(progress) = &self.progress {
+                        progress.lock().unwrap().retire(self.identity);
+                    }
+                    info!(
+                        "{}: {} was deleted and has been fully read, stopping",
+                        self.name,
+                        self.path.display()
+                    );
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Does a single read from the file and sends any complete records.
+    /// Returns the number of bytes read, which is 0 at EOF.
+    async fn read_records(&mut self) -> Result<usize> {
+        self.pending.reserve(FILE_READ_SIZE);
+        let n = self.file.read_buf(&mut self.pending).await?;
+        if n == 0 {
+            return Ok(0);
+        }
+        self.offset += n as u64;
+
+        let mut records = vec![];
+        while let Some((len, skip)) = self.next_record() {
+            let start = self.offset - self.pending.len() as u64;
+            // Splitting off the front of `pending` hands its memory to the record without copying it.
+            let record = self.pending.split_to(len);
+            self.pending.advance(skip);
+            self.scanned = 0;
+            records.push((record, start));
+        }
+        // The partial record hasn't been sent yet, so it needs to be read again if we restart.
+        let ack = self.commit(self.offset - self.pending.len() as u64).await?;
+        for (record, start) in records {
+            self.send(record, start, ack.clone()).await?;
+        }
+        // Every event has its own clone now, this one only has to stop holding the checkpoint back.
+        if let Some(ack) = ack {
+            ack.delivered();
+        }
+        Ok(n)
+    }
+
+    /// Returns the length of the next complete record at the front of `pending` and how many bytes follow
+    /// it before the next record, i.e. the delimiter, or `None` if there is no complete record yet.
+    fn next_record(&mut self) -> Option<(usize, usize)> {
+        let max_len = self.options.max_record_len;
+        // A delimiter may have been cut off by the end of the last read, so search its start again too.
+        let from = self.scanned.saturating_sub(self.delimiter.len() - 1);
+        // No need to search any further than the longest record.
+        let to = self.pending.len().min(max_len + self.delimiter.len());
+        if from < to
+            && let Some(pos) = find_delimiter(&self.pending[from..to], &self.delimiter)
+        {
+            self.oversized = false;
+            return Some((from + pos, self.delimiter.len()));
+        }
+        if self.pending.len() >= max_len {
+            if !self.oversized {
+                warn!(
+                    "{}: record in {} at offset {} is longer than {} bytes, splitting it",
+                    self.name,
+                    self.path.display(),
+                    self.offset - self.pending.len() as u64,
+                    max_len
+                );
+                self.oversized = true;
+            }
+            return Some((max_len, 0));
+        }
+        self.scanned = self.pending.len();
+        None
+    }
+
+    /// Records that everything in the current file before `offset` has been sent, with no events to go along
+    /// with it.
+    async fn commit_sent(&mut self, offset: u64) -> Result<()> {
+        if let Some(ack) = self.commit(offset).await? {
+            ack.delivered();
+        }
+        Ok(())
+    }
+
+    /// Records that everything in the current file before `offset` is being sent. The checkpoint is only
+    /// committed once the returned `Ack`, to attach to the events being sent, and every event sent before
+    /// them have been delivered. Returns `None` if there is no checkpoint store.
+    async fn commit(&mut self, offset: u64) -> Result<Option<Ack>> {
+        let Some(progress) = &self.progress else {
+            return Ok(None);
+        };
+        if self.fingerprint.is_none() {
+            self.fingerprint = Fingerprint::of(&self.file).await?;
+        }
+        let checkpoint = Checkpoint {
+            fingerprint: self.fingerprint,
+            offset,
+        };
+        let seq = progress.lock().unwrap().push(self.identity, checkpoint);
+        let progress = progress.clone();
+        Ok(Some(Ack::new(move |delivered| {
+            progress.lock().unwrap().done(seq, delivered)
+        })))
+    }
+
+    /// Sends a trailing record that was never terminated by a delimiter, used when we are about to
+    /// stop reading from the current file and the rest of the record can no longer arrive.
+    async fn flush_partial(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let start = self.offset - self.pending.len() as u64;
+        let record = self.pending.split();
+        self.scanned = 0;
+        self.oversized = false;
+        let ack = self.commit(self.offset).await?;
+        self.send(record, start, ack).await
+    }
+
+    /// Compares the file we have open with what is currently at `path`.
+    async fn check_rotation(&self) -> Result<Rotation> {
+        if self.follow_file {
+            let meta = self.file.metadata().await?;
+            if meta.nlink() == 0 {
+                return Ok(Rotation::Removed);
+            }
+            if meta.len() < self.offset {
+                return Ok(Rotation::Truncated);
+            }
+            return Ok(Rotation::None);
+        }
+
+        match tokio::fs::metadata(&self.path).await {
+            Ok(meta) if FileIdentity::from(&meta) != self.identity => {
+                // The new file may be moved again before we get to it, in which case we pick it up next time.
+                match File::open(&self.path).await {
+                    Ok(file) => return Ok(Rotation::Replaced(file)),
+                    Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Rotation::None),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            Ok(_) => {}
+            // Nothing at `path` means the file was moved and hasn't been recreated yet, keep reading the old one.
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        if self.file.metadata().await?.len() < self.offset {
+            return Ok(Rotation::Truncated);
+        }
+        Ok(Rotation::None)
+    }
+
+    /// `start` is the offset in to the current file that `record` was read from.
+    async fn send(&mut self, record: BytesMut, start: u64, ack: Option<Ack>) -> Result<()> {
+        let mut metadata = event::Metadata::new(self.name.clone());
+        metadata.path = Some(self.path.clone());
+        metadata.offset = Some(start);
+        let mut event = Event::new(record.freeze(), metadata);
+        if let Some(ack) = ack {
+            event = event.with_ack(ack);
+        }
+        send_all(&self.name, &mut self.out_chans, event).await
+    }
+}
+
+/// The checkpoints a `FileSource` has reached, committed in order as everything read before each one is
+/// delivered, so that a restarted agent reads again whatever hadn't made it to the sinks.
+struct Progress {
+    name: Arc<str>,
+    store: CheckpointStore,
+    /// Oldest first, along with their sequence number and whether their events have been delivered.
+    positions: VecDeque<(u64, FileIdentity, Checkpoint, bool)>,
+    next_seq: u64,
+    /// Set once events weren't delivered. Nothing is committed after that, so they are read again once the
+    /// agent restarts.
+    stalled: bool,
+    /// Commit with `CheckpointStore::commit_file`, keeping the checkpoints of other files under this name.
+    shared: bool,
+    /// The file, once it has been deleted. Its checkpoint is dropped as soon as nothing is left in flight.
+    retired: Option<FileIdentity>,
+}
+
+impl Progress {
+    fn new(name: Arc<str>, store: CheckpointStore) -> Self {
+        Self {
+            name,
+            store,
+            positions: VecDeque::new(),
+            next_seq: 0,
+            stalled: false,
+            shared: false,
+            retired: None,
+        }
+    }
+
+    /// The file has been deleted, so there will never be anything to resume.
+    fn retire(&mut self, identity: FileIdentity) {
+        self.retired = Some(identity);
+        if self.positions.is_empty() {
+            self.store.remove(&self.name, identity);
+        }
+    }
+
+    /// Adds the next checkpoint, returning the sequence number to report its events' delivery with.
+    fn push(&mut self, identity: FileIdentity, checkpoint: Checkpoint) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        if !self.stalled {
+            self.positions.push_back((seq, identity, checkpoint, false));
+        }
+        seq
+    }
+
+    fn done(&mut self, seq: u64, delivered: bool) {
+        if self.stalled {
+            return;
+        }
+        if !delivered {
+            warn!(
+                "{}: events weren't delivered, no longer checkpointing so that they are read again after a restart",
+                self.name
+            );
+            self.stalled = true;
+            self.positions.clear();
+            return;
+        }
+        if let Some(position) = self
+            .positions
+            .iter_mut()
+            .find(|(position, ..)| *position == seq)
+        {
+            position.3 = true;
+        }
+        let mut reached = None;
+        while self
+            .positions
+            .front()
+            .is_some_and(|(.., delivered)| *delivered)
+        {
+            reached = self.positions.pop_front();
+        }
+        let Some((_, identity, checkpoint, _)) = reached else {
+            return;
+        };
+        if let Some(retired) = self.retired
+            && self.positions.is_empty()
+        {
+            self.store.remove(&self.name, retired);
+        } else if self.shared {
+            self.store.commit_file(&self.name, identity, checkpoint);
+        } else {
+            self.store.commit(&self.name, identity, checkpoint);
+        }
+    }
+}
+
+impl Component for FileSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn start(&mut self, shutdown: Shutdown) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.run(shutdown))
+    }
+
+    fn health(&self) -> Health {
+        outputs_health(&self.out_chans)
+    }
+
+    fn shutdown(&mut self) -> BoxFuture<'_, Result<()>> {
+        self.out_chans.clear();
+        Box::pin(async { Ok(()) })
+    }
+}
+
+impl Source for FileSource {}
+
+/// Components that have had every output channel closed have nowhere left to send to.
+fn outputs_health(out_chans: &[Sender<Event>]) -> Health {
+    if out_chans.iter().all(|chan| chan.is_closed()) {
+        Health::Unhealthy("all output channels are closed".to_string())
+    } else {
+        Health::Healthy
+    }
+}
+
+/// The channel a transform or sink reads from, after any events handed on by the component it replaced.
+pub struct Input {
+    unfinished: VecDeque<Event>,
+    receiver: Receiver<Event>,
+}
+
+impl Input {
+    /// Events to read before anything else.
+    pub fn with_unfinished(mut self, events: impl IntoIterator<Item = Event>) -> Self {
+        let mut unfinished: VecDeque<Event> = events.into_iter().collect();
+        unfinished.append(&mut self.unfinished);
+        self.unfinished = unfinished;
+        self
+    }
+
+    /// Cancel safe, so it can be used in `select!`.
+    pub async fn recv(&mut self) -> Option<Event> {
+        match self.unfinished.pop_front() {
+            Some(event) => Some(event),
+            None => self.receiver.recv().await,
         }
     }
+
+    /// Swaps this out for an input that is already closed, returning the original.
+    fn take(&mut self) -> Self {
+        let (_, closed) = tokio::sync::mpsc::channel(1);
+        std::mem::replace(self, Self::from(closed))
+    }
+}
+
+impl From<Receiver<Event>> for Input {
+    fn from(receiver: Receiver<Event>) -> Self {
+        Self {
+            unfinished: VecDeque::new(),
+            receiver,
+        }
+    }
+}
+
+/// Resolves once every channel in `out_chans` has been closed, so idle sources can notice that everything
+/// downstream of them has gone without waiting for their next record.
+async fn outputs_closed(out_chans: &[Sender<Event>]) {
+    futures::future::join_all(out_chans.iter().map(Sender::closed)).await;
+}
+
+/// Sends a record to every channel in `out_chans`, dropping any channels whose receiver has gone away.
+/// Fails once there are no channels left to send to.
+async fn send_all(name: &str, out_chans: &mut Vec<Sender<Event>>, record: Event) -> Result<()> {
+    let mut closed = vec![];
+    // Every copy of an event has to be acknowledged, so the last channel gets `record` rather than a copy.
+    let last = out_chans.len().saturating_sub(1);
+    let mut record = Some(record);
+    for (idx, chan) in out_chans.iter().enumerate() {
+        let copy = if idx == last {
+            record.take()
+        } else {
+            record.clone()
+        };
+        if let Some(copy) = copy
+            && chan.send(copy).await.is_err()
+        {
+            closed.push(idx);
+        }
+    }
+    for idx in closed.into_iter().rev() {
+        warn!(
+            "{}: output channel {} closed, no longer sending to it",
+            name, idx
+        );
+        out_chans.remove(idx);
+    }
+    if out_chans.is_empty() {
+        bail!("{}: all output channels are closed", name);
+    }
+    Ok(())
+}
+
+/// Uniquely identifies a file on this host, regardless of what path it is currently at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileIdentity {
+    pub dev: u64,
+    pub ino: u64,
+}
+
+impl From<&Metadata> for FileIdentity {
+    fn from(meta: &Metadata) -> Self {
+        Self {
+            dev: meta.dev(),
+            ino: meta.ino(),
+        }
+    }
+}
+
+enum Rotation {
+    None,
+    /// The file was truncated in place (copytruncate).
+    Truncated,
+    /// A different file now lives at the path (rename and recreate), this is the newly opened file.
+    Replaced(File),
+    /// The file has been deleted, only returned if the source follows the file rather than the path.
+    Removed,
+}
+
+/// Returns the index of the first occurrence of `delimiter` in `buf`. `delimiter` must not be empty.
+fn find_delimiter(buf: &[u8], delimiter: &[u8]) -> Option<usize> {
+    buf.windows(delimiter.len()).position(|w| w == delimiter)
 }
 
-impl<'a, T> FileSink<'a, T> {
+impl FileSink {
+    /// Creates the file at `path` if it doesn't exist, otherwise records are appended to it.
+    /// `flush_policy` defaults to `FlushPolicy::default()` if unspecified.
     pub async fn new(
         name: String,
         path: PathBuf,
-        delimiter: &'a [u8],
-        recv: Receiver<T>,
+        delimiter: Vec<u8>,
+        recv: Input,
+        flush_policy: Option<FlushPolicy>,
     ) -> Result<Self> {
-        let file = File::open(&path).await?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
         Ok(Self {
             name,
             path,
-            file,
+            writer: BufWriter::new(file),
             delimiter,
             inp_chan: recv,
+            flush_policy: flush_policy.unwrap_or_default(),
+            unflushed: 0,
+            acks: vec![],
         })
     }
 
-    pub async fn start(self) -> Result<()> {
+    /// Writes every received record followed by `delimiter`, until all senders have been dropped or the sink
+    /// is being replaced.
+    async fn run(&mut self, mut shutdown: Shutdown) -> Result<()> {
+        info!("{}: writing to {}", self.name, self.path.display());
+        // Not `interval`, its first tick is immediate which would flush the first record on its own.
+        let mut flush_timer = tokio::time::interval_at(
+            tokio::time::Instant::now() + self.flush_policy.interval,
+            self.flush_policy.interval,
+        );
+        flush_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                record = self.inp_chan.recv() => {
+                    let Some(mut record) = record else {
+                        break;
+                    };
+                    if let Err(e) = self.write(&record.payload).await {
+                        // Whatever is still buffered may not make it out either.
+                        self.acks.clear();
+                        return Err(e);
+                    }
+                    self.unflushed += 1;
+                    self.acks.extend(record.take_ack());
+                    if self.unflushed >= self.flush_policy.batch_size {
+                        self.flush_writer().await?;
+                    }
+                }
+                _ = flush_timer.tick(), if self.unflushed > 0 => {
+                    self.flush_writer().await?;
+                }
+                _ = shutdown.wait() => {
+                    self.flush_writer().await?;
+                    info!("{}: stopping to be replaced", self.name);
+                    return Ok(());
+                }
+            }
+        }
+
+        self.flush_writer().await?;
+        info!("{}: all senders closed, stopping", self.name);
         Ok(())
     }
+
+    async fn write(&mut self, payload: &[u8]) -> Result<()> {
+        self.writer.write_all(payload).await?;
+        self.writer.write_all(&self.delimiter).await?;
+        Ok(())
+    }
+
+    /// Flushes the writer, acknowledging everything written since the last flush. If that fails, their acks
+    /// are dropped instead, telling the sources that those events weren't delivered.
+    async fn flush_writer(&mut self) -> Result<()> {
+        if let Err(e) = self.writer.flush().await {
+            self.acks.clear();
+            return Err(e.into());
+        }
+        self.unflushed = 0;
+        for ack in self.acks.drain(..) {
+            ack.delivered();
+        }
+        Ok(())
+    }
+}
+
+impl Component for FileSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn start(&mut self, shutdown: Shutdown) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.run(shutdown))
+    }
+
+    fn shutdown(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async {
+            Sink::flush(self).await?;
+            self.writer.get_ref().sync_data().await?;
+            Ok(())
+        })
+    }
+
+    fn take_input(&mut self) -> Option<Input> {
+        Some(self.inp_chan.take())
+    }
 }
 
-pub struct QUICSource {
-    listen_addr: Ipv4Addr,
-    listen_port: u16,
+impl Sink for FileSink {
+    fn flush(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.flush_writer())
+    }
 }
 
-pub struct QUICSink {
-    peer_addr: Ipv4Addr,
-    peer_port: u16,
+/// Only passes on records that contain `pattern`, or with `invert` set, records that don't contain it.
+pub struct Filter {
+    name: String,
+    pattern: Vec<u8>,
+    invert: bool,
+}
+
+impl Filter {
+    pub fn new(name: String, pattern: Vec<u8>, invert: bool) -> Self {
+        Self {
+            name,
+            pattern,
+            invert,
+        }
+    }
+}
+
+impl Transform for Filter {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn transform(&mut self, record: Event) -> Option<Event> {
+        let found =
+            self.pattern.is_empty() || find_delimiter(&record.payload, &self.pattern).is_some();
+        (found != self.invert).then_some(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const IDENTITY: FileIdentity = FileIdentity { dev: 1, ino: 2 };
+
+    /// A directory of its own, removed once the test is done with it.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(test: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("logga-module-{}-{}", std::process::id(), test));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn append(path: &Path, contents: &str) {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+    }
+
+    /// Starts following `path`, returning what it sends and the sender that shuts it down.
+    async fn follow(
+        path: &Path,
+        delimiter: &str,
+        options: Option<FileSourceOptions>,
+    ) -> (Receiver<Event>, tokio::sync::watch::Sender<bool>) {
+        let (send, recv) = tokio::sync::mpsc::channel(16);
+        let mut source = FileSource::new(
+            "app".to_string(),
+            path.to_path_buf(),
+            delimiter.as_bytes().to_vec(),
+            [send],
+            None,
+            options,
+        )
+        .await
+        .unwrap();
+        let (stop, shutdown) = Shutdown::channel();
+        tokio::spawn(async move { source.run(shutdown).await.unwrap() });
+        (recv, stop)
+    }
+
+    /// Returns the payloads of the next `count` events.
+    async fn records(recv: &mut Receiver<Event>, count: usize) -> Vec<String> {
+        let mut payloads = vec![];
+        for _ in 0..count {
+            let event = tokio::time::timeout(Duration::from_secs(5), recv.recv())
+                .await
+                .expect("timed out waiting for a record")
+                .unwrap();
+            payloads.push(String::from_utf8(event.payload.to_vec()).unwrap());
+        }
+        payloads
+    }
+
+    async fn assert_idle(recv: &mut Receiver<Event>) {
+        if let Ok(Some(event)) = tokio::time::timeout(Duration::from_millis(300), recv.recv()).await
+        {
+            panic!("unexpected record {:?}", event.payload);
+        }
+    }
+
+    async fn progress(test: &str) -> Progress {
+        let path =
+            std::env::temp_dir().join(format!("logga-progress-{}-{}", std::process::id(), test));
+        // Never flushed, so nothing is written to `path`.
+        let store = CheckpointStore::load(path).await.unwrap();
+        Progress::new("app".into(), store)
+    }
+
+    fn checkpoint(offset: u64) -> Checkpoint {
+        Checkpoint {
+            fingerprint: None,
+            offset,
+        }
+    }
+
+    fn committed(progress: &Progress) -> Option<u64> {
+        progress.store.get("app", IDENTITY, None, u64::MAX)
+    }
+
+    #[tokio::test]
+    async fn commits_in_order() {
+        let mut progress = progress("commits_in_order").await;
+        let first = progress.push(IDENTITY, checkpoint(10));
+        let second = progress.push(IDENTITY, checkpoint(20));
+        let third = progress.push(IDENTITY, checkpoint(30));
+        progress.done(second, true);
+        assert_eq!(committed(&progress), None);
+        progress.done(first, true);
+        assert_eq!(committed(&progress), Some(20));
+        progress.done(third, true);
+        assert_eq!(committed(&progress), Some(30));
+    }
+
+    #[tokio::test]
+    async fn stops_at_undelivered() {
+        let mut progress = progress("stops_at_undelivered").await;
+        let first = progress.push(IDENTITY, checkpoint(10));
+        let second = progress.push(IDENTITY, checkpoint(20));
+        progress.done(first, true);
+        progress.done(second, false);
+        let third = progress.push(IDENTITY, checkpoint(30));
+        progress.done(third, true);
+        assert_eq!(committed(&progress), Some(10));
+    }
+
+    #[tokio::test]
+    async fn retiring_drops_the_checkpoint_once_delivered() {
+        let mut progress = progress("retiring_drops_the_checkpoint_once_delivered").await;
+        progress.shared = true;
+        let other = FileIdentity { dev: 1, ino: 3 };
+        progress.store.commit_file("app", other, checkpoint(5));
+        let first = progress.push(IDENTITY, checkpoint(10));
+        let second = progress.push(IDENTITY, checkpoint(20));
+        progress.done(first, true);
+        progress.retire(IDENTITY);
+        assert_eq!(committed(&progress), Some(10));
+        progress.done(second, true);
+        assert_eq!(committed(&progress), None);
+        // Only this file's checkpoint goes, not those of the others sharing the name.
+        assert_eq!(progress.store.get("app", other, None, u64::MAX), Some(5));
+    }
+
+    #[tokio::test]
+    async fn follows_appended_records() {
+        let dir = TempDir::new("follows_appended_records");
+        let path = dir.0.join("app.log");
+        append(&path, "a\nb\n");
+        let (mut recv, _stop) = follow(&path, "\n", None).await;
+        assert_eq!(records(&mut recv, 2).await, ["a", "b"]);
+        append(&path, "c\n");
+        assert_eq!(records(&mut recv, 1).await, ["c"]);
+    }
+
+    #[tokio::test]
+    async fn splits_on_the_delimiter() {
+        let dir = TempDir::new("splits_on_the_delimiter");
+        let path = dir.0.join("app.log");
+        append(&path, "a;;b;;;;c;;");
+        let (mut recv, _stop) = follow(&path, ";;", None).await;
+        let event = recv.recv().await.unwrap();
+        assert_eq!(event.payload, "a");
+        assert_eq!(event.metadata.offset, Some(0));
+        assert_eq!(event.metadata.path.as_deref(), Some(path.as_path()));
+        // Empty records between consecutive delimiters are kept.
+        assert_eq!(records(&mut recv, 3).await, ["b", "", "c"]);
+    }
+
+    #[tokio::test]
+    async fn holds_back_a_partial_record() {
+        let dir = TempDir::new("holds_back_a_partial_record");
+        let path = dir.0.join("app.log");
+        append(&path, "a\npart");
+        let (mut recv, _stop) = follow(&path, "\n", None).await;
+        assert_eq!(records(&mut recv, 1).await, ["a"]);
+        assert_idle(&mut recv).await;
+        append(&path, "ial\n");
+        assert_eq!(records(&mut recv, 1).await, ["partial"]);
+    }
+
+    #[tokio::test]
+    async fn splits_records_longer_than_the_maximum() {
+        let dir = TempDir::new("splits_records_longer_than_the_maximum");
+        let path = dir.0.join("app.log");
+        append(&path, "abcdefghij\nabc\n");
+        let options = FileSourceOptions { max_record_len: 4 };
+        let (mut recv, _stop) = follow(&path, "\n", Some(options)).await;
+        assert_eq!(records(&mut recv, 4).await, ["abcd", "efgh", "ij", "abc"]);
+        // Split as soon as it is long enough, rather than waiting for the rest of it.
+        append(&path, "klmnop");
+        assert_eq!(records(&mut recv, 1).await, ["klmn"]);
+        assert_idle(&mut recv).await;
+    }
+
+    #[tokio::test]
+    async fn finds_delimiters_cut_off_by_a_read() {
+        let dir = TempDir::new("finds_delimiters_cut_off_by_a_read");
+        let path = dir.0.join("app.log");
+        append(&path, "ab\r");
+        let (mut recv, _stop) = follow(&path, "\r\n", None).await;
+        assert_idle(&mut recv).await;
+        append(&path, "\ncd\r\n");
+        assert_eq!(records(&mut recv, 2).await, ["ab", "cd"]);
+    }
+
+    #[tokio::test]
+    async fn drains_a_renamed_file_before_the_new_one() {
+        let dir = TempDir::new("drains_a_renamed_file_before_the_new_one");
+        let path = dir.0.join("app.log");
+        append(&path, "a\n");
+        let (mut recv, _stop) = follow(&path, "\n", None).await;
+        assert_eq!(records(&mut recv, 1).await, ["a"]);
+        let rotated = dir.0.join("app.log.1");
+        std::fs::rename(&path, &rotated).unwrap();
+        // The writer finishing up with the old file, including a last record it never terminated.
+        append(&rotated, "b\nunterminated");
+        append(&path, "c\n");
+        assert_eq!(records(&mut recv, 3).await, ["b", "unterminated", "c"]);
+        assert_idle(&mut recv).await;
+    }
+
+    #[tokio::test]
+    async fn reads_a_truncated_file_from_the_start() {
+        let dir = TempDir::new("reads_a_truncated_file_from_the_start");
+        let path = dir.0.join("app.log");
+        append(&path, "a\nb\n");
+        let (mut recv, _stop) = follow(&path, "\n", None).await;
+        assert_eq!(records(&mut recv, 2).await, ["a", "b"]);
+        // copytruncate
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(0)
+            .unwrap();
+        append(&path, "c\n");
+        assert_eq!(records(&mut recv, 1).await, ["c"]);
+        assert_idle(&mut recv).await;
+    }
+
+    #[tokio::test]
+    async fn waits_for_a_removed_file_to_be_recreated() {
+        let dir = TempDir::new("waits_for_a_removed_file_to_be_recreated");
+        let path = dir.0.join("app.log");
+        append(&path, "a\n");
+        let (mut recv, _stop) = follow(&path, "\n", None).await;
+        assert_eq!(records(&mut recv, 1).await, ["a"]);
+        std::fs::remove_file(&path).unwrap();
+        assert_idle(&mut recv).await;
+        append(&path, "b\n");
+        assert_eq!(records(&mut recv, 1).await, ["b"]);
+    }
+
+    #[tokio::test]
+    async fn following_a_file_stops_once_it_is_deleted() {
+        let dir = TempDir::new("following_a_file_stops_once_it_is_deleted");
+        let path = dir.0.join("app.log");
+        append(&path, "a\n");
+        let (send, mut recv) = tokio::sync::mpsc::channel(16);
+        let mut source = FileSource::new(
+            "app".to_string(),
+            path.clone(),
+            b"\n".to_vec(),
+            [send],
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+        source.set_follow_file(true);
+        let (_stop, shutdown) = Shutdown::channel();
+        let running = tokio::spawn(async move { source.run(shutdown).await });
+        assert_eq!(records(&mut recv, 1).await, ["a"]);
+        // Renaming it isn't enough, it is followed under its new name.
+        let rotated = dir.0.join("app.log.1");
+        std::fs::rename(&path, &rotated).unwrap();
+        append(&rotated, "b\n");
+        assert_eq!(records(&mut recv, 1).await, ["b"]);
+        assert!(!running.is_finished());
+        append(&rotated, "c\n");
+        std::fs::remove_file(&rotated).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), running)
+            .await
+            .expect("didn't stop once the file was deleted")
+            .unwrap()
+            .unwrap();
+        assert_eq!(records(&mut recv, 1).await, ["c"]);
+    }
+
+    /// Starts writing to `path`, returning the sender to write with and the running sink.
+    async fn write_to(
+        path: &Path,
+        delimiter: &str,
+        flush_policy: Option<FlushPolicy>,
+    ) -> (Sender<Event>, tokio::task::JoinHandle<Result<()>>) {
+        let (send, recv) = tokio::sync::mpsc::channel(16);
+        let mut sink = FileSink::new(
+            "out".to_string(),
+            path.to_path_buf(),
+            delimiter.as_bytes().to_vec(),
+            recv.into(),
+            flush_policy,
+        )
+        .await
+        .unwrap();
+        let (_stop, shutdown) = Shutdown::channel();
+        (send, tokio::spawn(async move { sink.run(shutdown).await }))
+    }
+
+    /// An event whose delivery is reported on `delivered`.
+    fn tracked(
+        payload: &'static str,
+        delivered: &tokio::sync::mpsc::UnboundedSender<bool>,
+    ) -> Event {
+        let delivered = delivered.clone();
+        Event::new(payload, event::Metadata::new("app".into())).with_ack(Ack::new(move |done| {
+            let _ = delivered.send(done);
+        }))
+    }
+
+    #[tokio::test]
+    async fn creates_then_appends_to_the_file() {
+        let dir = TempDir::new("creates_then_appends_to_the_file");
+        let path = dir.0.join("out.log");
+        for (payload, expected) in [("a", "a;;"), ("b", "a;;b;;")] {
+            let (send, sink) = write_to(&path, ";;", None).await;
+            send.send(Event::new(payload, event::Metadata::new("app".into())))
+                .await
+                .unwrap();
+            drop(send);
+            sink.await.unwrap().unwrap();
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn flushes_once_a_batch_fills() {
+        let dir = TempDir::new("flushes_once_a_batch_fills");
+        let path = dir.0.join("out.log");
+        let policy = FlushPolicy {
+            interval: Duration::from_secs(3600),
+            batch_size: 2,
+        };
+        let (send, _sink) = write_to(&path, "\n", Some(policy)).await;
+        let (delivered, mut acks) = tokio::sync::mpsc::unbounded_channel();
+        send.send(tracked("a", &delivered)).await.unwrap();
+        // Held until the record has actually been flushed to the file.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(acks.try_recv().is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+
+        send.send(tracked("b", &delivered)).await.unwrap();
+        assert_eq!(acks.recv().await, Some(true));
+        assert_eq!(acks.recv().await, Some(true));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a\nb\n");
+    }
+
+    #[tokio::test]
+    async fn flushes_once_the_interval_passes() {
+        let dir = TempDir::new("flushes_once_the_interval_passes");
+        let path = dir.0.join("out.log");
+        let policy = FlushPolicy {
+            interval: Duration::from_millis(200),
+            batch_size: 1024,
+        };
+        let (send, _sink) = write_to(&path, "\n", Some(policy)).await;
+        let (delivered, mut acks) = tokio::sync::mpsc::unbounded_channel();
+        send.send(tracked("a", &delivered)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(acks.try_recv().is_err());
+
+        let ack = tokio::time::timeout(Duration::from_secs(5), acks.recv()).await;
+        assert_eq!(ack.unwrap(), Some(true));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a\n");
+    }
+
+    #[tokio::test]
+    async fn input_reads_unfinished_first() {
+        let event = |payload: &'static str| Event::new(payload, event::Metadata::new("app".into()));
+        let (send, recv) = tokio::sync::mpsc::channel(4);
+        send.send(event("channel")).await.unwrap();
+        drop(send);
+        let mut input = Input::from(recv)
+            .with_unfinished([event("second")])
+            .with_unfinished([event("first")]);
+        let mut read = vec![];
+        while let Some(event) = input.recv().await {
+            read.push(event.payload);
+        }
+        assert_eq!(read, ["first", "second", "channel"]);
+    }
 }
@@ -0,0 +1,368 @@
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use glob::Pattern;
+use std::{collections::HashSet, path::PathBuf, time::Duration};
+use tokio::{sync::mpsc::Sender, task::JoinSet};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    checkpoint::CheckpointStore,
+    event::Event,
+    module::{
+        Component, FileIdentity, FileSource, FileSourceOptions, Health, Shutdown, Source,
+        outputs_health,
+    },
+};
+
+/// Controls how a `FileDiscovery` looks for files.
+#[derive(Debug, Clone, Copy)]
+pub struct DiscoveryOptions {
+    /// How often to look for new files matching the patterns.
+    pub rescan_interval: Duration,
+    /// Maximum number of files to have open at once. Files found beyond this are picked up on a later scan,
+    /// once some of the open ones have been removed and fully read.
+    pub max_open_files: usize,
+    /// Options for each file's `FileSource`.
+    pub source: FileSourceOptions,
+}
+
+impl Default for DiscoveryOptions {
+    fn default() -> Self {
+        Self {
+            rescan_interval: Duration::from_secs(10),
+            max_open_files: 256,
+            source: FileSourceOptions::default(),
+        }
+    }
+}
+
+/// Follows every file matching a set of glob patterns, e.g. `/var/log/app/*.log`, with one `FileSource`
+/// per file. New files are found by rescanning periodically, and a file's `FileSource` is retired once the
+/// file has been deleted and fully read.
+///
+/// Files are told apart by identity rather than path, so a file that is rotated to another name matching the
+/// patterns keeps its `FileSource`, and its checkpoint under this source's name, instead of being read again.
+pub struct FileDiscovery {
+    name: String,
+    include: Vec<String>,
+    exclude: Vec<Pattern>,
+    delimiter: Vec<u8>,
+    out_chans: Vec<Sender<Event>>,
+    checkpoints: Option<CheckpointStore>,
+    options: DiscoveryOptions,
+}
+
+impl FileDiscovery {
+    /// `options` defaults to `DiscoveryOptions::default()` if unspecified.
+    pub fn new(
+        name: String,
+        include: Vec<String>,
+        exclude: &[String],
+        delimiter: Vec<u8>,
+        channels: impl IntoIterator<Item = Sender<Event>>,
+        checkpoints: Option<CheckpointStore>,
+        options: Option<DiscoveryOptions>,
+    ) -> Result<Self> {
+        // Check the include patterns up front too, so that typos are reported at startup rather than on each scan.
+        for pattern in &include {
+            Pattern::new(pattern)
+                .with_context(|| format!("{}: invalid include pattern {:?}", name, pattern))?;
+        }
+        let exclude = exclude
+            .iter()
+            .map(|pattern| {
+                Pattern::new(pattern)
+                    .with_context(|| format!("{}: invalid exclude pattern {:?}", name, pattern))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            name,
+            include,
+            exclude,
+            delimiter,
+            out_chans: channels.into_iter().collect(),
+            checkpoints,
+            options: options.unwrap_or_default(),
+        })
+    }
+
+    /// Returns every file currently matching the patterns, sorted so that files are picked up in a stable order.
+    fn scan(&self) -> Vec<PathBuf> {
+        let mut found = vec![];
+        for pattern in &self.include {
+            let paths = match glob::glob(pattern) {
+                Ok(paths) => paths,
+                Err(e) => {
+                    error!(
+                        "{}: invalid include pattern {:?}: {}",
+                        self.name, pattern, e
+                    );
+                    continue;
+                }
+            };
+            for entry in paths {
+                match entry {
+                    Ok(path) if path.is_file() => found.push(path),
+                    Ok(_) => {}
+                    Err(e) => warn!("{}: unable to read {}", self.name, e),
+                }
+            }
+        }
+        found.retain(|path| !self.exclude.iter().any(|p| p.matches_path(path)));
+        found.sort();
+        found.dedup();
+        found
+    }
+
+    /// Keeps scanning for files until every channel has been closed or `shutdown` is triggered, in which case
+    /// this waits for every file's `FileSource` to stop too.
+    async fn run(&mut self, mut shutdown: Shutdown) -> Result<()> {
+        info!(
+            "{}: discovering files matching {:?}",
+            self.name, self.include
+        );
+        let mut tailers = JoinSet::new();
+        // Files we currently have a `FileSource` for.
+        let mut active = HashSet::new();
+        let mut rescan = tokio::time::interval(self.options.rescan_interval);
+        rescan.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = rescan.tick() => {
+                    if self.out_chans.iter().all(|chan| chan.is_closed()) {
+                        info!("{}: all output channels are closed, stopping", self.name);
+                        tailers.shutdown().await;
+                        return Ok(());
+                    }
+                    for path in self.scan() {
+                        // Most likely removed since the scan, if not the error is reported by `tailer`.
+                        if let Ok(meta) = std::fs::metadata(&path)
+                            && active.contains(&FileIdentity::from(&meta))
+                        {
+                            continue;
+                        }
+                        if active.len() >= self.options.max_open_files {
+                            debug!(
+                                "{}: already have {} files open, deferring the rest to the next scan",
+                                self.name,
+                                active.len()
+                            );
+                            break;
+                        }
+                        match self.tailer(path.clone()).await {
+                            // Replaced since we checked by a file that is already being followed.
+                            Ok(tailer) if active.contains(&tailer.identity) => {}
+                            Ok(mut tailer) => {
+                                let identity = tailer.identity;
+                                active.insert(identity);
+                                let shutdown = shutdown.clone();
+                                tailers.spawn(async move {
+                                    let result = tailer.run(shutdown).await;
+                                    (path, identity, result)
+                                });
+                            }
+                            // Most likely removed between the scan and opening it.
+                            Err(e) => warn!("{}: unable to follow {}: {:#}", self.name, path.display(), e),
+                        }
+                    }
+                }
+                Some(finished) = tailers.join_next() => {
+                    let (path, identity, result) = finished?;
+                    // A failed file is picked up again on the next scan if it still exists.
+                    if let Err(e) = result {
+                        error!("{}: stopped following {}: {:#}", self.name, path.display(), e);
+                    }
+                    active.remove(&identity);
+                }
+                _ = shutdown.wait() => {
+                    while let Some(finished) = tailers.join_next().await {
+                        let (path, _, result) = finished?;
+                        if let Err(e) = result {
+                            error!("{}: stopped following {}: {:#}", self.name, path.display(), e);
+                        }
+                    }
+                    info!("{}: shutting down", self.name);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    async fn tailer(&self, path: PathBuf) -> Result<FileSource> {
+        // Checkpoints are keyed by file identity as well as name, so every file can share ours.
+        let mut source = FileSource::new(
+            self.name.clone(),
+            path,
+            self.delimiter.clone(),
+            self.out_chans.clone(),
+            self.checkpoints.clone(),
+            Some(self.options.source),
+        )
+        .await?;
+        source.set_follow_file(true);
+        Ok(source)
+    }
+}
+
+impl Component for FileDiscovery {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn start(&mut self, shutdown: Shutdown) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.run(shutdown))
+    }
+
+    fn health(&self) -> Health {
+        outputs_health(&self.out_chans)
+    }
+
+    fn shutdown(&mut self) -> BoxFuture<'_, Result<()>> {
+        self.out_chans.clear();
+        Box::pin(async { Ok(()) })
+    }
+}
+
+impl Source for FileDiscovery {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Write, path::Path};
+    use tokio::sync::mpsc::Receiver;
+
+    /// A directory of its own, removed once the test is done with it.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(test: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "logga-discovery-{}-{}",
+                std::process::id(),
+                test
+            ));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn append(path: &Path, contents: &str) {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+    }
+
+    /// Starts following `dir/*.log`, returning what it sends and the sender that shuts it down.
+    fn discover(
+        dir: &Path,
+        store: &CheckpointStore,
+    ) -> (Receiver<Event>, tokio::sync::watch::Sender<bool>) {
+        let (send, recv) = tokio::sync::mpsc::channel(16);
+        let mut discovery = FileDiscovery::new(
+            "app".to_string(),
+            vec![dir.join("*.log").display().to_string()],
+            &[],
+            b"\n".to_vec(),
+            [send],
+            Some(store.clone()),
+            Some(DiscoveryOptions {
+                rescan_interval: Duration::from_millis(20),
+                ..Default::default()
+            }),
+        )
+        .unwrap();
+        let (stop, shutdown) = Shutdown::channel();
+        tokio::spawn(async move { discovery.run(shutdown).await.unwrap() });
+        (recv, stop)
+    }
+
+    /// Delivers the next `count` events, returning their payloads sorted.
+    async fn deliver(recv: &mut Receiver<Event>, count: usize) -> Vec<String> {
+        let mut payloads = vec![];
+        for _ in 0..count {
+            let event = tokio::time::timeout(Duration::from_secs(5), recv.recv())
+                .await
+                .expect("timed out waiting for an event")
+                .unwrap();
+            payloads.push(String::from_utf8(event.payload.to_vec()).unwrap());
+            event.delivered();
+        }
+        payloads.sort();
+        payloads
+    }
+
+    async fn assert_idle(recv: &mut Receiver<Event>) {
+        if let Ok(Some(event)) = tokio::time::timeout(Duration::from_millis(300), recv.recv()).await
+        {
+            panic!("unexpected event {:?}", event.payload);
+        }
+    }
+
+    fn identity(path: &Path) -> FileIdentity {
+        FileIdentity::from(&std::fs::metadata(path).unwrap())
+    }
+
+    #[tokio::test]
+    async fn rotating_to_a_matching_name_does_not_read_again() {
+        let dir = TempDir::new("rotating_to_a_matching_name_does_not_read_again");
+        let store = CheckpointStore::load(dir.0.join("checkpoints"))
+            .await
+            .unwrap();
+        let log = dir.0.join("app.log");
+        let rotated = dir.0.join("app.1.log");
+        append(&log, "a\nb\n");
+
+        let (mut recv, stop) = discover(&dir.0, &store);
+        assert_eq!(deliver(&mut recv, 2).await, ["a", "b"]);
+        std::fs::rename(&log, &rotated).unwrap();
+        append(&rotated, "c\n");
+        append(&log, "d\n");
+        assert_eq!(deliver(&mut recv, 2).await, ["c", "d"]);
+        assert_idle(&mut recv).await;
+        stop.send(true).unwrap();
+
+        // Both files resume from their checkpoints after a restart, wherever they were first found.
+        let (mut recv, _stop) = discover(&dir.0, &store);
+        append(&rotated, "e\n");
+        assert_eq!(deliver(&mut recv, 1).await, ["e"]);
+        assert_idle(&mut recv).await;
+    }
+
+    #[tokio::test]
+    async fn drops_the_checkpoints_of_deleted_files() {
+        let dir = TempDir::new("drops_the_checkpoints_of_deleted_files");
+        let store = CheckpointStore::load(dir.0.join("checkpoints"))
+            .await
+            .unwrap();
+        let log = dir.0.join("app.log");
+        let other = dir.0.join("other.log");
+        append(&log, "a\n");
+        append(&other, "b\n");
+        let (log_identity, other_identity) = (identity(&log), identity(&other));
+
+        let (mut recv, _stop) = discover(&dir.0, &store);
+        assert_eq!(deliver(&mut recv, 2).await, ["a", "b"]);
+        assert_eq!(store.get("app", log_identity, None, u64::MAX), Some(2));
+        std::fs::remove_file(&log).unwrap();
+        tokio::time::timeout(Duration::from_secs(15), async {
+            while store.get("app", log_identity, None, u64::MAX).is_some() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("the deleted file's checkpoint was kept");
+        assert_eq!(store.get("app", other_identity, None, u64::MAX), Some(2));
+    }
+}
@@ -0,0 +1,1128 @@
+use anyhow::{Context, Result, bail};
+use futures::future::BoxFuture;
+use quinn::{Connection, ConnectionError, Incoming, RecvStream};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    sync::mpsc::{self, Sender},
+    task::JoinSet,
+    time::Instant,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    comms::{
+        frame::{MAX_FRAME_LEN, read_frame_len, read_frame_payload},
+        limit::{Budget, RateLimiter},
+        pool::{Balance, ConnectionState, Peer, PeerEvent, Pool, ReconnectPolicy},
+        proto::{self, Control, Message, close, read_message, write_message},
+        recv::{Server, ServerOptions, peer_identity},
+        send::{Client, Trust},
+        tls::{self, Identity, IdentityFiles, PeerIdentity},
+        transport::TransportOptions,
+    },
+    event::{self, Ack, Event},
+    module::{
+        Component, FlushPolicy, Health, Input, Shutdown, Sink, Source, outputs_closed,
+        outputs_health, send_all,
+    },
+};
+
+/// Receives events from remote agents, e.g. so that one agent can aggregate the logs of a fleet.
+///
+/// Peers open any number of connections and send batches of events on any number of streams, see
+/// `comms::proto`. Events keep the metadata they had on the peer, and are tagged with `peer_addr`,
+/// `peer_agent` and, if the peer authenticated with a certificate, `peer_identity` (see `PeerIdentity::name`)
+/// and `peer_fingerprint`.
+///
+/// A stream stops being read while the outputs are full, or the peer is over its rate, or the events received
+/// from all peers and not yet acknowledged take up `QUICSourceOptions::max_in_flight_bytes`, which QUIC's flow
+/// control turns into the peer waiting to send more.
+pub struct QUICSource {
+    name: Arc<str>,
+    listen_addr: SocketAddr,
+    server: Server,
+    /// Reloaded while running if they change, see `Server::watch`.
+    identity: Option<IdentityFiles>,
+    client_ca: Option<PathBuf>,
+    /// Names a peer's certificate must have been issued for, any authenticated peer may connect if empty.
+    allowed_peers: Arc<[String]>,
+    limits: Arc<Limits>,
+    out_chans: Vec<Sender<Event>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct QUICSourceOptions {
+    pub server: ServerOptions,
+    /// Longest frame, and so batch, accepted in bytes. Must be at least the senders' `max_frame_len`.
+    pub max_frame_len: usize,
+    /// Most events accepted in a batch. Must be at least the senders' `FlushPolicy::batch_size`.
+    pub max_batch_events: usize,
+    /// Events each peer may send per second, unlimited if `None`. Peers are told apart by `PeerIdentity::name`
+    /// if they authenticated, otherwise by IP address.
+    pub peer_events_per_sec: Option<u64>,
+    /// Bytes each peer may send per second, unlimited if `None`.
+    pub peer_bytes_per_sec: Option<u64>,
+    /// Bytes of events received from all peers that can be waiting to be acknowledged at once. Must be at
+    /// least `max_frame_len`.
+    pub max_in_flight_bytes: usize,
+}
+
+impl Default for QUICSourceOptions {
+    fn default() -> Self {
+        Self {
+            server: ServerOptions::default(),
+            max_frame_len: MAX_FRAME_LEN,
+            max_batch_events: 65536,
+            peer_events_per_sec: None,
+            peer_bytes_per_sec: None,
+            max_in_flight_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+/// `QUICSourceOptions` shared by every connection, along with what they need to enforce them.
+struct Limits {
+    max_frame_len: usize,
+    max_batch_events: usize,
+    peer_events_per_sec: Option<u64>,
+    peer_bytes_per_sec: Option<u64>,
+    in_flight: Budget,
+    /// Each peer's rate, shared by all of its connections.
+    peers: Mutex<HashMap<String, Arc<RateLimiter>>>,
+}
+
+impl Limits {
+    fn rate_limiter(&self, peer: &str) -> Arc<RateLimiter> {
+        let mut peers = self.peers.lock().unwrap();
+        // Forget peers that are no longer connected.
+        peers.retain(|_, limiter| Arc::strong_count(limiter) > 1);
+        peers
+            .entry(peer.to_string())
+            .or_insert_with(|| {
+                Arc::new(RateLimiter::new(
+                    self.peer_events_per_sec,
+                    self.peer_bytes_per_sec,
+                ))
+            })
+            .clone()
+    }
+}
+
+impl QUICSource {
+    /// Without an `identity`, a self-signed certificate is generated that no peer will trust, see
+    /// `Identity::load_or_generate` for one that peers can be given. It is reloaded from the files it was
+    /// loaded from whenever they change.
+    ///
+    /// With a `client_ca`, a PEM file, peers must authenticate with a certificate issued by one of those in
+    /// it, and if `allowed_peers` isn't empty, for one of the names in it.
+    ///
+    /// `options` defaults to `QUICSourceOptions::default()` if unspecified.
+    pub fn new(
+        name: String,
+        listen_addr: SocketAddr,
+        channels: impl IntoIterator<Item = Sender<Event>>,
+        identity: Option<(Identity, IdentityFiles)>,
+        client_ca: Option<PathBuf>,
+        allowed_peers: Vec<String>,
+        options: Option<QUICSourceOptions>,
+    ) -> Result<Self> {
+        let options = options.unwrap_or_default();
+        if client_ca.is_none() && !allowed_peers.is_empty() {
+            bail!(
+                "{}: peers can only be restricted when they must authenticate",
+                name
+            );
+        }
+        if options.max_in_flight_bytes < options.max_frame_len {
+            bail!(
+                "{}: the in-flight budget must fit at least one frame of {} bytes",
+                name,
+                options.max_frame_len
+            );
+        }
+        let limits = Limits {
+            max_frame_len: options.max_frame_len,
+            max_batch_events: options.max_batch_events,
+            peer_events_per_sec: options.peer_events_per_sec,
+            peer_bytes_per_sec: options.peer_bytes_per_sec,
+            in_flight: Budget::new(options.max_in_flight_bytes),
+            peers: Mutex::new(HashMap::new()),
+        };
+        let (identity, identity_files) = identity.unzip();
+        let server = Server::new(
+            Some(options.server),
+            identity,
+            client_ca.as_deref().map(tls::load_certs).transpose()?,
+        )?;
+        Ok(Self {
+            name: name.into(),
+            listen_addr,
+            server,
+            identity: identity_files,
+            client_ca,
+            allowed_peers: allowed_peers.into(),
+            limits: Arc::new(limits),
+            out_chans: channels.into_iter().collect(),
+        })
+    }
+
+    /// Accepts connections until shutdown, or until every output channel has been closed.
+    async fn run(&mut self, mut shutdown: Shutdown) -> Result<()> {
+        let addr = self.listen_addr;
+        let endpoint = self
+            .server
+            .listen(addr)
+            .with_context(|| format!("{}: unable to listen on {}", self.name, addr))?;
+        info!("{}: listening on {}", self.name, addr);
+        let _watcher = self
+            .server
+            .watch(self.identity.clone(), self.client_ca.clone());
+
+        let mut connections = JoinSet::new();
+        loop {
+            tokio::select! {
+                incoming = self.server.accept(&endpoint) => {
+                    let Some(incoming) = incoming else {
+                        break;
+                    };
+                    connections.spawn(receive_connection(
+                        self.name.clone(),
+                        incoming,
+                        self.allowed_peers.clone(),
+                        self.limits.clone(),
+                        self.out_chans.clone(),
+                    ));
+                }
+                Some(finished) = connections.join_next() => {
+                    if let Err(e) = finished? {
+                        warn!("{}: {:#}", self.name, e);
+                    }
+                }
+                _ = shutdown.wait() => {
+                    info!("{}: shutting down", self.name);
+                    break;
+                }
+                _ = outputs_closed(&self.out_chans) => {
+                    info!("{}: all output channels are closed, stopping", self.name);
+                    break;
+                }
+            }
+        }
+
+        endpoint.close(close::NORMAL.into(), b"shutting down");
+        connections.shutdown().await;
+        endpoint.wait_idle().await;
+        Ok(())
+    }
+}
+
+/// Receives every stream on a single connection until the peer closes it, acknowledging each batch once all
+/// of its events have been dropped by the pipeline. Peers that aren't in `allowed_peers` are turned away.
+async fn receive_connection(
+    name: Arc<str>,
+    incoming: Incoming,
+    allowed_peers: Arc<[String]>,
+    limits: Arc<Limits>,
+    out_chans: Vec<Sender<Event>>,
+) -> Result<()> {
+    let peer = incoming.remote_address();
+    let connection = incoming
+        .await
+        .with_context(|| format!("connection from {} failed", peer))?;
+    let identity = peer_identity(&connection);
+    if !allowed_peers.is_empty()
+        && !identity
+            .as_ref()
+            .is_some_and(|identity| allowed_peers.iter().any(|name| identity.matches(name)))
+    {
+        connection.close(close::UNAUTHORIZED.into(), b"not an allowed peer");
+        bail!(
+            "rejected connection from {} ({}), not an allowed peer",
+            peer,
+            identity
+                .as_ref()
+                .map_or("unauthenticated", |identity| identity.subject.as_str())
+        );
+    }
+    let Control {
+        peer_agent,
+        send: mut control,
+        recv: control_recv,
+    } = Control::accept(&connection, &event::hostname())
+        .await
+        .with_context(|| format!("handshake with {} failed", peer))?;
+    info!(
+        "{}: connection from {} at {} ({})",
+        name,
+        peer_agent,
+        peer,
+        identity
+            .as_ref()
+            .map_or("unauthenticated", PeerIdentity::name)
+    );
+
+    let rate_limiter = limits.rate_limiter(&identity.as_ref().map_or_else(
+        || peer.ip().to_string(),
+        |identity| identity.name().to_string(),
+    ));
+
+    // Added to every event from this connection, replacing any tags of the same name the peer sent.
+    let mut tags = BTreeMap::new();
+    tags.insert("peer_addr".to_string(), peer.to_string());
+    tags.insert("peer_agent".to_string(), peer_agent.clone());
+    if let Some(identity) = identity {
+        tags.insert("peer_identity".to_string(), identity.name().to_string());
+        tags.insert("peer_fingerprint".to_string(), identity.fingerprint);
+    }
+    let tags = Arc::new(tags);
+
+    // Reading isn't cancel safe, so keep the one future around rather than reading in the loop.
+    let control_reader = read_control(&connection, control_recv, &name, &peer_agent);
+    tokio::pin!(control_reader);
+    let mut control_open = true;
+
+    let (ack_send, mut acks) = mpsc::unbounded_channel();
+    let mut streams = JoinSet::new();
+    let closed = loop {
+        tokio::select! {
+            stream = connection.accept_uni() => match stream {
+                Ok(recv) => {
+                    streams.spawn(receive_stream(
+                        name.clone(),
+                        recv,
+                        tags.clone(),
+                        limits.clone(),
+                        rate_limiter.clone(),
+                        ack_send.clone(),
+                        out_chans.clone(),
+                    ));
+                }
+                Err(e) => break e,
+            },
+            Some((seq, delivered)) = acks.recv() => {
+                if delivered {
+                    // A failure here means the connection is closing, which `accept_uni` notices.
+                    let _ = write_message(&mut control, &Message::Ack { seq }).await;
+                } else {
+                    warn!("{}: batch {} from {} wasn't delivered, closing the connection to have it resent", name, seq, peer);
+                    connection.close(close::UNDELIVERED.into(), b"batch not delivered");
+                }
+            }
+            _ = &mut control_reader, if control_open => control_open = false,
+            Some(finished) = streams.join_next() => {
+                if let Err(e) = finished? {
+                    warn!("{}: stream from {} failed: {:#}", name, peer, e);
+                }
+            }
+        }
+    };
+    while let Some(finished) = streams.join_next().await {
+        if let Err(e) = finished? {
+            warn!("{}: stream from {} failed: {:#}", name, peer, e);
+        }
+    }
+
+    match closed {
+        ConnectionError::ApplicationClosed(_) | ConnectionError::LocallyClosed => {
+            info!("{}: connection from {} closed", name, peer);
+            Ok(())
+        }
+        e => Err(e).with_context(|| format!("connection from {} lost", peer)),
+    }
+}
+
+/// Handles control messages from the peer until it finishes the control stream or the connection closes.
+async fn read_control(connection: &Connection, mut recv: RecvStream, name: &str, peer_agent: &str) {
+    loop {
+        match read_message(&mut recv, proto::MAX_CONTROL_LEN).await {
+            Ok(Some(Message::Goodbye { reason })) => {
+                info!(
+                    "{}: {} is closing the connection: {}",
+                    name, peer_agent, reason
+                );
+            }
+            Ok(Some(_)) => {
+                connection.close(close::PROTOCOL_ERROR.into(), b"unexpected control message");
+                return;
+            }
+            // The connection closing is noticed by `accept_uni`.
+            Ok(None) | Err(_) => return,
+        }
+    }
+}
+
+/// Forwards the events in every batch on a stream, until the peer finishes the stream. Each batch's sequence
+/// number is sent to `acks` once all of its events have been delivered or dropped, along with whether they
+/// were all delivered, which is also when the batch stops counting towards the in-flight budget.
+async fn receive_stream(
+    name: Arc<str>,
+    mut recv: RecvStream,
+    tags: Arc<BTreeMap<String, String>>,
+    limits: Arc<Limits>,
+    rate_limiter: Arc<RateLimiter>,
+    acks: mpsc::UnboundedSender<(u64, bool)>,
+    mut out_chans: Vec<Sender<Event>>,
+) -> Result<()> {
+    let mut received = 0;
+    while let Some(len) = read_frame_len(&mut recv, limits.max_frame_len).await? {
+        // Before reserving rather than after, see `RateLimiter::ready`.
+        rate_limiter.ready().await;
+        let reservation = limits.in_flight.reserve(len).await;
+        let payload = read_frame_payload(&mut recv, len).await?;
+        let Message::Batch { seq, events } = Message::decode(payload)? else {
+            bail!("expected a batch");
+        };
+        if events.len() > limits.max_batch_events {
+            bail!(
+                "batch of {} events is larger than the maximum of {}",
+                events.len(),
+                limits.max_batch_events
+            );
+        }
+        rate_limiter.take(events.len() as u64, len as u64);
+        let acks = acks.clone();
+        let ack = Ack::new(move |delivered| {
+            drop(reservation);
+            let _ = acks.send((seq, delivered));
+        });
+        for mut event in events {
+            let metadata = Arc::make_mut(&mut event.metadata);
+            metadata
+                .tags
+                .extend(tags.iter().map(|(k, v)| (k.clone(), v.clone())));
+            send_all(&name, &mut out_chans, event.with_ack(ack.clone())).await?;
+            received += 1;
+        }
+        // Every event has its own clone now, this one only has to stop holding the batch back.
+        ack.delivered();
+    }
+    debug!("{}: stream finished after {} event(s)", name, received);
+    Ok(())
+}
+
+impl Component for QUICSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn start(&mut self, shutdown: Shutdown) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.run(shutdown))
+    }
+
+    fn health(&self) -> Health {
+        outputs_health(&self.out_chans)
+    }
+
+    fn shutdown(&mut self) -> BoxFuture<'_, Result<()>> {
+        self.out_chans.clear();
+        Box::pin(async { Ok(()) })
+    }
+}
+
+impl Source for QUICSource {}
+
+/// Ships events to remote agents' `QUICSource`s, e.g. so that edge hosts can forward to a set of central
+/// agents.
+///
+/// Events are batched according to the `FlushPolicy`, and each batch is written on its own stream over a
+/// long-lived connection to one of the peers, picked according to `QUICSinkOptions::balance`, see
+/// `comms::proto` and `comms::pool`. Batches are kept until the peer acknowledges them and resent if the
+/// connection drops first, to another peer if that one is unreachable, so peers may see the same events more
+/// than once but won't miss any. Events are only dropped once acknowledged, so an agent relaying events it
+/// received from another agent only acknowledges them once they have been acknowledged further along.
+pub struct QUICSink {
+    name: Arc<str>,
+    pool: Pool,
+    identity: Option<IdentityFiles>,
+    inp_chan: Input,
+    /// Batches being filled. When balancing by consistent hashing there is one for the events of each peer
+    /// after one for events without the key, see `slot`, otherwise there is just the one.
+    batches: Vec<Batch>,
+    /// Batches that haven't been acknowledged yet, by sequence number.
+    unacked: BTreeMap<u64, Pending>,
+    next_seq: u64,
+    options: QUICSinkOptions,
+}
+
+#[derive(Default)]
+struct Batch {
+    events: Vec<Event>,
+    // Encoded length of `events`.
+    len: usize,
+    /// The key of the first event, see `Pool::key`. The whole batch goes wherever this key is picked for,
+    /// see `Balance::ConsistentHash`.
+    key: Option<u64>,
+}
+
+struct Pending {
+    events: Vec<Event>,
+    key: Option<u64>,
+    /// The peer it was sent to and the `stable_id` of the connection it was sent on, `None` if it still needs
+    /// sending.
+    sent_on: Option<(usize, usize)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct QUICSinkOptions {
+    /// When to send a batch.
+    pub flush_policy: FlushPolicy,
+    pub reconnect_policy: ReconnectPolicy,
+    /// Stop taking new events while this many batches are waiting to be acknowledged.
+    pub max_unacked_batches: usize,
+    /// How to pick the peer each batch is sent to.
+    pub balance: Balance,
+    pub transport: TransportOptions,
+    /// Longest batch to send, in bytes. Must be at most the peers' `QUICSourceOptions::max_frame_len`.
+    pub max_frame_len: usize,
+}
+
+impl Default for QUICSinkOptions {
+    fn default() -> Self {
+        Self {
+            flush_policy: FlushPolicy::default(),
+            reconnect_policy: ReconnectPolicy::default(),
+            max_unacked_batches: 16,
+            balance: Balance::default(),
+            transport: TransportOptions::default(),
+            max_frame_len: MAX_FRAME_LEN,
+        }
+    }
+}
+
+impl QUICSink {
+    /// Only peers that `trust` vouches for are sent to. `identity` is presented to peers that require mutual
+    /// TLS. Both are reloaded while running if their files change, for `identity` the files it was loaded
+    /// from. `options` defaults to
+    /// `QUICSinkOptions::default()` if unspecified.
+    pub async fn new(
+        name: String,
+        peers: Vec<Peer>,
+        trust: &Trust,
+        identity: Option<(Identity, IdentityFiles)>,
+        recv: Input,
+        options: Option<QUICSinkOptions>,
+    ) -> Result<Self> {
+        let options = options.unwrap_or_default();
+        let mut client = Client::new(
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            Some(options.transport.clone()),
+        )?;
+        let (identity, identity_files) = identity.unzip();
+        if let Some(identity) = identity {
+            client.set_identity(identity)?;
+        }
+        if trust.is_empty() {
+            bail!("{}: nothing to trust the peer with", name);
+        }
+        client
+            .trust(trust)
+            .with_context(|| format!("{}: failed to load the certificates to trust", name))?;
+        let slots = match options.balance {
+            Balance::ConsistentHash(_) => peers.len() + 1,
+            _ => 1,
+        };
+        let name: Arc<str> = name.into();
+        let pool = Pool::new(
+            name.clone(),
+            client,
+            peers,
+            options.balance.clone(),
+            options.reconnect_policy,
+        )?;
+        Ok(Self {
+            name,
+            pool,
+            identity: identity_files,
+            inp_chan: recv,
+            batches: (0..slots).map(|_| Batch::default()).collect(),
+            unacked: BTreeMap::new(),
+            next_seq: 0,
+            options,
+        })
+    }
+
+    /// Sends every received event to the peers, until all senders have been dropped or the sink is being
+    /// replaced. Either way, everything sent is acknowledged before returning.
+    async fn run(&mut self, mut shutdown: Shutdown) -> Result<()> {
+        let _watcher = self.pool.client().watch(self.identity.clone());
+        let mut flush_timer = tokio::time::interval(self.options.flush_policy.interval);
+        flush_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            let window_open = self.unacked.len() < self.options.max_unacked_batches;
+            let batched = self.batches.iter().any(|batch| !batch.events.is_empty());
+            tokio::select! {
+                record = self.inp_chan.recv(), if window_open => {
+                    let Some(record) = record else {
+                        break;
+                    };
+                    let record_len = proto::encoded_len(&record);
+                    // The peer would reject the frame, and keep rejecting it every time it was resent.
+                    let max_frame_len = self.options.max_frame_len;
+                    if record_len + proto::BATCH_HEADER_LEN > max_frame_len {
+                        warn!("{}: dropping event of {} bytes, it is too large to send", self.name, record.payload.len());
+                        // Dropped on purpose, so that the source doesn't keep sending it again.
+                        record.delivered();
+                        continue;
+                    }
+                    let key = self.pool.key(&record);
+                    let slot = self.slot(key);
+                    if self.batches[slot].len + record_len + proto::BATCH_HEADER_LEN > max_frame_len {
+                        self.send_batch(slot, &mut shutdown).await;
+                    }
+                    let batch = &mut self.batches[slot];
+                    if batch.events.is_empty() {
+                        batch.key = key;
+                    }
+                    batch.events.push(record);
+                    batch.len += record_len;
+                    if batch.events.len() >= self.options.flush_policy.batch_size {
+                        self.send_batch(slot, &mut shutdown).await;
+                    }
+                }
+                _ = flush_timer.tick(), if window_open && batched => {
+                    self.send_batches(&mut shutdown).await;
+                }
+                event = self.pool.next_event() => {
+                    self.handle(event, &mut shutdown).await;
+                }
+                _ = shutdown.wait() => {
+                    self.drain(&mut shutdown).await;
+                    info!("{}: stopping to be replaced", self.name);
+                    return Ok(());
+                }
+            }
+        }
+
+        self.drain(&mut shutdown).await;
+        info!("{}: all senders closed, stopping", self.name);
+        Ok(())
+    }
+
+    /// Which of `batches` an event with `key` goes in.
+    fn slot(&self, key: Option<u64>) -> usize {
+        match key {
+            Some(key) if self.batches.len() > 1 => self.pool.owner(key) + 1,
+            _ => 0,
+        }
+    }
+
+    /// Handles an acknowledgement, resends what was sent on a connection that has been lost, or reports a
+    /// peer coming and going.
+    async fn handle(&mut self, event: PeerEvent, shutdown: &mut Shutdown) {
+        match event {
+            PeerEvent::Ack(seq) => {
+                if let Some(Pending {
+                    events,
+                    sent_on: Some((peer, _)),
+                    ..
+                }) = self.unacked.remove(&seq)
+                {
+                    self.pool.acked(peer);
+                    events.into_iter().for_each(Event::delivered);
+                }
+            }
+            PeerEvent::Lost { peer, id } => {
+                self.unassign(peer, Some(id));
+                self.send_unacked(shutdown).await;
+            }
+            PeerEvent::State { peer, state } => {
+                let addr = self.pool.peer(peer).addr;
+                match state {
+                    ConnectionState::Connecting => debug!("{}: connecting to {}", self.name, addr),
+                    ConnectionState::Connected { peer_agent } => {
+                        info!("{}: connected to {} at {}", self.name, peer_agent, addr)
+                    }
+                    ConnectionState::Down {
+                        failures,
+                        retry_at,
+                        error,
+                    } => warn!(
+                        "{}: {} is down after {} attempt(s), retrying in {:?}: {}",
+                        self.name,
+                        addr,
+                        failures,
+                        retry_at.saturating_duration_since(Instant::now()),
+                        error
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Sends the current batches and waits for every batch to be acknowledged. When being replaced, only waits
+    /// for those already sent, and leaves the rest for `take_input`.
+    async fn drain(&mut self, shutdown: &mut Shutdown) {
+        self.send_batches(shutdown).await;
+        while self
+            .unacked
+            .values()
+            .any(|p| p.sent_on.is_some() || !shutdown.is_triggered())
+        {
+            let event = self.pool.next_event().await;
+            self.handle(event, shutdown).await;
+        }
+        let unsent: usize = self.unacked.values().map(|p| p.events.len()).sum();
+        if unsent > 0 {
+            info!(
+                "{}: leaving {} unsent event(s) for the replacement",
+                self.name, unsent
+            );
+        }
+    }
+
+    /// Gives the batch in `slot` the next sequence number and sends it.
+    async fn send_batch(&mut self, slot: usize, shutdown: &mut Shutdown) {
+        self.seal(slot);
+        self.send_unacked(shutdown).await;
+    }
+
+    /// Gives every batch the next sequence number and sends them.
+    async fn send_batches(&mut self, shutdown: &mut Shutdown) {
+        for slot in 0..self.batches.len() {
+            self.seal(slot);
+        }
+        self.send_unacked(shutdown).await;
+    }
+
+    /// Moves the batch in `slot`, if there is one, to those waiting to be sent.
+    fn seal(&mut self, slot: usize) {
+        let batch = std::mem::take(&mut self.batches[slot]);
+        if batch.events.is_empty() {
+            return;
+        }
+        let pending = Pending {
+            events: batch.events,
+            key: batch.key,
+            sent_on: None,
+        };
+        self.unacked.insert(self.next_seq, pending);
+        self.next_seq += 1;
+    }
+
+    /// Marks everything sent to `peer` on the connection `id`, or on any if unspecified, as needing sending
+    /// again, after the connection has gone.
+    fn unassign(&mut self, peer: usize, id: Option<usize>) {
+        for pending in self.unacked.values_mut() {
+            if let Some(sent_on) = pending.sent_on
+                && sent_on.0 == peer
+                && id.is_none_or(|id| id == sent_on.1)
+            {
+                pending.sent_on = None;
+            }
+        }
+    }
+
+    /// Sends every unacknowledged batch that hasn't been sent, waiting for a peer to become available as many
+    /// times as it takes. Once shutdown has been triggered nothing more is sent, not even a connection attempt
+    /// already under way, and the unsent batches are left for `take_input` to hand to the replacement.
+    async fn send_unacked(&mut self, shutdown: &mut Shutdown) {
+        loop {
+            let attempt = tokio::select! {
+                biased;
+                _ = shutdown.wait() => return,
+                attempt = self.try_send_unacked() => attempt,
+            };
+            let Err(retry_at) = attempt else {
+                return;
+            };
+
+            info!(
+                "{}: no peers available, retrying in {:?}",
+                self.name,
+                retry_at.saturating_duration_since(Instant::now())
+            );
+            tokio::select! {
+                _ = tokio::time::sleep_until(retry_at) => {}
+                _ = shutdown.wait() => {}
+            }
+        }
+    }
+
+    /// Writes each unsent batch on its own stream to the peer picked for it, failing over to the next peer
+    /// whenever one fails. If every peer has failed, returns when the first can be tried again.
+    async fn try_send_unacked(&mut self) -> Result<(), Instant> {
+        while let Some((&seq, pending)) = self.unacked.iter().find(|(_, p)| p.sent_on.is_none()) {
+            let peer = self.pool.pick(pending.key)?;
+            match self.send(peer, seq).await {
+                Ok(id) => {
+                    self.unacked.get_mut(&seq).unwrap().sent_on = Some((peer, id));
+                    self.pool.sent(peer);
+                }
+                Err(e) => {
+                    self.pool.failed(peer, format!("{:#}", e));
+                    // Every connection to the peer is gone now.
+                    self.unassign(peer, None);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the batch `seq` on a new stream to `peer`, connecting first if needed. Returns the `stable_id`
+    /// of the connection it was sent on.
+    async fn send(&mut self, peer: usize, seq: u64) -> Result<usize> {
+        let connection = self.pool.connection(peer).await?;
+        let batch = Message::Batch {
+            seq,
+            events: self.unacked[&seq]
+                .events
+                .iter()
+                .map(Event::without_ack)
+                .collect(),
+        };
+        let mut send = connection.open_uni().await?;
+        write_message(&mut send, &batch).await?;
+        send.finish()?;
+        Ok(connection.stable_id())
+    }
+}
+
+impl Component for QUICSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn start(&mut self, shutdown: Shutdown) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.run(shutdown))
+    }
+
+    /// Peers being unreachable is usually temporary, they are retried for as long as we keep running.
+    fn health(&self) -> Health {
+        if self.pool.available() == 0 {
+            Health::Degraded("no peers are reachable".to_string())
+        } else {
+            Health::Healthy
+        }
+    }
+
+    /// Everything has either been acknowledged or is left for `take_input` by now, see `run`.
+    fn shutdown(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async {
+            self.pool.close("shutting down").await;
+            Ok(())
+        })
+    }
+
+    /// Batches that are being filled or haven't been acknowledged are handed on too, to be sent again.
+    fn take_input(&mut self) -> Option<Input> {
+        let mut unfinished: Vec<Event> = std::mem::take(&mut self.unacked)
+            .into_values()
+            .flat_map(|pending| pending.events)
+            .collect();
+        for batch in &mut self.batches {
+            unfinished.append(&mut std::mem::take(batch).events);
+        }
+        Some(self.inp_chan.take().with_unfinished(unfinished))
+    }
+}
+
+impl Sink for QUICSink {
+    /// Makes a single attempt at sending the current batches, and anything not yet sent, to each peer.
+    fn flush(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async {
+            for slot in 0..self.batches.len() {
+                self.seal(slot);
+            }
+            if self.try_send_unacked().await.is_err() {
+                bail!("no peers are reachable");
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comms::tls::{
+        Pin,
+        testing::{Ca, TempDir},
+    };
+    use rustls::pki_types::CertificateDer;
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+
+    /// Reads from `recv` and sends to a peer that is never reached, which is all that dropping needs.
+    async fn sink(recv: mpsc::Receiver<Event>, options: QUICSinkOptions) -> QUICSink {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let peer = Peer {
+            addr: SocketAddr::from(([127, 0, 0, 1], 9)),
+            server_name: "localhost".into(),
+        };
+        let trust = Trust {
+            pins: vec![Pin::Certificate([0; 32])],
+            ..Trust::default()
+        };
+        QUICSink::new(
+            "fwd".into(),
+            vec![peer],
+            &trust,
+            None,
+            Input::from(recv),
+            Some(options),
+        )
+        .await
+        .unwrap()
+    }
+
+    /// An address nothing is listening on yet.
+    fn free_addr() -> SocketAddr {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.local_addr().unwrap()
+    }
+
+    /// Starts `source` and waits until it is listening on `addr`.
+    async fn listen(
+        mut source: QUICSource,
+        addr: SocketAddr,
+    ) -> tokio::task::JoinHandle<Result<()>> {
+        let (_, shutdown) = Shutdown::channel();
+        let task = tokio::spawn(async move { source.start(shutdown).await });
+        while std::net::UdpSocket::bind(addr).is_ok() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        task
+    }
+
+    /// Connects to the source at `addr` from `bind_ip` and handshakes, returning the connection and the
+    /// control stream, which has to be kept open.
+    async fn connect(
+        bind_ip: [u8; 4],
+        addr: SocketAddr,
+        cert: CertificateDer<'static>,
+    ) -> (Connection, Control) {
+        let mut client = Client::new(SocketAddr::from((bind_ip, 0)), None).unwrap();
+        client.trust_cert(cert).unwrap();
+        let connection = client.connect(addr, "localhost").await.unwrap();
+        let control = Control::connect(&connection, "edge").await.unwrap();
+        (connection, control)
+    }
+
+    /// Sends a batch of one event on its own stream.
+    async fn send_batch(connection: &Connection, seq: u64, payload: &str) {
+        let batch = Message::Batch {
+            seq,
+            events: vec![Event::new(
+                payload.to_string(),
+                event::Metadata::new("app".into()),
+            )],
+        };
+        let mut send = connection.open_uni().await.unwrap();
+        write_message(&mut send, &batch).await.unwrap();
+        send.finish().unwrap();
+    }
+
+    #[tokio::test]
+    async fn throttled_peer_leaves_budget_to_others() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let addr = free_addr();
+        let (out, mut events) = mpsc::channel(16);
+        // Room for one batch at a time, and each batch puts a peer over its rate for most of a second.
+        let options = QUICSourceOptions {
+            max_frame_len: 1000,
+            max_in_flight_bytes: 1000,
+            peer_bytes_per_sec: Some(400),
+            ..QUICSourceOptions::default()
+        };
+        let source =
+            QUICSource::new("in".into(), addr, [out], None, None, vec![], Some(options)).unwrap();
+        let cert = source.server.get_cert();
+        let _task = listen(source, addr).await;
+        // Told apart by IP address, as neither authenticates.
+        let (slow, _slow_control) = connect([127, 0, 0, 1], addr, cert.clone()).await;
+        let (other, _other_control) = connect([127, 0, 0, 2], addr, cert).await;
+        let padding = "x".repeat(700);
+
+        send_batch(&slow, 0, &format!("slow-0 {}", padding)).await;
+        let first = events.recv().await.unwrap();
+        assert!(first.payload.starts_with(b"slow-0"));
+        first.delivered();
+        send_batch(&slow, 1, &format!("slow-1 {}", padding)).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        send_batch(&other, 0, &format!("other-0 {}", padding)).await;
+
+        let next = events.recv().await.unwrap();
+        assert!(next.payload.starts_with(b"other-0"));
+        next.delivered();
+        let last = events.recv().await.unwrap();
+        assert!(last.payload.starts_with(b"slow-1"));
+        last.delivered();
+    }
+
+    #[tokio::test]
+    async fn acknowledges_dropped_oversized_events() {
+        let (send, recv) = mpsc::channel(1);
+        let options = QUICSinkOptions {
+            max_frame_len: 100,
+            ..QUICSinkOptions::default()
+        };
+        let mut sink = sink(recv, options).await;
+        let (done, delivered) = oneshot::channel();
+        let ack = Ack::new(move |delivered| {
+            let _ = done.send(delivered);
+        });
+        let event = Event::new(vec![b'x'; 200], event::Metadata::new("app".into())).with_ack(ack);
+        send.send(event).await.unwrap();
+        drop(send);
+        let (_, shutdown) = Shutdown::channel();
+        sink.start(shutdown).await.unwrap();
+        assert_eq!(delivered.await, Ok(true));
+    }
+
+    #[tokio::test]
+    async fn unreachable_peers_only_degrade() {
+        let (_send, recv) = mpsc::channel(1);
+        let mut sink = sink(recv, QUICSinkOptions::default()).await;
+        assert_eq!(sink.health(), Health::Healthy);
+        sink.pool.failed(0, "unreachable");
+        // Not `Unhealthy`, which would stop it from being restarted.
+        assert_eq!(
+            sink.health(),
+            Health::Degraded("no peers are reachable".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn only_accepts_allowed_peers() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let dir = TempDir::new("only_accepts_allowed_peers");
+        let (server_ca, client_ca) = (Ca::new("server-ca"), Ca::new("client-ca"));
+        let issued = server_ca.issue("localhost");
+        let identity = (issued.identity(), issued.write(&dir.0));
+        let client_ca_path = dir.0.join("client-ca.pem");
+        std::fs::write(&client_ca_path, client_ca.pem()).unwrap();
+        let addr = free_addr();
+        let (out, _events) = mpsc::channel(16);
+        let source = QUICSource::new(
+            "in".into(),
+            addr,
+            [out],
+            Some(identity),
+            Some(client_ca_path),
+            vec!["edge-01".into()],
+            None,
+        )
+        .unwrap();
+        let _task = listen(source, addr).await;
+        let connect_as = async |name: &str| {
+            let mut client = Client::new("127.0.0.1:0".parse().unwrap(), None).unwrap();
+            client.trust_cert(server_ca.cert()).unwrap();
+            client
+                .set_identity(client_ca.issue(name).identity())
+                .unwrap();
+            client.connect(addr, "localhost").await.unwrap()
+        };
+
+        let allowed = connect_as("edge-01").await;
+        Control::connect(&allowed, "edge").await.unwrap();
+        let refused = connect_as("edge-02").await;
+        match refused.closed().await {
+            ConnectionError::ApplicationClosed(close) => {
+                assert_eq!(close.error_code, close::UNAUTHORIZED.into())
+            }
+            e => panic!("closed for the wrong reason: {}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn sink_delivers_to_source() {
+        let _ = rustls::crypto::ring::default_provider().install_default();
+        let dir = TempDir::new("sink_delivers_to_source");
+        let (server_ca, client_ca) = (Ca::new("server-ca"), Ca::new("client-ca"));
+        let server_ca_path = dir.0.join("server-ca.pem");
+        std::fs::write(&server_ca_path, server_ca.pem()).unwrap();
+        let client_ca_path = dir.0.join("client-ca.pem");
+        std::fs::write(&client_ca_path, client_ca.pem()).unwrap();
+        let (server_dir, client_dir) = (dir.0.join("server"), dir.0.join("client"));
+        std::fs::create_dir(&server_dir).unwrap();
+        std::fs::create_dir(&client_dir).unwrap();
+        let server = server_ca.issue("localhost");
+        let client = client_ca.issue("edge-01");
+
+        let addr = free_addr();
+        let (out, mut received) = mpsc::channel(16);
+        let source = QUICSource::new(
+            "in".into(),
+            addr,
+            [out],
+            Some((server.identity(), server.write(&server_dir))),
+            Some(client_ca_path),
+            vec![],
+            None,
+        )
+        .unwrap();
+        let _source = listen(source, addr).await;
+
+        let (send, recv) = mpsc::channel(16);
+        let peer = Peer {
+            addr,
+            server_name: "localhost".into(),
+        };
+        let trust = Trust {
+            ca_paths: vec![server_ca_path],
+            ..Trust::default()
+        };
+        let options = QUICSinkOptions {
+            flush_policy: FlushPolicy {
+                batch_size: 2,
+                ..FlushPolicy::default()
+            },
+            ..QUICSinkOptions::default()
+        };
+        let mut sink = QUICSink::new(
+            "fwd".into(),
+            vec![peer],
+            &trust,
+            Some((client.identity(), client.write(&client_dir))),
+            Input::from(recv),
+            Some(options),
+        )
+        .await
+        .unwrap();
+        let mut acked = vec![];
+        for i in 0..3 {
+            let (done, delivered) = oneshot::channel();
+            let ack = Ack::new(move |delivered| {
+                let _ = done.send(delivered);
+            });
+            let mut metadata = event::Metadata::new("app".into());
+            metadata.tags.insert("env".into(), "test".into());
+            // Replaced by the source with what it saw of the peer.
+            metadata
+                .tags
+                .insert("peer_identity".into(), "spoofed".into());
+            send.send(Event::new(format!("event {}", i), metadata).with_ack(ack))
+                .await
+                .unwrap();
+            acked.push(delivered);
+        }
+        drop(send);
+        let (_, shutdown) = Shutdown::channel();
+        let sink = tokio::spawn(async move { sink.start(shutdown).await });
+
+        for i in 0..3 {
+            let event = received.recv().await.unwrap();
+            assert_eq!(event.payload, format!("event {}", i).as_bytes());
+            let tags = &event.metadata.tags;
+            assert_eq!(tags["env"], "test");
+            assert_eq!(tags["peer_identity"], "edge-01");
+            assert_eq!(tags["peer_agent"], &*event::hostname());
+            assert_eq!(
+                tags["peer_fingerprint"],
+                crate::comms::tls::fingerprint(&client.cert())
+            );
+            event.delivered();
+        }
+        // Only returns once the source has acknowledged everything.
+        sink.await.unwrap().unwrap();
+        for delivered in acked {
+            assert_eq!(delivered.await, Ok(true));
+        }
+    }
+}
@@ -0,0 +1,329 @@
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask, Watches};
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+
+/// How often to check the file when we can't use inotify for it.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// Even with inotify we still check the file every so often. Some filesystems (e.g. NFS) will happily
+/// accept a watch but never deliver events for changes made by other hosts.
+const INOTIFY_SAFETY_INTERVAL: Duration = Duration::from_secs(10);
+const INOTIFY_BUFFER_SIZE: usize = 4096;
+
+/// Tells a `FileSource` when the file it is following may have changed, so that idle sources don't need
+/// to spin checking for new data.
+pub(crate) enum FileWatcher {
+    Inotify(InotifyWatcher),
+    Poll,
+}
+
+/// Watches one file through the agent's shared inotify instance, see `Hub`.
+pub(crate) struct InotifyWatcher {
+    hub: Arc<Hub>,
+    id: u64,
+    notify: Arc<Notify>,
+    // Watches the inode we currently have open, which keeps working after the file is renamed.
+    file_wd: Option<WatchDescriptor>,
+    // Watches the directory so that we notice the file being recreated at `path` after a rotation.
+    dir_wd: WatchDescriptor,
+}
+
+impl FileWatcher {
+    /// Falls back to polling if inotify can't be used for `path`.
+    pub(crate) fn new(path: &Path) -> Self {
+        match InotifyWatcher::new(path) {
+            Ok(watcher) => Self::Inotify(watcher),
+            Err(e) => {
+                warn!(
+                    "Unable to use inotify for {}, falling back to polling: {}",
+                    path.display(),
+                    e
+                );
+                Self::Poll
+            }
+        }
+    }
+
+    /// Call this after (re)opening the file at `path`, so that we follow the new file.
+    pub(crate) fn watch_file(&mut self, path: &Path) {
+        if let Self::Inotify(watcher) = self
+            && let Err(e) = watcher.watch_file(path)
+        {
+            warn!(
+                "Unable to use inotify for {}, falling back to polling: {}",
+                path.display(),
+                e
+            );
+            *self = Self::Poll;
+        }
+    }
+
+    /// Waits until the file may have changed. This can return spuriously, so callers must check for
+    /// themselves whether anything actually happened.
+    pub(crate) async fn wait(&mut self) {
+        match self {
+            Self::Inotify(watcher) => {
+                if let Err(e) = watcher.wait().await {
+                    warn!("inotify failed, falling back to polling: {}", e);
+                    *self = Self::Poll;
+                }
+            }
+            Self::Poll => tokio::time::sleep(POLL_INTERVAL).await,
+        }
+    }
+}
+
+impl InotifyWatcher {
+    fn new(path: &Path) -> std::io::Result<Self> {
+        let hub = Hub::get()?;
+        let id = hub.next_id.fetch_add(1, Ordering::Relaxed);
+        let notify = Arc::new(Notify::new());
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let dir_wd = hub.subscribe(
+            &dir,
+            WatchMask::CREATE | WatchMask::MOVED_TO | WatchMask::MOVED_FROM | WatchMask::DELETE,
+            Subscriber {
+                id,
+                // Other files in the same directory are none of our business.
+                name: Some(path.file_name().unwrap_or_default().to_os_string()),
+                notify: notify.clone(),
+            },
+        )?;
+        let mut watcher = Self {
+            hub,
+            id,
+            notify,
+            file_wd: None,
+            dir_wd,
+        };
+        watcher.watch_file(path)?;
+        Ok(watcher)
+    }
+
+    fn watch_file(&mut self, path: &Path) -> std::io::Result<()> {
+        if let Some(wd) = self.file_wd.take() {
+            self.hub.unsubscribe(wd, self.id);
+        }
+        self.file_wd = Some(self.hub.subscribe(
+            path,
+            WatchMask::MODIFY | WatchMask::ATTRIB | WatchMask::MOVE_SELF | WatchMask::DELETE_SELF,
+            Subscriber {
+                id: self.id,
+                name: None,
+                notify: self.notify.clone(),
+            },
+        )?);
+        Ok(())
+    }
+
+    async fn wait(&mut self) -> std::io::Result<()> {
+        // Writers tend to produce a burst of events, `Notify` collapses them in to a single wake up.
+        tokio::select! {
+            _ = self.notify.notified() => {}
+            _ = tokio::time::sleep(INOTIFY_SAFETY_INTERVAL) => {}
+        }
+        if self.hub.failed.load(Ordering::Relaxed) {
+            return Err(std::io::Error::other("the inotify instance stopped"));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for InotifyWatcher {
+    fn drop(&mut self) {
+        self.hub.unsubscribe(self.dir_wd.clone(), self.id);
+        if let Some(wd) = self.file_wd.take() {
+            self.hub.unsubscribe(wd, self.id);
+        }
+    }
+}
+
+/// The agent's one inotify instance, shared by every `InotifyWatcher` and dispatching events to them by watch
+/// descriptor. Instances are limited per user (`fs.inotify.max_user_instances`, 128 by default), so one per
+/// followed file would run out long before an agent tailing hundreds of files does.
+///
+/// Events are read on a thread of its own rather than a task, as a task would stop along with the runtime
+/// that happened to start the instance while watchers on other runtimes still depend on it.
+struct Hub {
+    subscriptions: Mutex<Subscriptions>,
+    /// Set once the instance stops delivering events, after which a new one is started for new watchers.
+    failed: AtomicBool,
+    next_id: AtomicU64,
+}
+
+struct Subscriptions {
+    watches: Watches,
+    by_wd: HashMap<WatchDescriptor, Vec<Subscriber>>,
+}
+
+struct Subscriber {
+    id: u64,
+    /// For directory watches, the name of the file the subscriber cares about.
+    name: Option<OsString>,
+    notify: Arc<Notify>,
+}
+
+impl Hub {
+    /// The current instance, starting one first if there is none or it has failed.
+    fn get() -> std::io::Result<Arc<Self>> {
+        static CURRENT: Mutex<Option<Arc<Hub>>> = Mutex::new(None);
+        let mut current = CURRENT.lock().unwrap();
+        if let Some(hub) = current.as_ref()
+            && !hub.failed.load(Ordering::Relaxed)
+        {
+            return Ok(hub.clone());
+        }
+        let inotify = Inotify::init()?;
+        let hub = Arc::new(Self {
+            subscriptions: Mutex::new(Subscriptions {
+                watches: inotify.watches(),
+                by_wd: HashMap::new(),
+            }),
+            failed: AtomicBool::new(false),
+            next_id: AtomicU64::new(0),
+        });
+        let dispatcher = hub.clone();
+        std::thread::Builder::new()
+            .name("logga-inotify".to_string())
+            .spawn(move || dispatcher.dispatch(inotify))?;
+        *current = Some(hub.clone());
+        Ok(hub)
+    }
+
+    /// Watches `path` for `mask`, on top of whatever others are watching it for, and wakes `subscriber` for
+    /// every event on it.
+    fn subscribe(
+        &self,
+        path: &Path,
+        mask: WatchMask,
+        subscriber: Subscriber,
+    ) -> std::io::Result<WatchDescriptor> {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        let wd = subscriptions
+            .watches
+            .add(path, mask | WatchMask::MASK_ADD)?;
+        subscriptions
+            .by_wd
+            .entry(wd.clone())
+            .or_default()
+            .push(subscriber);
+        Ok(wd)
+    }
+
+    /// Stops waking the subscriber `id` for events on `wd`, removing the watch once nobody is left.
+    fn unsubscribe(&self, wd: WatchDescriptor, id: u64) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        let Some(subscribers) = subscriptions.by_wd.get_mut(&wd) else {
+            return;
+        };
+        subscribers.retain(|subscriber| subscriber.id != id);
+        if subscribers.is_empty() {
+            subscriptions.by_wd.remove(&wd);
+            // This fails if the file has already been deleted, which removes the watch anyway.
+            let _ = subscriptions.watches.remove(wd);
+        }
+    }
+
+    fn dispatch(&self, mut inotify: Inotify) {
+        let mut buffer = vec![0; INOTIFY_BUFFER_SIZE];
+        let error = loop {
+            match inotify.read_events_blocking(&mut buffer) {
+                Ok(events) => events.for_each(|event| self.wake(&event)),
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => break e,
+            }
+        };
+        warn!("inotify failed, falling back to polling: {}", error);
+        self.failed.store(true, Ordering::Relaxed);
+        self.wake_all();
+    }
+
+    fn wake(&self, event: &inotify::Event<&OsStr>) {
+        // Events were lost, so anyone might have missed one.
+        if event.mask.contains(EventMask::Q_OVERFLOW) {
+            self.wake_all();
+            return;
+        }
+        let subscriptions = self.subscriptions.lock().unwrap();
+        let Some(subscribers) = subscriptions.by_wd.get(&event.wd) else {
+            return;
+        };
+        for subscriber in subscribers {
+            if subscriber.name.is_none() || subscriber.name.as_deref() == event.name {
+                debug!("{:?} for {:?}", event.mask, event.name);
+                subscriber.notify.notify_one();
+            }
+        }
+    }
+
+    fn wake_all(&self) {
+        let subscriptions = self.subscriptions.lock().unwrap();
+        for subscriber in subscriptions.by_wd.values().flatten() {
+            subscriber.notify.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// A path in a directory of its own, removed once the test is done with it.
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(test: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("logga-watch-{}-{}", std::process::id(), test));
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("app.log");
+            std::fs::write(&path, "").unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(self.0.parent().unwrap());
+        }
+    }
+
+    /// Waits on a watcher of `path` while something is appended to it, well within `INOTIFY_SAFETY_INTERVAL`.
+    fn wakes_on_append(path: &Path) {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut watcher = FileWatcher::new(path);
+            assert!(matches!(watcher, FileWatcher::Inotify(_)));
+            let mut file = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+            file.write_all(b"record\n").unwrap();
+            tokio::time::timeout(Duration::from_secs(2), watcher.wait())
+                .await
+                .expect("the watcher wasn't woken");
+        });
+    }
+
+    #[test]
+    fn wakes_readers_after_the_first_runtime_stops() {
+        let path = TempPath::new("wakes_readers_after_the_first_runtime_stops");
+        // Each runtime is gone by the time the next starts, the hub has to outlive whichever started it.
+        wakes_on_append(&path.0);
+        wakes_on_append(&path.0);
+    }
+}